@@ -0,0 +1,376 @@
+use crate::app::config::{AccessLogConfig, AccessLogFormat};
+use crate::balance::circuit::Overflow;
+use crate::proxy::metered::Transferred;
+use crate::proxy::route::Attributes;
+use crate::proxy::NoBackend;
+use chrono::{SecondsFormat, Utc};
+use eyre::{Context, OptionExt, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::fs::OpenOptions;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info};
+
+/// Records waiting to be written. Records are dropped rather than slowing
+/// down the proxy when the writer falls this far behind.
+const QUEUE_SIZE: usize = 8192;
+
+/// Fields a template can refer to as `{name}`.
+const FIELDS: [&str; 18] = [
+    "timestamp",
+    "kind",
+    "listener",
+    "upstream",
+    "client_addr",
+    "sni",
+    "alpn",
+    "client_id",
+    "method",
+    "path",
+    "status",
+    "backend",
+    "bytes_received",
+    "bytes_sent",
+    "handshake_ms",
+    "connect_ms",
+    "duration_ms",
+    "termination",
+];
+
+/// One access log record, describing a client connection or an HTTP request.
+/// Bytes are counted from the client side, durations are in milliseconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct Record {
+    timestamp: String,
+    kind: &'static str,
+    listener: String,
+    upstream: String,
+    client_addr: SocketAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sni: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alpn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend: Option<SocketAddr>,
+    bytes_received: u64,
+    bytes_sent: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    handshake_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connect_ms: Option<f64>,
+    duration_ms: f64,
+    termination: &'static str,
+}
+
+impl Record {
+    pub fn connection(listener: &str, upstream: &str, client_addr: SocketAddr) -> Self {
+        Self::new("connection", listener, upstream, client_addr)
+    }
+
+    pub fn request(listener: &str, upstream: &str, client_addr: SocketAddr) -> Self {
+        Self::new("request", listener, upstream, client_addr)
+    }
+
+    fn new(kind: &'static str, listener: &str, upstream: &str, client_addr: SocketAddr) -> Self {
+        Self {
+            timestamp: String::new(),
+            kind,
+            listener: listener.to_string(),
+            upstream: upstream.to_string(),
+            client_addr,
+            sni: None,
+            alpn: None,
+            client_id: None,
+            method: None,
+            path: None,
+            status: None,
+            backend: None,
+            bytes_received: 0,
+            bytes_sent: 0,
+            handshake_ms: None,
+            connect_ms: None,
+            duration_ms: 0.0,
+            termination: "",
+        }
+    }
+
+    pub fn set_tls(&mut self, attributes: &Attributes) {
+        self.sni.clone_from(&attributes.sni);
+        self.alpn = attributes
+            .alpn
+            .as_deref()
+            .map(|alpn| String::from_utf8_lossy(alpn).into_owned());
+        if !attributes.client_names.is_empty() {
+            self.client_id = Some(attributes.client_names.join(","));
+        }
+    }
+
+    pub fn set_request(&mut self, method: &http::Method, path: &str) {
+        self.method = Some(method.to_string());
+        self.path = Some(path.to_string());
+    }
+
+    pub fn set_status(&mut self, status: http::StatusCode) {
+        self.status = Some(status.as_u16());
+    }
+
+    pub fn set_backend(&mut self, backend: SocketAddr) {
+        self.backend = Some(backend);
+    }
+
+    pub fn set_handshake(&mut self, duration: Duration) {
+        self.handshake_ms = Some(millis(duration));
+    }
+
+    pub fn set_connect(&mut self, duration: Duration) {
+        self.connect_ms = Some(millis(duration));
+    }
+
+    pub fn set_transferred(&mut self, transferred: &Transferred) {
+        self.bytes_received = transferred.received();
+        self.bytes_sent = transferred.sent();
+    }
+
+    pub fn set_duration(&mut self, duration: Duration) {
+        self.duration_ms = millis(duration);
+    }
+
+    /// Why the connection or request ended, such as `client_closed` or
+    /// `connect_failed`.
+    pub fn set_termination(&mut self, termination: &'static str) {
+        self.termination = termination;
+    }
+
+    /// Records a failure to get a backend connection, telling apart the
+    /// upstream being empty or overloaded from failed connects.
+    pub fn set_connect_error(&mut self, error: &eyre::Report) {
+        self.termination = if error.is::<NoBackend>() {
+            "no_backend"
+        } else if error.is::<Overflow>() {
+            "circuit_breaker_open"
+        } else {
+            "connect_failed"
+        };
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+enum Segment {
+    Literal(String),
+    Field(String),
+}
+
+enum Format {
+    Json,
+    Template(Vec<Segment>),
+}
+
+impl Format {
+    fn new(config: &AccessLogConfig) -> Result<Self> {
+        match config.format() {
+            AccessLogFormat::Json => Ok(Format::Json),
+            AccessLogFormat::Template => {
+                let template = config
+                    .template()
+                    .ok_or_eyre("Access log template is missing")?;
+                Ok(Format::Template(parse_template(template)?))
+            }
+        }
+    }
+
+    fn render(&self, record: &Record) -> Result<String> {
+        let mut line = match self {
+            Format::Json => serde_json::to_string(record)?,
+            Format::Template(segments) => {
+                let fields = serde_json::to_value(record)?;
+                let mut line = String::new();
+                for segment in segments {
+                    match segment {
+                        Segment::Literal(literal) => line.push_str(literal),
+                        Segment::Field(name) => match fields.get(name) {
+                            Some(Value::String(value)) => line.push_str(value),
+                            Some(Value::Null) | None => line.push('-'),
+                            Some(value) => line.push_str(&value.to_string()),
+                        },
+                    }
+                }
+                line
+            }
+        };
+        line.push('\n');
+        Ok(line)
+    }
+}
+
+/// Splits `template` into literals and `{field}` placeholders, `{{` and `}}`
+/// standing for literal braces.
+fn parse_template(template: &str) -> Result<Vec<Segment>> {
+    let mut segments = vec![];
+    let mut literal = String::new();
+    let mut rest = template;
+    while let Some(brace) = rest.find(['{', '}']) {
+        literal.push_str(&rest[..brace]);
+        rest = &rest[brace..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            literal.push_str(&rest[..1]);
+            rest = &rest[2..];
+            continue;
+        }
+        if rest.starts_with('}') {
+            eyre::bail!("Unmatched '}}' in access log template, write '}}}}' for a literal one");
+        }
+        let end = rest
+            .find('}')
+            .ok_or_eyre("Unclosed '{' in access log template")?;
+        let name = &rest[1..end];
+        if !FIELDS.contains(&name) {
+            eyre::bail!("Unknown access log field '{}'", name);
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(std::mem::take(&mut literal)));
+        }
+        segments.push(Segment::Field(name.to_string()));
+        rest = &rest[end + 1..];
+    }
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+/// Handle to the access log, cheap to clone. Records are formatted and
+/// written by a background task, so logging never waits on the output. The
+/// default instance drops every record.
+#[derive(Clone, Default)]
+pub struct AccessLog {
+    records: Option<mpsc::Sender<Record>>,
+}
+
+impl AccessLog {
+    /// Opens the output and starts writing records to it. A file output is
+    /// reopened on SIGUSR1, after it was moved away for rotation.
+    pub fn new(config: &AccessLogConfig) -> Result<Self> {
+        let format = Format::new(config)?;
+        let path = config.path().map(PathBuf::from);
+        let output = Output::open(path, config.buffer_size())?;
+        let reopen = match output.path {
+            Some(_) => {
+                Some(signal(SignalKind::user_defined1()).wrap_err("Failed to listen for SIGUSR1")?)
+            }
+            None => None,
+        };
+
+        let (records, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(write(
+            receiver,
+            format,
+            output,
+            config.flush_interval(),
+            reopen,
+        ));
+        Ok(Self {
+            records: Some(records),
+        })
+    }
+
+    pub fn log(&self, mut record: Record) {
+        let Some(records) = &self.records else {
+            return;
+        };
+        record.timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        if records.try_send(record).is_err() {
+            debug!("Access log queue full, dropping record");
+        }
+    }
+}
+
+struct Output {
+    path: Option<PathBuf>,
+    buffer_size: usize,
+    writer: BufWriter<Box<dyn AsyncWrite + Send + Unpin>>,
+}
+
+impl Output {
+    fn open(path: Option<PathBuf>, buffer_size: usize) -> Result<Self> {
+        let inner: Box<dyn AsyncWrite + Send + Unpin> = match &path {
+            Some(path) => Box::new(tokio::fs::File::from_std(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .wrap_err_with(|| format!("Failed to open access log {}", path.display()))?,
+            )),
+            None => Box::new(tokio::io::stdout()),
+        };
+        Ok(Self {
+            path,
+            buffer_size,
+            writer: BufWriter::with_capacity(buffer_size, inner),
+        })
+    }
+
+    async fn write(&mut self, line: &str) -> Result<()> {
+        self.writer.write_all(line.as_bytes()).await?;
+        if self.buffer_size == 0 {
+            self.writer.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn reopen(&mut self) -> Result<()> {
+        self.writer.flush().await?;
+        *self = Self::open(self.path.clone(), self.buffer_size)?;
+        Ok(())
+    }
+}
+
+async fn write(
+    mut records: mpsc::Receiver<Record>,
+    format: Format,
+    mut output: Output,
+    flush_interval: Duration,
+    mut reopen: Option<Signal>,
+) {
+    let mut flush = tokio::time::interval(flush_interval.max(Duration::from_millis(1)));
+    loop {
+        let result = tokio::select! {
+            record = records.recv() => match record {
+                Some(record) => match format.render(&record) {
+                    Ok(line) => output.write(&line).await,
+                    Err(e) => Err(e),
+                },
+                None => {
+                    if let Err(e) = output.writer.flush().await {
+                        error!("Failed to flush access log: {:?}", e);
+                    }
+                    return;
+                }
+            },
+            _ = flush.tick() => output.writer.flush().await.map_err(Into::into),
+            Some(()) = async { reopen.as_mut()?.recv().await } => {
+                info!("Reopening access log");
+                output.reopen().await
+            }
+        };
+        if let Err(e) = result {
+            error!("Failed to write access log: {:?}", e);
+        }
+    }
+}
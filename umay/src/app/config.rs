@@ -1,11 +1,13 @@
 use config::{Environment, File};
 use eyre::{Context, Result};
-use hickory_resolver::config::{NameServerConfig, ResolverConfig, ResolverOpts};
+use hickory_resolver::config::{LookupIpStrategy, NameServerConfig, ResolverConfig, ResolverOpts};
+use hickory_resolver::system_conf::read_system_conf;
 use hickory_resolver::Name;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Read;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
 use std::time::Duration;
 use std::{env, fs};
 use tracing::warn;
@@ -20,6 +22,17 @@ pub struct UmayConfig {
     shutdown_grace_period: u64,
     stream: Option<StreamConfig>, // Optional stream config
     http: Option<HttpConfig>,     // Optional http config
+    #[serde(default)]
+    refresh: RefreshConfig, // Default discovery refresh for all upstreams
+    dns: Option<DnsConfig>,       // Default resolver settings for all upstreams
+    zone: Option<String>,         // Zone this instance runs in
+    admin: Option<AdminConfig>,   // Admin endpoints such as /metrics, disabled when unset
+    #[serde(default)]
+    probes: Option<ProbeConfig>, // Liveness and readiness on their own port, disabled when unset
+    access_log: Option<AccessLogConfig>, // Access log records, disabled when unset
+    #[serde(default)]
+    logging: LoggingConfig,
+    telemetry: Option<TelemetryConfig>, // OpenTelemetry traces, disabled when unset
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -46,7 +59,22 @@ impl StreamConfig {
 pub struct Upstream {
     load_balancer: LoadBalancer,
     service_discovery: ServiceDiscovery,
+    #[serde(default)]
     servers: Vec<UpstreamServer>,
+    maglev: Option<MaglevConfig>,
+    p2c_ewma: Option<P2cEwmaConfig>,
+    #[serde(default)]
+    slow_start: u64, // in seconds, 0 disables
+    refresh: Option<RefreshConfig>, // Overrides the top-level refresh
+    dns: Option<DnsConfig>,         // Overrides the top-level dns
+    file: Option<FileDiscoveryConfig>, // Required by the `file` service discovery
+    kubernetes: Option<KubernetesConfig>, // Required by the `kubernetes` service discovery
+    consul: Option<ConsulConfig>,   // Required by the `consul` service discovery
+    locality: Option<LocalityConfig>, // Prefers backends in the zone of this instance
+    subset: Option<SubsetConfig>,   // Fallback for routes targeting a subset
+    circuit_breaker: Option<CircuitBreakerConfig>, // Unlimited when unset
+    #[serde(default)]
+    retries: usize, // Other backends tried after a failed connect
 }
 
 impl Upstream {
@@ -62,6 +90,54 @@ impl Upstream {
         self.servers.as_ref()
     }
 
+    pub fn maglev(&self) -> Option<&MaglevConfig> {
+        self.maglev.as_ref()
+    }
+
+    pub fn p2c_ewma(&self) -> Option<&P2cEwmaConfig> {
+        self.p2c_ewma.as_ref()
+    }
+
+    pub fn slow_start(&self) -> Duration {
+        Duration::from_secs(self.slow_start)
+    }
+
+    pub fn refresh(&self) -> Option<&RefreshConfig> {
+        self.refresh.as_ref()
+    }
+
+    pub fn dns(&self) -> Option<&DnsConfig> {
+        self.dns.as_ref()
+    }
+
+    pub fn file(&self) -> Option<&FileDiscoveryConfig> {
+        self.file.as_ref()
+    }
+
+    pub fn kubernetes(&self) -> Option<&KubernetesConfig> {
+        self.kubernetes.as_ref()
+    }
+
+    pub fn consul(&self) -> Option<&ConsulConfig> {
+        self.consul.as_ref()
+    }
+
+    pub fn locality(&self) -> Option<&LocalityConfig> {
+        self.locality.as_ref()
+    }
+
+    pub fn subset(&self) -> Option<&SubsetConfig> {
+        self.subset.as_ref()
+    }
+
+    pub fn circuit_breaker(&self) -> Option<&CircuitBreakerConfig> {
+        self.circuit_breaker.as_ref()
+    }
+
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
     pub fn new(
         load_balancer: LoadBalancer,
         service_discovery: ServiceDiscovery,
@@ -71,14 +147,598 @@ impl Upstream {
             load_balancer,
             service_discovery,
             servers,
+            maglev: None,
+            p2c_ewma: None,
+            slow_start: 0,
+            refresh: None,
+            dns: None,
+            file: None,
+            kubernetes: None,
+            consul: None,
+            locality: None,
+            subset: None,
+            circuit_breaker: None,
+            retries: 0,
+        }
+    }
+
+    pub fn set_maglev(&mut self, maglev: Option<MaglevConfig>) {
+        self.maglev = maglev;
+    }
+
+    pub fn set_p2c_ewma(&mut self, p2c_ewma: Option<P2cEwmaConfig>) {
+        self.p2c_ewma = p2c_ewma;
+    }
+
+    pub fn set_slow_start(&mut self, slow_start: u64) {
+        self.slow_start = slow_start;
+    }
+
+    pub fn set_refresh(&mut self, refresh: Option<RefreshConfig>) {
+        self.refresh = refresh;
+    }
+
+    pub fn set_dns(&mut self, dns: Option<DnsConfig>) {
+        self.dns = dns;
+    }
+
+    pub fn set_file(&mut self, file: Option<FileDiscoveryConfig>) {
+        self.file = file;
+    }
+
+    pub fn set_kubernetes(&mut self, kubernetes: Option<KubernetesConfig>) {
+        self.kubernetes = kubernetes;
+    }
+
+    pub fn set_consul(&mut self, consul: Option<ConsulConfig>) {
+        self.consul = consul;
+    }
+
+    pub fn set_locality(&mut self, locality: Option<LocalityConfig>) {
+        self.locality = locality;
+    }
+
+    pub fn set_subset(&mut self, subset: Option<SubsetConfig>) {
+        self.subset = subset;
+    }
+
+    pub fn set_circuit_breaker(&mut self, circuit_breaker: Option<CircuitBreakerConfig>) {
+        self.circuit_breaker = circuit_breaker;
+    }
+
+    pub fn set_retries(&mut self, retries: usize) {
+        self.retries = retries;
+    }
+}
+
+/// Limits of an upstream as a whole and of each of its backends, overflowing
+/// connections are closed and requests answered with 503.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CircuitBreakerConfig {
+    #[serde(default)]
+    upstream: CircuitLimits,
+    #[serde(default)]
+    backend: CircuitLimits, // `max_retries` only applies to the upstream
+}
+
+impl CircuitBreakerConfig {
+    pub fn upstream(&self) -> &CircuitLimits {
+        &self.upstream
+    }
+
+    pub fn backend(&self) -> &CircuitLimits {
+        &self.backend
+    }
+
+    pub fn new(upstream: CircuitLimits, backend: CircuitLimits) -> Self {
+        Self { upstream, backend }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CircuitLimits {
+    #[serde(default)]
+    max_connections: usize, // 0 means unlimited
+    #[serde(default)]
+    max_pending: usize, // Connects in progress, 0 means unlimited
+    #[serde(default)]
+    max_requests: usize, // Concurrent HTTP requests, 0 means unlimited
+    #[serde(default)]
+    max_retries: usize, // Concurrent retries, 0 means unlimited
+}
+
+impl CircuitLimits {
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    pub fn max_pending(&self) -> usize {
+        self.max_pending
+    }
+
+    pub fn max_requests(&self) -> usize {
+        self.max_requests
+    }
+
+    pub fn max_retries(&self) -> usize {
+        self.max_retries
+    }
+
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    pub fn set_max_pending(&mut self, max_pending: usize) {
+        self.max_pending = max_pending;
+    }
+
+    pub fn set_max_requests(&mut self, max_requests: usize) {
+        self.max_requests = max_requests;
+    }
+
+    pub fn set_max_retries(&mut self, max_retries: usize) {
+        self.max_retries = max_retries;
+    }
+}
+
+/// Keeps traffic in the zone of this instance while enough of the local
+/// capacity is healthy.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LocalityConfig {
+    zone: Option<String>, // Overrides the top-level zone
+    #[serde(default = "default_locality_label")]
+    label: String, // Backend metadata holding the zone
+    #[serde(default = "default_spillover_threshold")]
+    spillover_threshold: f64, // Healthy fraction of the local weight below which other zones are used
+}
+
+impl LocalityConfig {
+    pub fn zone(&self) -> Option<&str> {
+        self.zone.as_deref()
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn spillover_threshold(&self) -> f64 {
+        self.spillover_threshold.clamp(0.0, 1.0)
+    }
+
+    pub fn new(zone: Option<String>) -> Self {
+        Self {
+            zone,
+            label: default_locality_label(),
+            spillover_threshold: default_spillover_threshold(),
+        }
+    }
+
+    pub fn set_label(&mut self, label: String) {
+        self.label = label;
+    }
+
+    pub fn set_spillover_threshold(&mut self, spillover_threshold: f64) {
+        self.spillover_threshold = spillover_threshold;
+    }
+}
+
+/// How an upstream answers routes targeting a subset without backends.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SubsetConfig {
+    #[serde(default)]
+    fallback: SubsetFallback,
+    #[serde(default)]
+    default_subset: BTreeMap<String, String>, // Used by the `default_subset` fallback
+}
+
+impl SubsetConfig {
+    pub fn fallback(&self) -> &SubsetFallback {
+        &self.fallback
+    }
+
+    pub fn default_subset(&self) -> &BTreeMap<String, String> {
+        &self.default_subset
+    }
+
+    pub fn new(fallback: SubsetFallback, default_subset: BTreeMap<String, String>) -> Self {
+        Self {
+            fallback,
+            default_subset,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SubsetFallback {
+    #[default]
+    Any,
+    None,
+    DefaultSubset,
+}
+
+/// Targets a subset of the upstream backends with the connections or
+/// requests matching all of its conditions.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteConfig {
+    #[serde(rename = "match", default)]
+    conditions: RouteMatch, // Matches everything when empty
+    #[serde(default)]
+    subset: BTreeMap<String, String>, // Backend metadata, the whole upstream when empty
+}
+
+impl RouteConfig {
+    pub fn conditions(&self) -> &RouteMatch {
+        &self.conditions
+    }
+
+    pub fn subset(&self) -> &BTreeMap<String, String> {
+        &self.subset
+    }
+
+    pub fn new(conditions: RouteMatch, subset: BTreeMap<String, String>) -> Self {
+        Self { conditions, subset }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RouteMatch {
+    sni: Option<String>,       // Server name, `*.example.com` matches subdomains
+    alpn: Option<String>,      // Negotiated protocol, e.g. h2
+    client_id: Option<String>, // DNS name of the client certificate
+    #[serde(default)]
+    headers: BTreeMap<String, String>, // HTTP request headers
+}
+
+impl RouteMatch {
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+
+    pub fn alpn(&self) -> Option<&str> {
+        self.alpn.as_deref()
+    }
+
+    pub fn client_id(&self) -> Option<&str> {
+        self.client_id.as_deref()
+    }
+
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+
+    pub fn set_sni(&mut self, sni: Option<String>) {
+        self.sni = sni;
+    }
+
+    pub fn set_alpn(&mut self, alpn: Option<String>) {
+        self.alpn = alpn;
+    }
+
+    pub fn set_client_id(&mut self, client_id: Option<String>) {
+        self.client_id = client_id;
+    }
+
+    pub fn set_headers(&mut self, headers: BTreeMap<String, String>) {
+        self.headers = headers;
+    }
+}
+
+fn default_locality_label() -> String {
+    "zone".to_string()
+}
+
+fn default_spillover_threshold() -> f64 {
+    0.7
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileDiscoveryConfig {
+    path: String,
+    format: Option<FileFormat>, // Inferred from the file extension when unset
+}
+
+impl FileDiscoveryConfig {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn format(&self) -> Option<FileFormat> {
+        self.format.clone()
+    }
+
+    pub fn new(path: String, format: Option<FileFormat>) -> Self {
+        Self { path, format }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    Json,
+    Yaml,
+}
+
+impl FileFormat {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Ok(FileFormat::Json),
+            Some("yaml") | Some("yml") => Ok(FileFormat::Yaml),
+            _ => Err(eyre::eyre!(
+                "Cannot infer the format of {}, set it to json or yaml",
+                path.display()
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KubernetesConfig {
+    service: String,            // namespace/service:port, the port is a name or a number
+    kubeconfig: Option<String>, // Uses the in-cluster service account when unset
+    zone: Option<String>,       // Zone of this instance, enables topology aware hints
+}
+
+impl KubernetesConfig {
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn kubeconfig(&self) -> Option<&str> {
+        self.kubeconfig.as_deref()
+    }
+
+    pub fn zone(&self) -> Option<&str> {
+        self.zone.as_deref()
+    }
+
+    pub fn new(service: String) -> Self {
+        Self {
+            service,
+            kubeconfig: None,
+            zone: None,
+        }
+    }
+
+    pub fn set_kubeconfig(&mut self, kubeconfig: Option<String>) {
+        self.kubeconfig = kubeconfig;
+    }
+
+    pub fn set_zone(&mut self, zone: Option<String>) {
+        self.zone = zone;
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConsulConfig {
+    service: String,
+    #[serde(default = "default_consul_address")]
+    address: String, // URL of the Consul agent
+    datacenter: Option<String>, // Datacenter of the agent when unset
+    tag: Option<String>,        // Only instances with this tag
+    token: Option<String>,      // ACL token
+    #[serde(default = "default_consul_wait")]
+    wait: u64, // in seconds, maximum duration of a blocking query
+}
+
+impl ConsulConfig {
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn datacenter(&self) -> Option<&str> {
+        self.datacenter.as_deref()
+    }
+
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn wait(&self) -> Duration {
+        Duration::from_secs(self.wait)
+    }
+
+    pub fn new(service: String) -> Self {
+        Self {
+            service,
+            address: default_consul_address(),
+            datacenter: None,
+            tag: None,
+            token: None,
+            wait: default_consul_wait(),
         }
     }
+
+    pub fn set_address(&mut self, address: String) {
+        self.address = address;
+    }
+
+    pub fn set_datacenter(&mut self, datacenter: Option<String>) {
+        self.datacenter = datacenter;
+    }
+
+    pub fn set_tag(&mut self, tag: Option<String>) {
+        self.tag = tag;
+    }
+
+    pub fn set_token(&mut self, token: Option<String>) {
+        self.token = token;
+    }
+
+    pub fn set_wait(&mut self, wait: u64) {
+        self.wait = wait;
+    }
+}
+
+fn default_consul_address() -> String {
+    "http://127.0.0.1:8500".to_string()
+}
+
+fn default_consul_wait() -> u64 {
+    60
+}
+
+/// Controls how often service discovery runs. Without a fixed `interval` the
+/// refresh follows the TTL reported by discovery, clamped to
+/// `min_interval..=max_interval`, and every delay is randomized by `jitter`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RefreshConfig {
+    interval: Option<u64>, // in seconds, ignores record TTLs when set
+    #[serde(default = "default_min_refresh_interval")]
+    min_interval: u64, // in seconds
+    #[serde(default = "default_max_refresh_interval")]
+    max_interval: u64, // in seconds
+    #[serde(default = "default_refresh_jitter")]
+    jitter: f64, // fraction of the interval, 0.1 means +/-10%
+    #[serde(default = "default_stale_grace_period")]
+    stale_grace_period: u64, // in seconds, keeps the last backends on errors
+}
+
+impl Default for RefreshConfig {
+    fn default() -> Self {
+        Self {
+            interval: None,
+            min_interval: default_min_refresh_interval(),
+            max_interval: default_max_refresh_interval(),
+            jitter: default_refresh_jitter(),
+            stale_grace_period: default_stale_grace_period(),
+        }
+    }
+}
+
+impl RefreshConfig {
+    /// Fallback interval for sources that do not report a TTL.
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval.map(Duration::from_secs)
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        Duration::from_secs(self.min_interval)
+    }
+
+    pub fn max_interval(&self) -> Duration {
+        Duration::from_secs(self.max_interval.max(self.min_interval))
+    }
+
+    pub fn jitter(&self) -> f64 {
+        self.jitter.clamp(0.0, 1.0)
+    }
+
+    pub fn stale_grace_period(&self) -> Duration {
+        Duration::from_secs(self.stale_grace_period)
+    }
+
+    /// Returns the delay until the next refresh for a result valid for `ttl`.
+    pub fn next_interval(&self, ttl: Option<Duration>) -> Duration {
+        match self.interval() {
+            Some(interval) => interval,
+            None => ttl
+                .unwrap_or(Self::DEFAULT_INTERVAL)
+                .clamp(self.min_interval(), self.max_interval()),
+        }
+    }
+
+    pub fn set_interval(&mut self, interval: Option<u64>) {
+        self.interval = interval;
+    }
+
+    pub fn set_min_interval(&mut self, min_interval: u64) {
+        self.min_interval = min_interval;
+    }
+
+    pub fn set_max_interval(&mut self, max_interval: u64) {
+        self.max_interval = max_interval;
+    }
+
+    pub fn set_jitter(&mut self, jitter: f64) {
+        self.jitter = jitter;
+    }
+
+    pub fn set_stale_grace_period(&mut self, stale_grace_period: u64) {
+        self.stale_grace_period = stale_grace_period;
+    }
+}
+
+fn default_min_refresh_interval() -> u64 {
+    5
+}
+
+fn default_max_refresh_interval() -> u64 {
+    300
+}
+
+fn default_refresh_jitter() -> f64 {
+    0.1
+}
+
+fn default_stale_grace_period() -> u64 {
+    300
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MaglevConfig {
+    table_size: usize,
+}
+
+impl MaglevConfig {
+    pub fn table_size(&self) -> usize {
+        self.table_size
+    }
+
+    pub fn new(table_size: usize) -> Self {
+        Self { table_size }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct P2cEwmaConfig {
+    decay: u64,       // in seconds
+    default_rtt: u64, // in milliseconds
+}
+
+impl P2cEwmaConfig {
+    pub fn decay(&self) -> Duration {
+        Duration::from_secs(self.decay)
+    }
+
+    pub fn default_rtt(&self) -> Duration {
+        Duration::from_millis(self.default_rtt)
+    }
+
+    pub fn new(decay: u64, default_rtt: u64) -> Self {
+        Self { decay, default_rtt }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UpstreamServer {
     address: String,
-    port: u16,
+    #[serde(default)]
+    port: u16, // Taken from the SRV records with `dns_srv` discovery
+    #[serde(default = "default_weight")]
+    weight: usize,
+    #[serde(default)]
+    backup: bool, // Only used when all primary servers are unavailable
+    #[serde(default)]
+    down: bool, // Permanently excluded from selection
+    #[serde(default = "default_max_fails")]
+    max_fails: usize, // 0 disables failure accounting
+    #[serde(default = "default_fail_timeout")]
+    fail_timeout: u64, // in seconds
+    #[serde(default)]
+    max_conns: usize, // 0 means unlimited
+    #[serde(default)]
+    metadata: BTreeMap<String, String>, // Labels such as zone, version or canary
 }
 
 impl UpstreamServer {
@@ -90,13 +750,91 @@ impl UpstreamServer {
         self.port
     }
 
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+
+    pub fn backup(&self) -> bool {
+        self.backup
+    }
+
+    pub fn down(&self) -> bool {
+        self.down
+    }
+
+    pub fn max_fails(&self) -> usize {
+        self.max_fails
+    }
+
+    pub fn fail_timeout(&self) -> Duration {
+        Duration::from_secs(self.fail_timeout)
+    }
+
+    pub fn max_conns(&self) -> usize {
+        self.max_conns
+    }
+
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
     pub fn to_socket_addrs(&self) -> Result<SocketAddr> {
         Ok(SocketAddr::new(self.address.parse()?, self.port))
     }
 
     pub fn new(address: String, port: u16) -> Self {
-        Self { address, port }
+        Self {
+            address,
+            port,
+            weight: default_weight(),
+            backup: false,
+            down: false,
+            max_fails: default_max_fails(),
+            fail_timeout: default_fail_timeout(),
+            max_conns: 0,
+            metadata: BTreeMap::new(),
+        }
     }
+
+    pub fn set_weight(&mut self, weight: usize) {
+        self.weight = weight;
+    }
+
+    pub fn set_backup(&mut self, backup: bool) {
+        self.backup = backup;
+    }
+
+    pub fn set_down(&mut self, down: bool) {
+        self.down = down;
+    }
+
+    pub fn set_max_fails(&mut self, max_fails: usize) {
+        self.max_fails = max_fails;
+    }
+
+    pub fn set_fail_timeout(&mut self, fail_timeout: u64) {
+        self.fail_timeout = fail_timeout;
+    }
+
+    pub fn set_max_conns(&mut self, max_conns: usize) {
+        self.max_conns = max_conns;
+    }
+
+    pub fn set_metadata(&mut self, metadata: BTreeMap<String, String>) {
+        self.metadata = metadata;
+    }
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+fn default_max_fails() -> usize {
+    1
+}
+
+fn default_fail_timeout() -> u64 {
+    10
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -105,6 +843,11 @@ pub struct StreamServer {
     listen: ListenConfig,
     proxy_pass: String, // The proxy_pass is now a string that maps to a dynamic upstream
     tls: Option<TlsConfig>, // TLS configuration encapsulated here
+    #[serde(default)]
+    routes: Vec<RouteConfig>, // Targets subsets of the upstream
+    rate_limit: Option<RateLimitConfig>, // Limits per client, unlimited when unset
+    global_rate_limit: Option<GlobalRateLimitConfig>, // Limits shared by all instances
+    acl: Option<AclConfig>, // Client addresses allowed or denied, all allowed when unset
 }
 
 impl StreamServer {
@@ -124,6 +867,22 @@ impl StreamServer {
         self.tls.as_ref()
     }
 
+    pub fn routes(&self) -> &[RouteConfig] {
+        &self.routes
+    }
+
+    pub fn rate_limit(&self) -> Option<&RateLimitConfig> {
+        self.rate_limit.as_ref()
+    }
+
+    pub fn global_rate_limit(&self) -> Option<&GlobalRateLimitConfig> {
+        self.global_rate_limit.as_ref()
+    }
+
+    pub fn acl(&self) -> Option<&AclConfig> {
+        self.acl.as_ref()
+    }
+
     pub fn new(
         name: String,
         listen: ListenConfig,
@@ -135,6 +894,10 @@ impl StreamServer {
             listen,
             proxy_pass,
             tls,
+            routes: vec![],
+            rate_limit: None,
+            global_rate_limit: None,
+            acl: None,
         }
     }
 
@@ -153,6 +916,22 @@ impl StreamServer {
     pub fn set_tls(&mut self, tls: Option<TlsConfig>) {
         self.tls = tls;
     }
+
+    pub fn set_routes(&mut self, routes: Vec<RouteConfig>) {
+        self.routes = routes;
+    }
+
+    pub fn set_rate_limit(&mut self, rate_limit: Option<RateLimitConfig>) {
+        self.rate_limit = rate_limit;
+    }
+
+    pub fn set_global_rate_limit(&mut self, global_rate_limit: Option<GlobalRateLimitConfig>) {
+        self.global_rate_limit = global_rate_limit;
+    }
+
+    pub fn set_acl(&mut self, acl: Option<AclConfig>) {
+        self.acl = acl;
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -266,6 +1045,14 @@ pub struct HttpConfig {
 }
 
 impl HttpConfig {
+    pub fn new(upstreams: HashMap<String, Upstream>, servers: Vec<HttpServer>) -> Self {
+        Self { upstreams, servers }
+    }
+
+    pub fn upstream(&self, key: &str) -> Option<&Upstream> {
+        self.upstreams.get(key)
+    }
+
     pub fn upstreams(&self) -> &HashMap<String, Upstream> {
         &self.upstreams
     }
@@ -279,12 +1066,17 @@ impl HttpConfig {
 pub struct HttpServer {
     name: String,
     listen: ListenConfig,
-    tls: Option<TlsConfig>, // TLS configuration encapsulated here
-    proxy_pass: String,     // Maps to the dynamic upstream in the HashMap
-    location: LocationConfig,
-    proxy_http_version: String,
-    proxy_set_header: String,
+    tls: Option<TlsConfig>,     // TLS configuration encapsulated here
+    proxy_pass: String,         // Maps to the dynamic upstream in the HashMap
+    location: LocationConfig,   // Requests outside its path are answered with 404
+    proxy_http_version: String, // 1.0 or 1.1, used for requests to the backends
+    proxy_set_header: String,   // "Name: value" set on proxied requests, '' removes the header
     keepalive_timeout: usize,
+    #[serde(default)]
+    routes: Vec<RouteConfig>, // Targets subsets of the upstream
+    rate_limit: Option<RateLimitConfig>, // Limits per client, unlimited when unset
+    global_rate_limit: Option<GlobalRateLimitConfig>, // Limits shared by all instances
+    acl: Option<AclConfig>, // Client addresses allowed or denied, all allowed when unset
 }
 
 impl HttpServer {
@@ -316,10 +1108,64 @@ impl HttpServer {
         &self.proxy_set_header
     }
 
+    /// Name and unquoted value of `proxy_set_header`, None when it is not in
+    /// the `Name: value` form.
+    pub fn proxy_header(&self) -> Option<(&str, &str)> {
+        let (name, value) = self.proxy_set_header.split_once(':')?;
+        let value = value.trim();
+        let unquoted = ['\'', '"']
+            .into_iter()
+            .find_map(|quote| value.strip_prefix(quote)?.strip_suffix(quote));
+        Some((name.trim(), unquoted.unwrap_or(value)))
+    }
+
     pub fn keepalive_timeout(&self) -> usize {
         self.keepalive_timeout
     }
 
+    pub fn routes(&self) -> &[RouteConfig] {
+        &self.routes
+    }
+
+    pub fn rate_limit(&self) -> Option<&RateLimitConfig> {
+        self.rate_limit.as_ref()
+    }
+
+    pub fn global_rate_limit(&self) -> Option<&GlobalRateLimitConfig> {
+        self.global_rate_limit.as_ref()
+    }
+
+    pub fn acl(&self) -> Option<&AclConfig> {
+        self.acl.as_ref()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        listen: ListenConfig,
+        tls: Option<TlsConfig>,
+        proxy_pass: String,
+        location: LocationConfig,
+        proxy_http_version: String,
+        proxy_set_header: String,
+        keepalive_timeout: usize,
+    ) -> Self {
+        Self {
+            name,
+            listen,
+            tls,
+            proxy_pass,
+            location,
+            proxy_http_version,
+            proxy_set_header,
+            keepalive_timeout,
+            routes: vec![],
+            rate_limit: None,
+            global_rate_limit: None,
+            acl: None,
+        }
+    }
+
     pub fn set_name(&mut self, name: String) {
         self.name = name;
     }
@@ -344,18 +1190,332 @@ impl HttpServer {
         self.proxy_http_version = proxy_http_version;
     }
 
-    pub fn set_proxy_set_header(&mut self, proxy_set_header: String) {
-        self.proxy_set_header = proxy_set_header;
+    pub fn set_proxy_set_header(&mut self, proxy_set_header: String) {
+        self.proxy_set_header = proxy_set_header;
+    }
+
+    pub fn set_keepalive_timeout(&mut self, keepalive_timeout: usize) {
+        self.keepalive_timeout = keepalive_timeout;
+    }
+
+    pub fn set_routes(&mut self, routes: Vec<RouteConfig>) {
+        self.routes = routes;
+    }
+
+    pub fn set_rate_limit(&mut self, rate_limit: Option<RateLimitConfig>) {
+        self.rate_limit = rate_limit;
+    }
+
+    pub fn set_global_rate_limit(&mut self, global_rate_limit: Option<GlobalRateLimitConfig>) {
+        self.global_rate_limit = global_rate_limit;
+    }
+
+    pub fn set_acl(&mut self, acl: Option<AclConfig>) {
+        self.acl = acl;
+    }
+
+    /// Rate of requests per client, the one of the location taking
+    /// precedence over the one of the listener.
+    pub fn request_rate(&self) -> Option<&RateConfig> {
+        self.location
+            .request_rate()
+            .or_else(|| self.rate_limit.as_ref()?.request_rate())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LocationConfig {
+    path: String,
+    request_rate: Option<RateConfig>, // Requests per client, overrides the listener's
+}
+
+impl LocationConfig {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn request_rate(&self) -> Option<&RateConfig> {
+        self.request_rate.as_ref()
+    }
+
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            request_rate: None,
+        }
+    }
+
+    pub fn set_request_rate(&mut self, request_rate: Option<RateConfig>) {
+        self.request_rate = request_rate;
+    }
+}
+
+/// Limits applied to each client address, or to each network of clients when
+/// the prefixes are shorter than an address. Connections over a limit are
+/// closed, requests over a limit are answered with 429.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RateLimitConfig {
+    connection_rate: Option<RateConfig>, // New connections per client
+    #[serde(default)]
+    max_connections: usize, // Concurrent connections per client, 0 means unlimited
+    request_rate: Option<RateConfig>,    // HTTP requests per client
+    #[serde(default = "default_ipv4_prefix")]
+    ipv4_prefix: u8,
+    #[serde(default = "default_ipv6_prefix")]
+    ipv6_prefix: u8,
+    #[serde(default = "default_max_clients")]
+    max_clients: usize, // Clients tracked at once, idle ones are forgotten first and new ones rejected when all have connections
+}
+
+impl RateLimitConfig {
+    pub fn connection_rate(&self) -> Option<&RateConfig> {
+        self.connection_rate.as_ref()
+    }
+
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    pub fn request_rate(&self) -> Option<&RateConfig> {
+        self.request_rate.as_ref()
+    }
+
+    pub fn ipv4_prefix(&self) -> u8 {
+        self.ipv4_prefix
+    }
+
+    pub fn ipv6_prefix(&self) -> u8 {
+        self.ipv6_prefix
+    }
+
+    pub fn max_clients(&self) -> usize {
+        self.max_clients
+    }
+
+    pub fn new() -> Self {
+        Self {
+            connection_rate: None,
+            max_connections: 0,
+            request_rate: None,
+            ipv4_prefix: default_ipv4_prefix(),
+            ipv6_prefix: default_ipv6_prefix(),
+            max_clients: default_max_clients(),
+        }
+    }
+
+    pub fn set_connection_rate(&mut self, connection_rate: Option<RateConfig>) {
+        self.connection_rate = connection_rate;
+    }
+
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    pub fn set_request_rate(&mut self, request_rate: Option<RateConfig>) {
+        self.request_rate = request_rate;
+    }
+
+    pub fn set_prefixes(&mut self, ipv4_prefix: u8, ipv6_prefix: u8) {
+        self.ipv4_prefix = ipv4_prefix;
+        self.ipv6_prefix = ipv6_prefix;
+    }
+
+    pub fn set_max_clients(&mut self, max_clients: usize) {
+        self.max_clients = max_clients;
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A token bucket refilled at `per_second`, holding up to `burst` tokens.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RateConfig {
+    per_second: f64,
+    burst: Option<u32>, // Defaults to one second worth of tokens
+}
+
+impl RateConfig {
+    pub fn per_second(&self) -> f64 {
+        self.per_second
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+            .unwrap_or_else(|| self.per_second.ceil().max(1.0) as u32)
+    }
+
+    pub fn new(per_second: f64, burst: Option<u32>) -> Self {
+        Self { per_second, burst }
+    }
+}
+
+/// Limits shared by all instances, decided by an Envoy compatible rate limit
+/// service for each connection of a stream server and each request of an
+/// HTTP server.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GlobalRateLimitConfig {
+    endpoint: String, // gRPC service, e.g. "http://127.0.0.1:8081"
+    domain: String,
+    descriptors: Vec<DescriptorConfig>,
+    #[serde(default = "default_rate_limit_timeout")]
+    timeout: u64, // in milliseconds
+    #[serde(default)]
+    failure_mode: FailureMode, // When the service fails or times out
+}
+
+impl GlobalRateLimitConfig {
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    pub fn descriptors(&self) -> &[DescriptorConfig] {
+        &self.descriptors
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout)
+    }
+
+    pub fn failure_mode(&self) -> &FailureMode {
+        &self.failure_mode
+    }
+
+    pub fn new(endpoint: String, domain: String, descriptors: Vec<DescriptorConfig>) -> Self {
+        Self {
+            endpoint,
+            domain,
+            descriptors,
+            timeout: default_rate_limit_timeout(),
+            failure_mode: FailureMode::default(),
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout.as_millis() as u64;
+    }
+
+    pub fn set_failure_mode(&mut self, failure_mode: FailureMode) {
+        self.failure_mode = failure_mode;
+    }
+}
+
+/// One descriptor sent to the rate limit service. It is left out when one of
+/// its entries has no value, such as a missing header.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DescriptorConfig {
+    entries: Vec<DescriptorEntry>,
+}
+
+impl DescriptorConfig {
+    pub fn entries(&self) -> &[DescriptorEntry] {
+        &self.entries
+    }
+
+    pub fn new(entries: Vec<DescriptorEntry>) -> Self {
+        Self { entries }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DescriptorEntry {
+    key: String,
+    #[serde(flatten)]
+    source: DescriptorSource, // Where the value comes from
+}
+
+impl DescriptorEntry {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn source(&self) -> &DescriptorSource {
+        &self.source
+    }
+
+    pub fn new(key: String, source: DescriptorSource) -> Self {
+        Self { key, source }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum DescriptorSource {
+    ClientIp,
+    Sni,
+    Path,                      // HTTP only
+    Header { header: String }, // HTTP only
+    Value { value: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureMode {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// Client addresses or CIDR ranges checked right after accept. A client in a
+/// `deny` range is rejected, and when `allow` ranges are given a client must
+/// be in one of them. Rules of the file are added to the inline ones and
+/// reloaded when it changes.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AclConfig {
+    #[serde(default)]
+    allow: Vec<String>, // e.g. "10.0.0.0/8" or "2001:db8::1"
+    #[serde(default)]
+    deny: Vec<String>,
+    file: Option<String>, // .json or .yaml file with `allow` and `deny` lists
+}
+
+impl AclConfig {
+    pub fn allow(&self) -> &[String] {
+        &self.allow
+    }
+
+    pub fn deny(&self) -> &[String] {
+        &self.deny
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    pub fn new(allow: Vec<String>, deny: Vec<String>) -> Self {
+        Self {
+            allow,
+            deny,
+            file: None,
+        }
     }
 
-    pub fn set_keepalive_timeout(&mut self, keepalive_timeout: usize) {
-        self.keepalive_timeout = keepalive_timeout;
+    pub fn set_file(&mut self, file: Option<String>) {
+        self.file = file;
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct LocationConfig {
-    path: String,
+fn default_rate_limit_timeout() -> u64 {
+    100
+}
+
+fn default_ipv4_prefix() -> u8 {
+    32
+}
+
+fn default_ipv6_prefix() -> u8 {
+    64
+}
+
+fn default_max_clients() -> usize {
+    65536
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -375,13 +1535,19 @@ pub enum LoadBalancer {
     Random,
     IpHash,
     WeightedRoundRobin,
+    Maglev,
+    P2cEwma,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum ServiceDiscovery {
     Dns,
+    DnsSrv,
     Local,
+    File,
+    Kubernetes,
+    Consul,
 }
 
 impl UmayConfig {
@@ -415,6 +1581,157 @@ impl UmayConfig {
         if self.stream.is_none() && self.http.is_none() {
             eyre::bail!("At least one of 'stream' or 'http' configurations must be present.");
         }
+
+        let stream_upstreams = self.stream.iter().flat_map(|s| s.upstreams.iter());
+        let http_upstreams = self.http.iter().flat_map(|h| h.upstreams.iter());
+        for (name, upstream) in stream_upstreams.chain(http_upstreams) {
+            if matches!(upstream.service_discovery(), ServiceDiscovery::File)
+                && upstream.file().is_none()
+            {
+                eyre::bail!("Upstream '{}' uses file discovery without a 'file'", name);
+            }
+            if matches!(upstream.service_discovery(), ServiceDiscovery::Kubernetes)
+                && upstream.kubernetes().is_none()
+            {
+                eyre::bail!(
+                    "Upstream '{}' uses kubernetes discovery without a 'kubernetes'",
+                    name
+                );
+            }
+            if matches!(upstream.service_discovery(), ServiceDiscovery::Consul)
+                && upstream.consul().is_none()
+            {
+                eyre::bail!(
+                    "Upstream '{}' uses consul discovery without a 'consul'",
+                    name
+                );
+            }
+            if upstream.subset().is_some_and(|subset| {
+                *subset.fallback() == SubsetFallback::DefaultSubset
+                    && subset.default_subset().is_empty()
+            }) {
+                eyre::bail!(
+                    "Upstream '{}' falls back to the default subset without a 'default_subset'",
+                    name
+                );
+            }
+            if upstream.locality().is_some() && self.zone_for(upstream).is_none() {
+                eyre::bail!(
+                    "Upstream '{}' uses locality without a zone, set 'zone' or 'locality.zone'",
+                    name
+                );
+            }
+            for server in upstream.servers() {
+                if server.port() == 0
+                    && !matches!(upstream.service_discovery(), ServiceDiscovery::DnsSrv)
+                {
+                    eyre::bail!(
+                        "Upstream '{}' server '{}' must have a port",
+                        name,
+                        server.address()
+                    );
+                }
+                if server.weight() == 0 {
+                    eyre::bail!(
+                        "Upstream '{}' server '{}' must have a weight greater than 0",
+                        name,
+                        server.address()
+                    );
+                }
+            }
+        }
+
+        for server in self.http.iter().flat_map(|h| h.servers.iter()) {
+            if !server.location().path().starts_with('/') {
+                eyre::bail!(
+                    "Server '{}' has a location 'path' not starting with '/'",
+                    server.name()
+                );
+            }
+            if !matches!(server.proxy_http_version(), "1.0" | "1.1") {
+                eyre::bail!(
+                    "Server '{}' has an unsupported 'proxy_http_version', use 1.0 or 1.1",
+                    server.name()
+                );
+            }
+            let valid_header = server.proxy_header().is_some_and(|(name, value)| {
+                http::HeaderName::try_from(name).is_ok()
+                    && http::HeaderValue::try_from(value).is_ok()
+            });
+            if !server.proxy_set_header().trim().is_empty() && !valid_header {
+                eyre::bail!(
+                    "Server '{}' has an invalid 'proxy_set_header', use 'Name: value'",
+                    server.name()
+                );
+            }
+        }
+
+        let stream_limits = self.stream.iter().flat_map(|s| {
+            s.servers.iter().map(|server| {
+                let global = server.global_rate_limit();
+                (server.name(), server.rate_limit(), None, global)
+            })
+        });
+        let http_limits = self.http.iter().flat_map(|h| {
+            h.servers.iter().map(|server| {
+                let location = server.location().request_rate();
+                let global = server.global_rate_limit();
+                (server.name(), server.rate_limit(), location, global)
+            })
+        });
+        for (name, rate_limit, location_rate, global_rate_limit) in stream_limits.chain(http_limits)
+        {
+            let rates = rate_limit
+                .into_iter()
+                .flat_map(|limit| [limit.connection_rate(), limit.request_rate()])
+                .chain([location_rate])
+                .flatten();
+            for rate in rates {
+                if !rate.per_second().is_finite() || rate.per_second() <= 0.0 || rate.burst() == 0 {
+                    eyre::bail!(
+                        "Server '{}' has a rate limit without a positive 'per_second' and 'burst'",
+                        name
+                    );
+                }
+            }
+            if let Some(limit) = global_rate_limit {
+                if limit.descriptors().is_empty()
+                    || limit.descriptors().iter().any(|d| d.entries().is_empty())
+                {
+                    eyre::bail!(
+                        "Server '{}' has a global rate limit without descriptor entries",
+                        name
+                    );
+                }
+            }
+            if let Some(limit) = rate_limit {
+                if limit.ipv4_prefix() > 32 || limit.ipv6_prefix() > 128 {
+                    eyre::bail!("Server '{}' has a rate limit prefix out of range", name);
+                }
+                if limit.max_clients() == 0 {
+                    eyre::bail!("Server '{}' tracks no clients, set 'max_clients'", name);
+                }
+            }
+        }
+
+        if self
+            .telemetry
+            .as_ref()
+            .is_some_and(|telemetry| !(0.0..=1.0).contains(&telemetry.sample_ratio()))
+        {
+            eyre::bail!("Telemetry 'sample_ratio' must be between 0 and 1");
+        }
+
+        if *self.logging.output() == LogOutput::File && self.logging.path().is_none() {
+            eyre::bail!("Logging to a file without a 'path'");
+        }
+
+        if let Some(access_log) = &self.access_log {
+            if *access_log.format() == AccessLogFormat::Template && access_log.template().is_none()
+            {
+                eyre::bail!("Access log uses the template format without a 'template'");
+            }
+        }
         Ok(())
     }
 
@@ -464,6 +1781,83 @@ impl UmayConfig {
         self.http.as_ref()
     }
 
+    /// Returns the refresh settings of `upstream`, falling back to the
+    /// top-level ones.
+    pub fn refresh_for<'a>(&'a self, upstream: &'a Upstream) -> &'a RefreshConfig {
+        upstream.refresh().unwrap_or(&self.refresh)
+    }
+
+    pub fn set_refresh(&mut self, refresh: RefreshConfig) {
+        self.refresh = refresh;
+    }
+
+    /// Returns the resolver settings of `upstream`, falling back to the
+    /// top-level ones.
+    pub fn dns_for<'a>(&'a self, upstream: &'a Upstream) -> Option<&'a DnsConfig> {
+        upstream.dns().or(self.dns.as_ref())
+    }
+
+    pub fn set_dns(&mut self, dns: Option<DnsConfig>) {
+        self.dns = dns;
+    }
+
+    pub fn zone(&self) -> Option<&str> {
+        self.zone.as_deref()
+    }
+
+    pub fn set_zone(&mut self, zone: Option<String>) {
+        self.zone = zone;
+    }
+
+    pub fn admin(&self) -> Option<&AdminConfig> {
+        self.admin.as_ref()
+    }
+
+    pub fn set_admin(&mut self, admin: Option<AdminConfig>) {
+        self.admin = admin;
+    }
+
+    pub fn probes(&self) -> Option<&ProbeConfig> {
+        self.probes.as_ref()
+    }
+
+    pub fn set_probes(&mut self, probes: Option<ProbeConfig>) {
+        self.probes = probes;
+    }
+
+    pub fn access_log(&self) -> Option<&AccessLogConfig> {
+        self.access_log.as_ref()
+    }
+
+    pub fn set_access_log(&mut self, access_log: Option<AccessLogConfig>) {
+        self.access_log = access_log;
+    }
+
+    pub fn logging(&self) -> &LoggingConfig {
+        &self.logging
+    }
+
+    pub fn set_logging(&mut self, logging: LoggingConfig) {
+        self.logging = logging;
+    }
+
+    pub fn telemetry(&self) -> Option<&TelemetryConfig> {
+        self.telemetry.as_ref()
+    }
+
+    pub fn set_telemetry(&mut self, telemetry: Option<TelemetryConfig>) {
+        self.telemetry = telemetry;
+    }
+
+    /// The zone `upstream` treats as local, its own locality zone taking
+    /// precedence over the top-level one.
+    pub fn zone_for<'a>(&'a self, upstream: &'a Upstream) -> Option<&'a str> {
+        upstream
+            .locality()
+            .and_then(LocalityConfig::zone)
+            .or(self.zone())
+    }
+
     pub fn new(
         worker_threads: usize,
         close_timeout: u64,
@@ -479,31 +1873,409 @@ impl UmayConfig {
             shutdown_grace_period,
             stream,
             http,
+            refresh: RefreshConfig::default(),
+            dns: None,
+            zone: None,
+            admin: None,
+            probes: None,
+            access_log: None,
+            logging: LoggingConfig::default(),
+            telemetry: None,
         }
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+/// Where the admin endpoints are served, on localhost by default.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AdminConfig {
+    #[serde(default = "default_admin_address")]
+    address: IpAddr,
+    #[serde(default = "default_admin_port")]
+    port: u16,
+    token: Option<String>, // Bearer token required by the endpoints changing state
+}
+
+impl AdminConfig {
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn new(address: IpAddr, port: u16) -> Self {
+        Self {
+            address,
+            port,
+            token: None,
+        }
+    }
+
+    pub fn set_token(&mut self, token: Option<String>) {
+        self.token = token;
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self::new(default_admin_address(), default_admin_port())
+    }
+}
+
+fn default_admin_address() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+fn default_admin_port() -> u16 {
+    9901
+}
+
+/// Where `/healthz` and `/ready` are served for the orchestrator, apart from
+/// the admin endpoints which stay on localhost.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProbeConfig {
+    #[serde(default = "default_probe_address")]
+    address: IpAddr,
+    #[serde(default = "default_probe_port")]
+    port: u16,
+}
+
+impl ProbeConfig {
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+
+    pub fn new(address: IpAddr, port: u16) -> Self {
+        Self { address, port }
+    }
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self::new(default_probe_address(), default_probe_port())
+    }
+}
+
+fn default_probe_address() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
+fn default_probe_port() -> u16 {
+    9902
+}
+
+/// One record per connection, and per request on HTTP listeners.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    format: AccessLogFormat,
+    template: Option<String>, // e.g. "{client_addr} {listener} {backend} {termination}", {{ and }} for braces
+    path: Option<String>,     // Appended to, stdout when unset
+    #[serde(default = "default_access_log_buffer_size")]
+    buffer_size: usize, // in bytes, 0 writes every record through
+    #[serde(default = "default_access_log_flush_interval")]
+    flush_interval: u64, // in milliseconds
+}
+
+impl AccessLogConfig {
+    pub fn format(&self) -> &AccessLogFormat {
+        &self.format
+    }
+
+    pub fn template(&self) -> Option<&str> {
+        self.template.as_deref()
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    pub fn flush_interval(&self) -> Duration {
+        Duration::from_millis(self.flush_interval)
+    }
+
+    pub fn new(format: AccessLogFormat, path: Option<String>) -> Self {
+        Self {
+            format,
+            template: None,
+            path,
+            buffer_size: default_access_log_buffer_size(),
+            flush_interval: default_access_log_flush_interval(),
+        }
+    }
+
+    pub fn set_template(&mut self, template: Option<String>) {
+        self.template = template;
+    }
+
+    pub fn set_buffer_size(&mut self, buffer_size: usize) {
+        self.buffer_size = buffer_size;
+    }
+
+    pub fn set_flush_interval(&mut self, flush_interval: Duration) {
+        self.flush_interval = flush_interval.as_millis() as u64;
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    #[default]
+    Json,
+    Template,
+}
+
+/// Application logs, as opposed to the access log.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LoggingConfig {
+    #[serde(default = "default_log_level")]
+    level: String, // error, warn, info, debug or trace
+    #[serde(default)]
+    directives: Vec<String>, // Per module levels, e.g. "umay::balance=debug"
+    #[serde(default)]
+    format: LogFormat,
+    #[serde(default)]
+    output: LogOutput,
+    path: Option<String>, // With the file output
+}
+
+impl LoggingConfig {
+    pub fn level(&self) -> &str {
+        &self.level
+    }
+
+    pub fn directives(&self) -> &[String] {
+        &self.directives
+    }
+
+    pub fn format(&self) -> &LogFormat {
+        &self.format
+    }
+
+    pub fn output(&self) -> &LogOutput {
+        &self.output
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// The level followed by the per module directives, in `RUST_LOG` syntax.
+    pub fn filter(&self) -> String {
+        std::iter::once(self.level.as_str())
+            .chain(self.directives.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    pub fn new(level: String, format: LogFormat, output: LogOutput) -> Self {
+        Self {
+            level,
+            directives: vec![],
+            format,
+            output,
+            path: None,
+        }
+    }
+
+    pub fn set_directives(&mut self, directives: Vec<String>) {
+        self.directives = directives;
+    }
+
+    pub fn set_path(&mut self, path: Option<String>) {
+        self.path = path;
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self::new(
+            default_log_level(),
+            LogFormat::default(),
+            LogOutput::default(),
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    #[default]
+    Stdout,
+    Stderr,
+    File,
+}
+
+/// Spans of connections and requests, exported over OTLP/gRPC.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TelemetryConfig {
+    endpoint: String, // Collector, e.g. "http://127.0.0.1:4317"
+    #[serde(default = "default_service_name")]
+    service_name: String,
+    #[serde(default = "default_sample_ratio")]
+    sample_ratio: f64, // Share of new traces recorded, sampled parents are always followed
+}
+
+impl TelemetryConfig {
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    pub fn service_name(&self) -> &str {
+        &self.service_name
+    }
+
+    pub fn sample_ratio(&self) -> f64 {
+        self.sample_ratio
+    }
+
+    pub fn new(endpoint: String) -> Self {
+        Self {
+            endpoint,
+            service_name: default_service_name(),
+            sample_ratio: default_sample_ratio(),
+        }
+    }
+
+    pub fn set_sample_ratio(&mut self, sample_ratio: f64) {
+        self.sample_ratio = sample_ratio;
+    }
+}
+
+fn default_service_name() -> String {
+    "umay".to_string()
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_access_log_buffer_size() -> usize {
+    64 * 1024
+}
+
+fn default_access_log_flush_interval() -> u64 {
+    1000
+}
+
+/// Resolver settings used by DNS based discovery. Anything left unset keeps
+/// the value from the system configuration (`/etc/resolv.conf`).
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
 pub struct DnsConfig {
-    nameservers: Option<Vec<String>>,
+    nameservers: Option<Vec<NameserverConfig>>,
+    #[serde(default)]
     search: Vec<String>,
     ndots: Option<usize>,
+    timeout: Option<u64>, // in milliseconds, per attempt
+    attempts: Option<usize>,
+    ip_strategy: Option<IpStrategy>,
+    cache_size: Option<usize>, // number of cached records, positive and negative
+    positive_min_ttl: Option<u64>, // in seconds
+    positive_max_ttl: Option<u64>, // in seconds
+    negative_min_ttl: Option<u64>, // in seconds
+    negative_max_ttl: Option<u64>, // in seconds
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct NameserverConfig {
+    address: String, // ip:port
+    #[serde(default)]
+    protocol: DnsProtocol,
+    tls_name: Option<String>, // Server name to verify with `tls`
+}
+
+impl NameserverConfig {
+    pub fn new(address: String, protocol: DnsProtocol, tls_name: Option<String>) -> Self {
+        Self {
+            address,
+            protocol,
+            tls_name,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsProtocol {
+    #[default]
+    Udp,
+    Tcp,
+    Tls,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpStrategy {
+    Ipv4Only,
+    Ipv6Only,
+    Ipv4AndIpv6,
+    Ipv4ThenIpv6,
+    Ipv6ThenIpv4,
 }
 
 impl DnsConfig {
+    /// Builds the resolver settings on top of the system configuration. When
+    /// it cannot be read the nameservers have to be configured, rather than
+    /// sending internal names to public resolvers.
     pub fn into_resolver_config(self) -> eyre::Result<(ResolverConfig, ResolverOpts)> {
-        let mut config = ResolverConfig::new();
-        let mut opts = ResolverOpts::default();
-
-        if let Some(nameservers) = self.nameservers {
-            for ns in nameservers {
-                let socket_addr: SocketAddr = ns.parse().wrap_err("Invalid nameserver address")?;
-                config.add_name_server(NameServerConfig::new(
-                    socket_addr,
-                    hickory_resolver::config::Protocol::Udp,
-                ));
+        let system = read_system_conf();
+        let (mut config, mut opts) = match self.nameservers {
+            Some(nameservers) => {
+                let (domain, search, opts) = match system {
+                    Ok((config, opts)) => {
+                        (config.domain().cloned(), config.search().to_vec(), opts)
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed to read the system DNS configuration, using the configured nameservers only: {}",
+                            e
+                        );
+                        (None, vec![], ResolverOpts::default())
+                    }
+                };
+                let mut config = ResolverConfig::from_parts(domain, search, vec![]);
+                for ns in nameservers {
+                    config.add_name_server(ns.into_name_server_config()?);
+                }
+                (config, opts)
             }
-        }
+            None => system.wrap_err(
+                "Failed to read the system DNS configuration, set dns.nameservers explicitly",
+            )?,
+        };
 
         for domain in self.search {
             let name: Name = domain.parse().wrap_err("Invalid search domain")?;
@@ -513,7 +2285,84 @@ impl DnsConfig {
         if let Some(ndts) = self.ndots {
             opts.ndots = ndts;
         }
+        if let Some(timeout) = self.timeout {
+            opts.timeout = Duration::from_millis(timeout);
+        }
+        if let Some(attempts) = self.attempts {
+            opts.attempts = attempts;
+        }
+        if let Some(ip_strategy) = self.ip_strategy {
+            opts.ip_strategy = match ip_strategy {
+                IpStrategy::Ipv4Only => LookupIpStrategy::Ipv4Only,
+                IpStrategy::Ipv6Only => LookupIpStrategy::Ipv6Only,
+                IpStrategy::Ipv4AndIpv6 => LookupIpStrategy::Ipv4AndIpv6,
+                IpStrategy::Ipv4ThenIpv6 => LookupIpStrategy::Ipv4thenIpv6,
+                IpStrategy::Ipv6ThenIpv4 => LookupIpStrategy::Ipv6thenIpv4,
+            };
+        }
+        if let Some(cache_size) = self.cache_size {
+            opts.cache_size = cache_size;
+        }
+        opts.positive_min_ttl = self.positive_min_ttl.map(Duration::from_secs);
+        opts.positive_max_ttl = self.positive_max_ttl.map(Duration::from_secs);
+        opts.negative_min_ttl = self.negative_min_ttl.map(Duration::from_secs);
+        opts.negative_max_ttl = self.negative_max_ttl.map(Duration::from_secs);
 
         Ok((config, opts))
     }
+
+    pub fn set_nameservers(&mut self, nameservers: Option<Vec<NameserverConfig>>) {
+        self.nameservers = nameservers;
+    }
+
+    pub fn set_search(&mut self, search: Vec<String>) {
+        self.search = search;
+    }
+
+    pub fn set_ndots(&mut self, ndots: Option<usize>) {
+        self.ndots = ndots;
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<u64>) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_attempts(&mut self, attempts: Option<usize>) {
+        self.attempts = attempts;
+    }
+
+    pub fn set_ip_strategy(&mut self, ip_strategy: Option<IpStrategy>) {
+        self.ip_strategy = ip_strategy;
+    }
+
+    pub fn set_cache_size(&mut self, cache_size: Option<usize>) {
+        self.cache_size = cache_size;
+    }
+}
+
+impl NameserverConfig {
+    fn into_name_server_config(self) -> eyre::Result<NameServerConfig> {
+        let socket_addr: SocketAddr = self
+            .address
+            .parse()
+            .wrap_err("Invalid nameserver address")?;
+
+        let mut config = NameServerConfig::new(
+            socket_addr,
+            match self.protocol {
+                DnsProtocol::Udp => hickory_resolver::config::Protocol::Udp,
+                DnsProtocol::Tcp => hickory_resolver::config::Protocol::Tcp,
+                DnsProtocol::Tls => hickory_resolver::config::Protocol::Tls,
+            },
+        );
+
+        if self.protocol == DnsProtocol::Tls {
+            let tls_name = self.tls_name.ok_or_else(|| {
+                eyre::eyre!("Nameserver {} uses tls but has no tls_name", self.address)
+            })?;
+            config.tls_dns_name = Some(tls_name);
+        }
+
+        Ok(config)
+    }
 }
@@ -0,0 +1,132 @@
+use crate::app::config::{LogFormat, LogOutput, LoggingConfig, TelemetryConfig};
+use crate::app::telemetry;
+use eyre::{Context, OptionExt, Result};
+use once_cell::sync::OnceCell;
+use std::fs::OpenOptions;
+use std::sync::Mutex;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+type FormatLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Filter the debug signal switches to.
+const DEBUG_FILTER: &str = "debug";
+
+static LOG_FILTER: OnceCell<LogFilter> = OnceCell::new();
+
+/// The filter of the application logs, which can be changed while running.
+pub struct LogFilter {
+    handle: FilterHandle,
+    initial: String,
+}
+
+impl LogFilter {
+    pub fn current(&self) -> String {
+        self.handle
+            .with_current(ToString::to_string)
+            .unwrap_or_default()
+    }
+
+    /// Replaces the filter, given in `RUST_LOG` syntax such as
+    /// `info,umay::balance=debug`.
+    pub fn set(&self, filter: &str) -> Result<()> {
+        let filter = EnvFilter::try_new(filter).wrap_err("Invalid log filter")?;
+        self.handle.reload(filter)?;
+        info!("Log filter set to {}", self.current());
+        Ok(())
+    }
+
+    /// Goes back to the filter set at startup.
+    pub fn reset(&self) -> Result<()> {
+        self.set(&self.initial)
+    }
+}
+
+/// Installs the global subscriber of the application logs, and of the
+/// exported spans when telemetry is configured. `RUST_LOG`, when set, takes
+/// precedence over the configured level and directives. The log filter does
+/// not apply to spans, which are exported from the `info` level.
+pub fn init(
+    config: &LoggingConfig,
+    telemetry: Option<&TelemetryConfig>,
+) -> Result<&'static LogFilter> {
+    let initial = std::env::var("RUST_LOG").unwrap_or_else(|_| config.filter());
+    let filter = EnvFilter::try_new(&initial).wrap_err("Invalid log filter")?;
+    let (filter, handle) = reload::Layer::new(filter);
+    let spans = telemetry
+        .map(telemetry::init)
+        .transpose()?
+        .map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+
+    Registry::default()
+        .with(format_layer(config)?.with_filter(filter))
+        .with(spans.with_filter(LevelFilter::INFO))
+        .try_init()?;
+
+    LOG_FILTER
+        .try_insert(LogFilter { handle, initial })
+        .map_err(|_| eyre::eyre!("Logging is already initialized"))
+}
+
+/// The log filter, once logging is initialized.
+pub fn log_filter() -> Option<&'static LogFilter> {
+    LOG_FILTER.get()
+}
+
+/// Switches the log filter to `debug` on SIGUSR2, and back to the initial
+/// filter on the next one.
+pub fn toggle_debug_on_signal() -> Result<()> {
+    let log_filter = log_filter().ok_or_eyre("Logging is not initialized")?;
+    let mut signals =
+        signal(SignalKind::user_defined2()).wrap_err("Failed to listen for SIGUSR2")?;
+    tokio::spawn(async move {
+        let mut debug = false;
+        while signals.recv().await.is_some() {
+            debug = !debug;
+            let result = if debug {
+                log_filter.set(DEBUG_FILTER)
+            } else {
+                log_filter.reset()
+            };
+            if let Err(e) = result {
+                warn!("Failed to change the log filter: {:?}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn format_layer(config: &LoggingConfig) -> Result<FormatLayer> {
+    let (writer, ansi) = match config.output() {
+        LogOutput::Stdout => (BoxMakeWriter::new(std::io::stdout), true),
+        LogOutput::Stderr => (BoxMakeWriter::new(std::io::stderr), true),
+        LogOutput::File => {
+            let path = config
+                .path()
+                .ok_or_eyre("Logging to a file without a path")?;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .wrap_err_with(|| format!("Failed to open log file {}", path))?;
+            (BoxMakeWriter::new(Mutex::new(file)), false)
+        }
+    };
+
+    let layer = fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .with_thread_names(true)
+        .with_thread_ids(true)
+        .with_line_number(true);
+    Ok(match config.format() {
+        LogFormat::Text => layer.compact().boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    })
+}
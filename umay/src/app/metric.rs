@@ -1,10 +1,328 @@
+use prometheus_client::encoding::text::encode;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+type HistogramFamily<L> = Family<L, Histogram, fn() -> Histogram>;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ListenerLabels {
+    listener: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct HandshakeLabels {
+    listener: String,
+    result: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct RateLimitLabels {
+    listener: String,
+    limit: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct AclLabels {
+    listener: String,
+    reason: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct BackendLabels {
+    upstream: String,
+    backend: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct UpstreamResultLabels {
+    upstream: String,
+    result: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct OverflowLabels {
+    upstream: String,
+    limit: String,
+}
+
+/// Registry of everything Umay measures, exposed in the Prometheus text
+/// format on the admin port.
 pub struct Metrics {
-    pub name: String,
-    pub value: f64,
+    registry: Registry,
+    listeners: ListenerFamilies,
+    upstreams: UpstreamFamilies,
+}
+
+#[derive(Clone, Default)]
+struct ListenerFamilies {
+    accepted: Family<ListenerLabels, Counter>,
+    active: Family<ListenerLabels, Gauge>,
+    closed: Family<ListenerLabels, Counter>,
+    received_bytes: Family<ListenerLabels, Counter>,
+    sent_bytes: Family<ListenerLabels, Counter>,
+    handshakes: Family<HandshakeLabels, Counter>,
+    rate_limited: Family<RateLimitLabels, Counter>,
+    acl_rejections: Family<AclLabels, Counter>,
+}
+
+#[derive(Clone)]
+struct UpstreamFamilies {
+    selections: Family<BackendLabels, Counter>,
+    connect_duration: HistogramFamily<BackendLabels>,
+    refreshes: Family<UpstreamResultLabels, Counter>,
+    overflows: Family<OverflowLabels, Counter>,
+}
+
+impl Default for UpstreamFamilies {
+    fn default() -> Self {
+        Self {
+            selections: Family::default(),
+            connect_duration: Family::new_with_constructor(connect_histogram),
+            refreshes: Family::default(),
+            overflows: Family::default(),
+        }
+    }
+}
+
+/// From 0.5ms to about 16s.
+fn connect_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.0005, 2.0, 16))
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Metrics {
-    pub fn new(name: String, value: f64) -> Self {
-        Self { name, value }
+    pub fn new() -> Self {
+        let mut registry = Registry::with_prefix("umay");
+        let listeners = ListenerFamilies::default();
+        let upstreams = UpstreamFamilies::default();
+
+        registry.register(
+            "connections_accepted",
+            "Client connections accepted",
+            listeners.accepted.clone(),
+        );
+        registry.register(
+            "connections_active",
+            "Client connections being handled",
+            listeners.active.clone(),
+        );
+        registry.register(
+            "connections_closed",
+            "Client connections closed",
+            listeners.closed.clone(),
+        );
+        registry.register(
+            "received_bytes",
+            "Bytes received from clients",
+            listeners.received_bytes.clone(),
+        );
+        registry.register(
+            "sent_bytes",
+            "Bytes sent to clients",
+            listeners.sent_bytes.clone(),
+        );
+        registry.register(
+            "tls_handshakes",
+            "TLS handshakes with clients by result",
+            listeners.handshakes.clone(),
+        );
+        registry.register(
+            "rate_limited",
+            "Client connections and requests rejected by rate limits",
+            listeners.rate_limited.clone(),
+        );
+        registry.register(
+            "acl_rejections",
+            "Client connections rejected by ACLs by reason",
+            listeners.acl_rejections.clone(),
+        );
+        registry.register(
+            "backend_selections",
+            "Backends selected by the load balancer",
+            upstreams.selections.clone(),
+        );
+        registry.register(
+            "backend_connect_duration_seconds",
+            "Time taken to connect to backends",
+            upstreams.connect_duration.clone(),
+        );
+        registry.register(
+            "discovery_refreshes",
+            "Discovery refreshes by result",
+            upstreams.refreshes.clone(),
+        );
+        registry.register(
+            "circuit_breaker_overflows",
+            "Connections and requests rejected by circuit breakers",
+            upstreams.overflows.clone(),
+        );
+
+        Self {
+            registry,
+            listeners,
+            upstreams,
+        }
+    }
+
+    pub fn listener(&self, name: &str) -> ListenerMetrics {
+        ListenerMetrics {
+            name: name.to_string(),
+            families: self.listeners.clone(),
+        }
+    }
+
+    pub fn upstream(&self, name: &str) -> UpstreamMetrics {
+        UpstreamMetrics {
+            name: name.to_string(),
+            families: self.upstreams.clone(),
+            backends: Arc::default(),
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text format.
+    pub fn encode(&self) -> eyre::Result<String> {
+        let mut buffer = String::new();
+        encode(&mut buffer, &self.registry)?;
+        Ok(buffer)
+    }
+}
+
+/// Metrics of one listener. The default instance is not registered anywhere.
+#[derive(Clone, Default)]
+pub struct ListenerMetrics {
+    name: String,
+    families: ListenerFamilies,
+}
+
+impl ListenerMetrics {
+    fn labels(&self) -> ListenerLabels {
+        ListenerLabels {
+            listener: self.name.clone(),
+        }
+    }
+
+    pub fn on_accept(&self) {
+        let labels = self.labels();
+        self.families.accepted.get_or_create(&labels).inc();
+        self.families.active.get_or_create(&labels).inc();
+    }
+
+    pub fn on_close(&self) {
+        let labels = self.labels();
+        self.families.active.get_or_create(&labels).dec();
+        self.families.closed.get_or_create(&labels).inc();
+    }
+
+    pub fn on_handshake(&self, success: bool) {
+        let labels = HandshakeLabels {
+            listener: self.name.clone(),
+            result: if success { "success" } else { "failure" }.to_string(),
+        };
+        self.families.handshakes.get_or_create(&labels).inc();
+    }
+
+    pub fn on_rate_limited(&self, limit: &str) {
+        let labels = RateLimitLabels {
+            listener: self.name.clone(),
+            limit: limit.to_string(),
+        };
+        self.families.rate_limited.get_or_create(&labels).inc();
+    }
+
+    pub fn on_acl_rejection(&self, reason: &str) {
+        let labels = AclLabels {
+            listener: self.name.clone(),
+            reason: reason.to_string(),
+        };
+        self.families.acl_rejections.get_or_create(&labels).inc();
+    }
+
+    pub fn received_bytes(&self) -> Counter {
+        self.families
+            .received_bytes
+            .get_or_create(&self.labels())
+            .clone()
+    }
+
+    pub fn sent_bytes(&self) -> Counter {
+        self.families
+            .sent_bytes
+            .get_or_create(&self.labels())
+            .clone()
+    }
+}
+
+/// Metrics of one upstream. The default instance is not registered anywhere.
+#[derive(Clone, Default)]
+pub struct UpstreamMetrics {
+    name: String,
+    families: UpstreamFamilies,
+    backends: Arc<Mutex<HashSet<SocketAddr>>>,
+}
+
+impl UpstreamMetrics {
+    fn backend_labels(&self, backend: SocketAddr) -> BackendLabels {
+        BackendLabels {
+            upstream: self.name.clone(),
+            backend: backend.to_string(),
+        }
+    }
+
+    pub fn on_select(&self, backend: SocketAddr) {
+        self.families
+            .selections
+            .get_or_create(&self.backend_labels(backend))
+            .inc();
+    }
+
+    pub fn observe_connect(&self, backend: SocketAddr, duration: Duration) {
+        self.families
+            .connect_duration
+            .get_or_create(&self.backend_labels(backend))
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Drops the series of the backends no longer in `backends`, so that
+    /// churning ones do not pile up.
+    pub fn sync(&self, backends: impl IntoIterator<Item = SocketAddr>) {
+        let current: HashSet<SocketAddr> = backends.into_iter().collect();
+        let mut known = self.backends.lock().unwrap_or_else(|e| e.into_inner());
+        for &removed in known.difference(&current) {
+            let labels = self.backend_labels(removed);
+            self.families.selections.remove(&labels);
+            self.families.connect_duration.remove(&labels);
+        }
+        *known = current;
+    }
+
+    /// Counts a discovery refresh, `result` being `success`, `stale` or
+    /// `failure`.
+    pub fn on_refresh(&self, result: &str) {
+        let labels = UpstreamResultLabels {
+            upstream: self.name.clone(),
+            result: result.to_string(),
+        };
+        self.families.refreshes.get_or_create(&labels).inc();
+    }
+
+    pub fn on_overflow(&self, limit: &str) {
+        let labels = OverflowLabels {
+            upstream: self.name.clone(),
+            limit: limit.to_string(),
+        };
+        self.families.overflows.get_or_create(&labels).inc();
     }
 }
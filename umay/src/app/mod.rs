@@ -2,10 +2,15 @@ use socket2::TcpKeepalive;
 use std::time::Duration;
 use tokio::net::TcpStream;
 
+pub mod access_log;
+pub mod admin;
 pub mod config;
+pub mod logging;
 pub mod metric;
+pub mod readiness;
 pub mod server;
 pub mod signal;
+pub mod telemetry;
 
 fn set_nodelay_or_warn(socket: &TcpStream) {
     if let Err(e) = socket.set_nodelay(true) {
@@ -0,0 +1,39 @@
+use crate::balance::LoadBalancer;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Whether Umay can take traffic: every listener is bound, every upstream
+/// completed its first discovery and shutdown has not started.
+pub struct Readiness {
+    listeners: usize,
+    bound: AtomicUsize,
+    load_balancers: Vec<Arc<LoadBalancer>>,
+    shutting_down: AtomicBool,
+}
+
+impl Readiness {
+    pub fn new(listeners: usize, load_balancers: Vec<Arc<LoadBalancer>>) -> Self {
+        Self {
+            listeners,
+            bound: AtomicUsize::new(0),
+            load_balancers,
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    pub fn on_bound(&self) {
+        self.bound.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Stops reporting ready for good, so that traffic moves away while
+    /// connections are drained.
+    pub fn on_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Release);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        !self.shutting_down.load(Ordering::Acquire)
+            && self.bound.load(Ordering::Acquire) >= self.listeners
+            && self.load_balancers.iter().all(|lb| lb.discovered())
+    }
+}
@@ -1,22 +1,34 @@
+use crate::app::access_log::AccessLog;
+use crate::app::admin::{Admin, Listener};
 use crate::app::config::{
-    LoadBalancer as LoadBalancerConfig, Protocol, ServiceDiscovery as ServiceDiscoveryConfig,
-    UmayConfig, Upstream,
+    CircuitLimits, DnsConfig, LoadBalancer as LoadBalancerConfig, Protocol,
+    ServiceDiscovery as ServiceDiscoveryConfig, SubsetFallback, UmayConfig, Upstream,
 };
-use crate::app::metric::Metrics;
-use crate::balance::discovery::{DnsDiscovery, LocalDiscovery, ServiceDiscovery};
+use crate::app::metric::{ListenerMetrics, Metrics, UpstreamMetrics};
+use crate::app::readiness::Readiness;
+use crate::balance::circuit::{CircuitBreaker, Limits};
+use crate::balance::discovery::{
+    self, consul::ConsulDiscovery, file::FileDiscovery, kubernetes::KubernetesDiscovery,
+    DnsDiscovery, DnsSrvDiscovery, LocalDiscovery, ServiceDiscovery,
+};
+use crate::balance::locality::Locality;
 use crate::balance::selection::SelectionAlgorithm;
+use crate::balance::subset::Fallback;
 use crate::balance::{selection, Backends, LoadBalancer};
+use crate::proxy::acl::Acl;
+use crate::proxy::global_rate_limit::GlobalRateLimiter;
 use crate::proxy::http::HttpProxy;
+use crate::proxy::rate_limit::RateLimiter;
+use crate::proxy::route::Routes;
 use crate::proxy::stream::StreamProxy;
 use crate::tls;
 use crate::tls::credentials::Store;
 use eyre::{eyre, Context, ContextCompat, OptionExt, Result};
-use futures::StreamExt;
-use selection::{LeastConnections, Random, RoundRobin, WeightedRoundRobin};
+use futures::{future, StreamExt};
+use selection::{LeastConnections, Maglev, P2cEwma, Random, RoundRobin, WeightedRoundRobin};
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::watch;
 use tokio_stream::wrappers::TcpListenerStream;
@@ -28,15 +40,25 @@ use tracing::{debug, error, info};
 pub struct UmayServer {
     stream_proxies: Vec<StreamProxy>,
     http_proxies: Vec<HttpProxy>,
+    /// One per upstream, shared by the listeners proxying to it.
+    load_balancers: Vec<(String, Arc<LoadBalancer>)>,
     config: Arc<UmayConfig>,
-    metrics: Arc<Metrics>,
+    admin: Arc<Admin>,
+    readiness: Arc<Readiness>,
 }
 
 impl TryFrom<Arc<UmayConfig>> for UmayServer {
     type Error = eyre::Error;
 
     fn try_from(config: Arc<UmayConfig>) -> Result<Self> {
+        let metrics = Arc::new(Metrics::new());
+        let access_log = match config.access_log() {
+            Some(access_log_config) => AccessLog::new(access_log_config)?,
+            None => AccessLog::default(),
+        };
+        let mut listeners = vec![];
         let mut stream_proxies = vec![];
+        let mut load_balancers = vec![];
 
         if let Some(stream_config) = config.stream() {
             for stream_server in stream_config.servers() {
@@ -49,16 +71,49 @@ impl TryFrom<Arc<UmayConfig>> for UmayServer {
                 let upstream = stream_config
                     .upstream(stream_server.proxy_pass())
                     .wrap_err("Failed to find upstream for stream server")?;
-                let load_balancer = initialize_load_balancer(upstream)?;
+                let load_balancer = shared_load_balancer(
+                    &mut load_balancers,
+                    &config,
+                    upstream,
+                    stream_server.proxy_pass(),
+                    &metrics,
+                )?;
+                let routes = Routes::new(stream_server.routes())?;
+                listeners.push(Listener::new(
+                    stream_server.name().to_string(),
+                    stream_server.listen().port(),
+                    stream_server.listen().protocol().clone(),
+                    true,
+                    stream_server.proxy_pass().to_string(),
+                    Arc::clone(&load_balancer),
+                ));
 
                 // Handle different protocols
                 match stream_server.listen().protocol() {
                     Protocol::Tcp | Protocol::Ws => {
-                        stream_proxies.push(StreamProxy::new(
+                        let mut stream_proxy = StreamProxy::new(
                             Arc::new(stream_server.clone()),
                             tls_server,
                             load_balancer,
-                        ));
+                            routes,
+                        );
+                        stream_proxy.set_metrics(metrics.listener(stream_server.name()));
+                        stream_proxy.set_access_log(access_log.clone());
+                        if let Some(rate_limit) = stream_server.rate_limit() {
+                            stream_proxy.set_rate_limiter(RateLimiter::new(
+                                rate_limit,
+                                rate_limit.request_rate(),
+                            ));
+                        }
+                        if let Some(acl) = stream_server.acl() {
+                            stream_proxy.set_acl(Acl::new(acl)?);
+                        }
+                        if let Some(global_rate_limit) = stream_server.global_rate_limit() {
+                            stream_proxy.set_global_rate_limiter(GlobalRateLimiter::new(
+                                global_rate_limit,
+                            )?);
+                        }
+                        stream_proxies.push(stream_proxy);
                     }
                     Protocol::Udp => {
                         todo!() // UDP implementation
@@ -70,28 +125,145 @@ impl TryFrom<Arc<UmayConfig>> for UmayServer {
             }
         }
 
-        let http_proxies = vec![]; // For now, since HttpProxy isn't yet initialized
+        let mut http_proxies = vec![];
+
+        // Upstreams of the stream and http sections are not shared even when
+        // their names are the same.
+        let mut http_load_balancers = vec![];
+        if let Some(http_config) = config.http() {
+            for http_server in http_config.servers() {
+                let tls_server = match http_server.tls() {
+                    Some(tls_config) => Some(initialize_tls_server(&Store::try_from(tls_config)?)?),
+                    None => None,
+                };
+
+                let upstream = http_config
+                    .upstream(http_server.proxy_pass())
+                    .wrap_err("Failed to find upstream for http server")?;
+                let load_balancer = shared_load_balancer(
+                    &mut http_load_balancers,
+                    &config,
+                    upstream,
+                    http_server.proxy_pass(),
+                    &metrics,
+                )?;
+                let routes = Routes::new(http_server.routes())?;
+                listeners.push(Listener::new(
+                    http_server.name().to_string(),
+                    http_server.listen().port(),
+                    http_server.listen().protocol().clone(),
+                    tls_server.is_some(),
+                    http_server.proxy_pass().to_string(),
+                    Arc::clone(&load_balancer),
+                ));
+
+                let mut http_proxy = HttpProxy::new(
+                    Arc::new(http_server.clone()),
+                    tls_server,
+                    load_balancer,
+                    routes,
+                );
+                http_proxy.set_metrics(metrics.listener(http_server.name()));
+                http_proxy.set_access_log(access_log.clone());
+                let rate_limit = http_server.rate_limit().cloned().unwrap_or_default();
+                http_proxy
+                    .set_rate_limiter(RateLimiter::new(&rate_limit, http_server.request_rate()));
+                if let Some(acl) = http_server.acl() {
+                    http_proxy.set_acl(Acl::new(acl)?);
+                }
+                if let Some(global_rate_limit) = http_server.global_rate_limit() {
+                    http_proxy.set_global_rate_limiter(GlobalRateLimiter::new(global_rate_limit)?);
+                }
+                http_proxies.push(http_proxy);
+            }
+        }
+
+        load_balancers.extend(http_load_balancers);
+        let readiness = Arc::new(Readiness::new(
+            listeners.len(),
+            load_balancers
+                .iter()
+                .map(|(_, lb)| Arc::clone(lb))
+                .collect(),
+        ));
+        let admin = Admin::new(
+            Arc::clone(&config),
+            metrics,
+            Arc::clone(&readiness),
+            listeners,
+        );
 
         Ok(Self {
             stream_proxies,
             http_proxies,
+            load_balancers,
+            admin: Arc::new(admin),
+            readiness,
             config,
-            metrics: Arc::new(Metrics::new("umay".to_string(), 1.0)),
         })
     }
 }
 
 impl UmayServer {
     pub async fn run(&self, mut shutdown_rx: watch::Receiver<()>) -> Result<()> {
+        // The admin endpoints outlive the listeners so that probes see the
+        // readiness drop during the grace period.
+        let (admin_shutdown_tx, admin_shutdown_rx) = watch::channel(());
+        if let Some(admin_config) = self.config.admin() {
+            let listener = TcpListener::bind(admin_config.socket_addr())
+                .await
+                .wrap_err("Failed to bind admin listener")?;
+            let admin = Arc::clone(&self.admin);
+            let receiver = admin_shutdown_rx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = admin.serve(listener, receiver).await {
+                    error!("Error running admin endpoints: {:?}", e);
+                }
+            });
+        }
+        if let Some(probe_config) = self.config.probes() {
+            let listener = TcpListener::bind(probe_config.socket_addr())
+                .await
+                .wrap_err("Failed to bind probe listener")?;
+            let admin = Arc::clone(&self.admin);
+            let receiver = admin_shutdown_rx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = admin.serve_probes(listener, receiver).await {
+                    error!("Error running probes: {:?}", e);
+                }
+            });
+        }
+
+        for (name, load_balancer) in &self.load_balancers {
+            Self::start_load_balancer(Arc::clone(load_balancer), name).await;
+        }
+
+        // The listeners keep accepting through the grace period, while the
+        // dropped readiness moves traffic away.
+        let (listener_shutdown_tx, listener_shutdown_rx) = watch::channel(());
         for stream_proxy in self.stream_proxies.iter().cloned() {
             let port = stream_proxy.port();
-            stream_proxy
-                .load_balancer()
-                .start_refresh_task(Duration::from_secs(30));
+            let receiver = listener_shutdown_rx.clone();
+            let readiness = Arc::clone(&self.readiness);
+            let filter = ClientFilter::new(stream_proxy.acl(), stream_proxy.metrics());
+            tokio::spawn(async move {
+                if let Err(e) =
+                    Self::run_service(stream_proxy, port, filter, readiness, receiver).await
+                {
+                    error!("Error running service on port {}: {:?}", port, e);
+                }
+            });
+        }
 
-            let receiver = shutdown_rx.clone();
+        for http_proxy in self.http_proxies.iter().cloned() {
+            let port = http_proxy.port();
+            let receiver = listener_shutdown_rx.clone();
+            let readiness = Arc::clone(&self.readiness);
+            let filter = ClientFilter::new(http_proxy.acl(), http_proxy.metrics());
             tokio::spawn(async move {
-                if let Err(e) = Self::run_service(stream_proxy, port, receiver).await {
+                if let Err(e) =
+                    Self::run_service(http_proxy, port, filter, readiness, receiver).await
+                {
                     error!("Error running service on port {}: {:?}", port, e);
                 }
             });
@@ -100,24 +272,41 @@ impl UmayServer {
         tokio::select! {
             _ = shutdown_rx.changed() => {
                 info!("Shutdown signal received, starting graceful shutdown.");
-                self.shutdown().await;
+                self.readiness.on_shutdown();
+                self.shutdown(listener_shutdown_tx).await;
             }
         }
+        let _ = admin_shutdown_tx.send(());
 
         Ok(())
     }
 
+    async fn start_load_balancer(load_balancer: Arc<LoadBalancer>, upstream: &str) {
+        // Resolve backends before accepting traffic instead of waiting for
+        // the first refresh tick.
+        if let Err(e) = load_balancer.refresh().await {
+            error!(
+                "Initial discovery failed for upstream {}: {:?}",
+                upstream, e
+            );
+        }
+        load_balancer.start_refresh_task();
+    }
+
     async fn run_service<S>(
         service: S,
         port: u16,
+        filter: ClientFilter,
+        readiness: Arc<Readiness>,
         mut shutdown_rx: watch::Receiver<()>,
     ) -> Result<()>
     where
-        S: Service<TcpStream, Response=(), Error=eyre::Error> + Clone + Send + 'static,
+        S: Service<TcpStream, Response = (), Error = eyre::Error> + Clone + Send + 'static,
         S::Future: Send + 'static,
     {
-        let mut tcp_listener_stream = bind_listener(port).await?;
+        let mut tcp_listener_stream = bind_listener(port, filter).await?;
         info!("Listening on 0.0.0.0:{}", port);
+        readiness.on_bound();
 
         loop {
             tokio::select! {
@@ -148,20 +337,55 @@ impl UmayServer {
 
         Ok(())
     }
-    async fn shutdown(&self) {
+    /// Closes the listeners once the grace period is over, then leaves the
+    /// exit timeout to the open connections.
+    async fn shutdown(&self, listener_shutdown_tx: watch::Sender<()>) {
         info!(
             "Graceful shutdown: grace period {:?} starts",
             self.config.shutdown_grace_period()
         );
 
         tokio::time::sleep(self.config.shutdown_grace_period()).await;
+        info!("Graceful shutdown: grace period ends, closing listeners");
+        let _ = listener_shutdown_tx.send(());
 
         tokio::time::sleep(self.config.exit_timeout()).await;
-        info!("Graceful shutdown: grace period ends");
     }
 }
 
-async fn bind_listener(port: u16) -> Result<Pin<Box<dyn Stream<Item=Result<TcpStream>> + Send>>> {
+/// Rejects clients by the ACL of a listener, counting the rejections.
+struct ClientFilter {
+    acl: Option<Arc<Acl>>,
+    metrics: ListenerMetrics,
+}
+
+impl ClientFilter {
+    fn new(acl: Option<Arc<Acl>>, metrics: ListenerMetrics) -> Self {
+        if let Some(acl) = &acl {
+            acl.watch();
+        }
+        Self { acl, metrics }
+    }
+
+    fn accepts(&self, client: SocketAddr) -> bool {
+        let Some(acl) = &self.acl else {
+            return true;
+        };
+        match acl.check(client.ip()) {
+            Ok(()) => true,
+            Err(rejection) => {
+                debug!("Closing connection from {}: {}", client, rejection);
+                self.metrics.on_acl_rejection(rejection.as_str());
+                false
+            }
+        }
+    }
+}
+
+async fn bind_listener(
+    port: u16,
+    filter: ClientFilter,
+) -> Result<Pin<Box<dyn Stream<Item = Result<TcpStream>> + Send>>> {
     let listen_addr = format!("0.0.0.0:{}", port);
     let tcp_listener = {
         let std_tcp_listener = std::net::TcpListener::bind(&listen_addr)?;
@@ -171,31 +395,38 @@ async fn bind_listener(port: u16) -> Result<Pin<Box<dyn Stream<Item=Result<TcpSt
             .wrap_err(format!("Failed to bind to address: {}", listen_addr))?
     };
 
-    let stream = TcpListenerStream::new(tcp_listener).map(|res| {
-        let tcp = res
-            .map_err(|e| eyre!(e))
-            .wrap_err("Failed to accept connection")?;
+    let stream = TcpListenerStream::new(tcp_listener)
+        .filter_map(move |res| future::ready(accept(res, &filter).transpose()));
 
-        super::set_nodelay_or_warn(&tcp);
-        let tcp = super::set_keepalive_or_warn(tcp, None).wrap_err("Failed to set keepalive")?;
+    Ok(Box::pin(stream))
+}
 
-        fn ipv4_mapped(orig: SocketAddr) -> SocketAddr {
-            if let SocketAddr::V6(v6) = orig {
-                if let Some(ip) = v6.ip().to_ipv4_mapped() {
-                    return (ip, orig.port()).into();
-                }
+/// Sets up an accepted connection, or drops it when the client is rejected
+/// before any TLS work is done.
+fn accept(res: std::io::Result<TcpStream>, filter: &ClientFilter) -> Result<Option<TcpStream>> {
+    let tcp = res
+        .map_err(|e| eyre!(e))
+        .wrap_err("Failed to accept connection")?;
+
+    fn ipv4_mapped(orig: SocketAddr) -> SocketAddr {
+        if let SocketAddr::V6(v6) = orig {
+            if let Some(ip) = v6.ip().to_ipv4_mapped() {
+                return (ip, orig.port()).into();
             }
-            orig
         }
+        orig
+    }
 
-        let client_addr = tcp.peer_addr().wrap_err("Failed to get peer address")?;
-        let client = ipv4_mapped(client_addr);
-        debug!("Accepted connection from {}", client);
-
-        Ok(tcp)
-    });
+    let client_addr = tcp.peer_addr().wrap_err("Failed to get peer address")?;
+    let client = ipv4_mapped(client_addr);
+    if !filter.accepts(client) {
+        return Ok(None);
+    }
+    debug!("Accepted connection from {}", client);
 
-    Ok(Box::pin(stream))
+    super::set_nodelay_or_warn(&tcp);
+    let tcp = super::set_keepalive_or_warn(tcp, None).wrap_err("Failed to set keepalive")?;
+    Ok(Some(tcp))
 }
 
 fn initialize_tls_server(store: &Store) -> Result<Arc<tls::server::Server>> {
@@ -205,49 +436,148 @@ fn initialize_tls_server(store: &Store) -> Result<Arc<tls::server::Server>> {
     )))
 }
 
-fn initialize_load_balancer(upstream: &Upstream) -> Result<Arc<LoadBalancer>> {
-    let discovery = create_discovery(upstream)?;
+/// Load balancer of the upstream `name`, created on first use so that the
+/// listeners proxying to it share its discovery, health and circuit breaker.
+fn shared_load_balancer(
+    load_balancers: &mut Vec<(String, Arc<LoadBalancer>)>,
+    config: &UmayConfig,
+    upstream: &Upstream,
+    name: &str,
+    metrics: &Metrics,
+) -> Result<Arc<LoadBalancer>> {
+    if let Some((_, load_balancer)) = load_balancers.iter().find(|(n, _)| n == name) {
+        return Ok(Arc::clone(load_balancer));
+    }
+    let load_balancer = initialize_load_balancer(config, upstream, metrics.upstream(name))?;
+    load_balancers.push((name.to_string(), Arc::clone(&load_balancer)));
+    Ok(load_balancer)
+}
+
+fn initialize_load_balancer(
+    config: &UmayConfig,
+    upstream: &Upstream,
+    metrics: UpstreamMetrics,
+) -> Result<Arc<LoadBalancer>> {
+    let discovery = create_discovery(upstream, config.dns_for(upstream).cloned())?;
     let backends = Backends::new(discovery);
 
-    let balancer: LoadBalancerConfig = upstream.load_balancer().to_owned();
-    let selector = create_selector(balancer)?;
+    let selector = create_selector(upstream)?;
+
+    let mut load_balancer = LoadBalancer::new(backends, selector);
+    load_balancer.set_slow_start(upstream.slow_start());
+    load_balancer.set_refresh(config.refresh_for(upstream).clone());
+    load_balancer.set_locality(create_locality(config, upstream));
+    load_balancer.set_subset_fallback(create_subset_fallback(upstream));
+    load_balancer.set_retries(upstream.retries());
+    load_balancer.set_metrics(metrics);
+    if let Some(circuit_breaker) = upstream.circuit_breaker() {
+        load_balancer.set_circuit_breaker(CircuitBreaker::new(
+            circuit_limits(circuit_breaker.upstream()),
+            circuit_limits(circuit_breaker.backend()),
+        ));
+    }
+    Ok(Arc::new(load_balancer))
+}
+
+fn circuit_limits(config: &CircuitLimits) -> Limits {
+    Limits {
+        max_connections: config.max_connections(),
+        max_pending: config.max_pending(),
+        max_requests: config.max_requests(),
+        max_retries: config.max_retries(),
+    }
+}
+
+fn create_subset_fallback(upstream: &Upstream) -> Fallback {
+    let Some(subset) = upstream.subset() else {
+        return Fallback::default();
+    };
+    match subset.fallback() {
+        SubsetFallback::Any => Fallback::Any,
+        SubsetFallback::None => Fallback::None,
+        SubsetFallback::DefaultSubset => Fallback::Subset(subset.default_subset().clone()),
+    }
+}
 
-    Ok(Arc::new(LoadBalancer::new(backends, selector)))
+fn create_locality(config: &UmayConfig, upstream: &Upstream) -> Option<Locality> {
+    let locality = upstream.locality()?;
+    let zone = config.zone_for(upstream)?;
+    Some(Locality::new(
+        zone.to_string(),
+        locality.label().to_string(),
+        locality.spillover_threshold(),
+    ))
 }
 
 fn create_discovery(
     config: &Upstream,
+    dns_config: Option<DnsConfig>,
 ) -> Result<Box<dyn ServiceDiscovery + Send + Sync + 'static>> {
     match config.service_discovery().clone() {
         ServiceDiscoveryConfig::Dns => {
-            let us = config
+            let servers = config
                 .servers()
                 .iter()
-                .next()
-                .ok_or_else(|| eyre!("No servers found"))?;
+                .filter(|us| !us.down())
+                .cloned()
+                .collect();
 
-            let discovery = DnsDiscovery::new(us.address().to_owned(), us.port(), None)?;
+            let discovery = DnsDiscovery::new(servers, dns_config)?;
 
             Ok(Box::new(discovery))
         }
+        ServiceDiscoveryConfig::DnsSrv => {
+            let servers = config
+                .servers()
+                .iter()
+                .filter(|us| !us.down())
+                .cloned()
+                .collect();
+
+            Ok(Box::new(DnsSrvDiscovery::new(servers, dns_config)?))
+        }
         ServiceDiscoveryConfig::Local => {
             let mut backends = vec![];
-            for us in config.servers() {
-                backends.push(us.to_socket_addrs()?);
+            for us in config.servers().iter().filter(|us| !us.down()) {
+                backends.push(discovery::backend_for(us, us.to_socket_addrs()?));
             }
             Ok(Box::new(LocalDiscovery::with_backends(backends)))
         }
+        ServiceDiscoveryConfig::File => {
+            let file = config
+                .file()
+                .ok_or_eyre("File discovery requires a 'file' config")?;
+            Ok(Box::new(FileDiscovery::new(file.path(), file.format())?))
+        }
+        ServiceDiscoveryConfig::Kubernetes => {
+            let kubernetes = config
+                .kubernetes()
+                .ok_or_eyre("Kubernetes discovery requires a 'kubernetes' config")?;
+            Ok(Box::new(KubernetesDiscovery::new(kubernetes.clone())?))
+        }
+        ServiceDiscoveryConfig::Consul => {
+            let consul = config
+                .consul()
+                .ok_or_eyre("Consul discovery requires a 'consul' config")?;
+            Ok(Box::new(ConsulDiscovery::new(consul.clone())?))
+        }
     }
 }
 
-fn create_selector(
-    load_balancer: LoadBalancerConfig,
-) -> Result<Arc<dyn SelectionAlgorithm + Send + Sync>> {
-    match load_balancer {
+fn create_selector(upstream: &Upstream) -> Result<Arc<dyn SelectionAlgorithm + Send + Sync>> {
+    match upstream.load_balancer().clone() {
         LoadBalancerConfig::Random => Ok(Arc::new(Random)),
         LoadBalancerConfig::RoundRobin => Ok(Arc::new(RoundRobin::default())),
         LoadBalancerConfig::WeightedRoundRobin => Ok(Arc::new(WeightedRoundRobin::default())),
         LoadBalancerConfig::LeastConn => Ok(Arc::new(LeastConnections::default())),
         LoadBalancerConfig::IpHash => todo!(),
+        LoadBalancerConfig::Maglev => match upstream.maglev() {
+            Some(maglev) => Ok(Arc::new(Maglev::new(maglev.table_size())?)),
+            None => Ok(Arc::new(Maglev::default())),
+        },
+        LoadBalancerConfig::P2cEwma => match upstream.p2c_ewma() {
+            Some(p2c) => Ok(Arc::new(P2cEwma::new(p2c.decay(), p2c.default_rtt()))),
+            None => Ok(Arc::new(P2cEwma::default())),
+        },
     }
 }
@@ -0,0 +1,100 @@
+use crate::app::config::TelemetryConfig;
+use eyre::{Context as _, Result};
+use http::header::{HeaderName, HeaderValue};
+use http::HeaderMap;
+use once_cell::sync::OnceCell;
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::warn;
+
+static PROVIDER: OnceCell<TracerProvider> = OnceCell::new();
+
+/// Starts exporting spans to the configured collector and returns the tracer
+/// the tracing layer records with. Needs a running Tokio runtime, which the
+/// exporter sends batches on.
+pub fn init(config: &TelemetryConfig) -> Result<Tracer> {
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(config.endpoint())
+        .build()
+        .wrap_err("Failed to build the OTLP exporter")?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sample_ratio(),
+        ))))
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            config.service_name().to_string(),
+        )]))
+        .build();
+    let tracer = provider.tracer("umay");
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    PROVIDER
+        .set(provider)
+        .map_err(|_| eyre::eyre!("Telemetry is already initialized"))?;
+    Ok(tracer)
+}
+
+/// Exports the spans still waiting in the batch.
+pub fn flush() {
+    if let Some(provider) = PROVIDER.get() {
+        for result in provider.force_flush() {
+            if let Err(e) = result {
+                warn!("Failed to export spans: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Exports the remaining spans and stops the exporter.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            warn!("Failed to shut down telemetry: {:?}", e);
+        }
+    }
+}
+
+/// The trace context a request carries in its `traceparent` header.
+pub fn extract(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+/// Writes `context` to the `traceparent` header of a forwarded request.
+pub fn inject(context: &Context, headers: &mut HeaderMap) {
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(context, &mut HeaderInjector(headers))
+    });
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
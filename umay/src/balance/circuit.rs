@@ -0,0 +1,190 @@
+use crate::balance::Backend;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Resource guarded by a circuit breaker.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Limit {
+    /// Open connections to backends.
+    Connections,
+    /// Connections to backends still being established.
+    Pending,
+    /// HTTP requests in flight.
+    Requests,
+    /// Connect attempts on another backend after a failure.
+    Retries,
+}
+
+impl Limit {
+    pub const ALL: [Limit; 4] = [
+        Limit::Connections,
+        Limit::Pending,
+        Limit::Requests,
+        Limit::Retries,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Limit::Connections => "connections",
+            Limit::Pending => "pending",
+            Limit::Requests => "requests",
+            Limit::Retries => "retries",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Maximum concurrent use of each resource, 0 means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    pub max_connections: usize,
+    pub max_pending: usize,
+    pub max_requests: usize,
+    pub max_retries: usize,
+}
+
+impl Limits {
+    fn max(&self, limit: Limit) -> usize {
+        match limit {
+            Limit::Connections => self.max_connections,
+            Limit::Pending => self.max_pending,
+            Limit::Requests => self.max_requests,
+            Limit::Retries => self.max_retries,
+        }
+    }
+}
+
+/// Returned when a circuit breaker rejects a connection or request.
+#[derive(Debug)]
+pub struct Overflow(pub Limit);
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Circuit breaker open for {}", self.0.as_str())
+    }
+}
+
+impl std::error::Error for Overflow {}
+
+#[derive(Debug, Default)]
+struct Counters([AtomicUsize; 4]);
+
+impl Counters {
+    fn try_increment(&self, limit: Limit, max: usize) -> bool {
+        let counter = &self.0[limit.index()];
+        if max == 0 {
+            counter.fetch_add(1, Ordering::AcqRel);
+            return true;
+        }
+        counter
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < max).then_some(active + 1)
+            })
+            .is_ok()
+    }
+
+    fn decrement(&self, limit: Limit) {
+        self.0[limit.index()].fetch_sub(1, Ordering::AcqRel);
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.iter().all(|c| c.load(Ordering::Acquire) == 0)
+    }
+}
+
+/// Caps the connections, pending connects, requests and retries of an
+/// upstream and of each of its backends, so that an overloaded backend is
+/// failed fast instead of piling up work in the proxy. Retries are only
+/// capped per upstream.
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    upstream_limits: Limits,
+    backend_limits: Limits,
+    upstream: Arc<Counters>,
+    backends: Mutex<HashMap<SocketAddr, Arc<Counters>>>,
+    overflows: [AtomicU64; 4],
+}
+
+impl CircuitBreaker {
+    pub fn new(upstream_limits: Limits, backend_limits: Limits) -> Self {
+        Self {
+            upstream_limits,
+            backend_limits,
+            ..Self::default()
+        }
+    }
+
+    /// Takes one unit of `limit` for the upstream and, when given, for
+    /// `backend`. The unit is given back when the returned permit is dropped.
+    pub fn try_acquire(&self, limit: Limit, backend: Option<&Backend>) -> Result<Permit, Overflow> {
+        if !self
+            .upstream
+            .try_increment(limit, self.upstream_limits.max(limit))
+        {
+            return Err(self.overflow(limit));
+        }
+        let mut permit = Permit {
+            limit,
+            counters: vec![Arc::clone(&self.upstream)],
+        };
+
+        if let Some(backend) = backend.filter(|_| limit != Limit::Retries) {
+            let counters = self.backend_counters(backend.addr);
+            if !counters.try_increment(limit, self.backend_limits.max(limit)) {
+                return Err(self.overflow(limit));
+            }
+            permit.counters.push(counters);
+        }
+        Ok(permit)
+    }
+
+    /// Units of `limit` currently taken for the whole upstream.
+    pub fn active(&self, limit: Limit) -> usize {
+        self.upstream.0[limit.index()].load(Ordering::Acquire)
+    }
+
+    /// Number of times `limit` was reached since the start.
+    pub fn overflows(&self, limit: Limit) -> u64 {
+        self.overflows[limit.index()].load(Ordering::Relaxed)
+    }
+
+    /// Forgets the idle counters of backends that are gone.
+    pub fn sync(&self, backends: &BTreeSet<Backend>) {
+        let current: BTreeSet<SocketAddr> = backends.iter().map(|b| b.addr).collect();
+        self.backends
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|addr, counters| current.contains(addr) || !counters.is_idle());
+    }
+
+    fn backend_counters(&self, addr: SocketAddr) -> Arc<Counters> {
+        let mut backends = self.backends.lock().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(backends.entry(addr).or_default())
+    }
+
+    fn overflow(&self, limit: Limit) -> Overflow {
+        self.overflows[limit.index()].fetch_add(1, Ordering::Relaxed);
+        Overflow(limit)
+    }
+}
+
+/// One unit of a circuit breaker limit, held while the resource is in use.
+#[derive(Debug)]
+pub struct Permit {
+    limit: Limit,
+    counters: Vec<Arc<Counters>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        for counters in &self.counters {
+            counters.decrement(self.limit);
+        }
+    }
+}
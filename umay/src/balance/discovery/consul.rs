@@ -0,0 +1,225 @@
+use crate::app::config::ConsulConfig;
+use crate::balance::discovery::http::{encode, ApiClient};
+use crate::balance::discovery::{jittered, Change, ServiceDiscovery, Watch, WATCH_RETRY_INTERVAL};
+use crate::balance::Backend;
+use async_trait::async_trait;
+use eyre::Context;
+use futures::StreamExt;
+use http::{HeaderMap, HeaderValue, StatusCode};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, warn};
+
+/// Extra time given to a blocking query on top of its `wait`, Consul adds up
+/// to `wait / 16` of jitter before answering.
+const BLOCKING_QUERY_GRACE: Duration = Duration::from_secs(5);
+
+/// Minimum time between two queries of the watch, randomized by
+/// `QUERY_JITTER`. Blocking queries can return right away, for example after
+/// the index was reset, and must not hammer the agent.
+const MIN_QUERY_INTERVAL: Duration = Duration::from_secs(1);
+const QUERY_JITTER: f64 = 0.5;
+
+/// Discovers the passing instances of a service from the health endpoint of
+/// a Consul agent.
+///
+/// After the first lookup every query is a blocking query on the last
+/// `X-Consul-Index`, which returns as soon as the instances change or after
+/// `wait`, and the watch chains them to push changes right away. Tags and
+/// service metadata are carried over to the backend metadata, a tag
+/// `primary` becoming `tag:primary`.
+pub struct ConsulDiscovery {
+    client: ApiClient,
+    service: String,
+    datacenter: Option<String>,
+    tag: Option<String>,
+    token: Option<String>,
+    wait: Duration,
+    index: AtomicU64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceEntry {
+    node: Node,
+    service: AgentService,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Node {
+    node: String,
+    address: String,
+    datacenter: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AgentService {
+    #[serde(default)]
+    address: String,
+    port: u16,
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+    #[serde(default)]
+    meta: Option<BTreeMap<String, String>>,
+    weights: Option<Weights>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Weights {
+    passing: usize,
+}
+
+impl ConsulDiscovery {
+    pub fn new(config: ConsulConfig) -> eyre::Result<Self> {
+        Ok(Self {
+            client: ApiClient::new(config.address(), None)?,
+            service: config.service().to_string(),
+            datacenter: config.datacenter().map(str::to_string),
+            tag: config.tag().map(str::to_string),
+            token: config.token().map(str::to_string),
+            wait: config.wait(),
+            index: AtomicU64::new(0),
+        })
+    }
+
+    fn path(&self, index: u64) -> String {
+        let mut path = format!("/v1/health/service/{}?passing=true", encode(&self.service));
+        if let Some(datacenter) = &self.datacenter {
+            path.push_str(&format!("&dc={}", encode(datacenter)));
+        }
+        if let Some(tag) = &self.tag {
+            path.push_str(&format!("&tag={}", encode(tag)));
+        }
+        if index > 0 {
+            path.push_str(&format!("&index={}&wait={}s", index, self.wait.as_secs()));
+        }
+        path
+    }
+
+    fn backend(&self, entry: ServiceEntry) -> eyre::Result<Backend> {
+        // Instances registered without an address use the one of their node.
+        let address = match entry.service.address.as_str() {
+            "" => entry.node.address.as_str(),
+            address => address,
+        };
+        let ip: IpAddr = address
+            .parse()
+            .wrap_err_with(|| format!("Invalid instance address {}", address))?;
+
+        let mut metadata = entry.service.meta.unwrap_or_default();
+        for tag in entry.service.tags.unwrap_or_default() {
+            metadata.insert(format!("tag:{}", tag), "true".to_string());
+        }
+        metadata.insert("node".to_string(), entry.node.node);
+        if let Some(datacenter) = entry.node.datacenter {
+            metadata.insert("datacenter".to_string(), datacenter);
+        }
+
+        let weight = entry.service.weights.map_or(1, |w| w.passing.max(1));
+        Ok(Backend {
+            metadata,
+            ..Backend::new(SocketAddr::new(ip, entry.service.port), weight)
+        })
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for ConsulDiscovery {
+    async fn discover(&self) -> eyre::Result<Arc<BTreeSet<Backend>>> {
+        let index = self.index.load(Ordering::Acquire);
+        let mut headers = HeaderMap::new();
+        if let Some(token) = &self.token {
+            headers.insert("X-Consul-Token", HeaderValue::from_str(token)?);
+        }
+
+        let timeout = self.wait + self.wait / 16 + BLOCKING_QUERY_GRACE;
+        let response = tokio::time::timeout(timeout, self.client.get(&self.path(index), headers))
+            .await
+            .wrap_err("Consul query timed out")??;
+        if response.status() != StatusCode::OK {
+            eyre::bail!(
+                "Consul health query for {} failed with {}",
+                self.service,
+                response.status()
+            );
+        }
+
+        // As the Consul documentation recommends, an index going backwards
+        // starts over without blocking, and an index of 0 is taken as 1 so
+        // that the next query still blocks.
+        let next = match response
+            .headers()
+            .get("X-Consul-Index")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+        {
+            Some(next) if next < index => 0,
+            Some(next) => next.max(1),
+            None => 0,
+        };
+        self.index.store(next, Ordering::Release);
+
+        let entries: Vec<ServiceEntry> = serde_json::from_slice(response.body())?;
+        let mut backends = BTreeSet::new();
+        for entry in entries {
+            match self.backend(entry) {
+                Ok(backend) => {
+                    backends.insert(backend);
+                }
+                Err(e) => warn!("Ignoring Consul instance of {}: {:?}", self.service, e),
+            }
+        }
+
+        debug!(
+            "Discovered {} instances of {} at index {}",
+            backends.len(),
+            self.service,
+            next
+        );
+        if backends.is_empty() {
+            eyre::bail!("No passing instances found for {}", self.service);
+        }
+        Ok(Arc::new(backends))
+    }
+
+    /// Chains blocking queries, pushing the instances whenever they change.
+    fn watch(self: Arc<Self>) -> Option<Watch> {
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let mut last: Option<Arc<BTreeSet<Backend>>> = None;
+            while !tx.is_closed() {
+                let started = Instant::now();
+                let change = match self.discover().await {
+                    Ok(backends) if last.as_ref() == Some(&backends) => None,
+                    Ok(backends) => {
+                        last = Some(Arc::clone(&backends));
+                        Some(Ok(Change::Replace(backends)))
+                    }
+                    Err(e) => Some(Err(e)),
+                };
+                let failed = matches!(change, Some(Err(_)));
+                if let Some(change) = change {
+                    if tx.send(change).await.is_err() {
+                        return;
+                    }
+                }
+                let delay = if failed {
+                    WATCH_RETRY_INTERVAL
+                } else {
+                    jittered(MIN_QUERY_INTERVAL, QUERY_JITTER).saturating_sub(started.elapsed())
+                };
+                tokio::time::sleep(delay).await;
+            }
+        });
+        Some(ReceiverStream::new(rx).boxed())
+    }
+}
@@ -0,0 +1,209 @@
+use crate::app::config::FileFormat;
+use crate::balance::discovery::{Change, ServiceDiscovery, Watch, WATCH_RETRY_INTERVAL};
+use crate::balance::Backend;
+use arc_swap::ArcSwapOption;
+use async_trait::async_trait;
+use eyre::Context;
+use futures::StreamExt;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{info, warn};
+
+/// How often a watched endpoints file is checked for changes.
+const FILE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reads the backends from a JSON or YAML endpoints file:
+///
+/// ```yaml
+/// endpoints:
+///   - address: 10.0.0.1
+///     port: 8080
+///     weight: 2
+///     metadata:
+///       zone: eu-west-1a
+/// ```
+///
+/// The file is parsed and validated as a whole, so a change is applied
+/// atomically. A rewrite that cannot be parsed, for example one caught half
+/// written, is ignored and the last valid endpoints are kept.
+pub struct FileDiscovery {
+    path: PathBuf,
+    format: FileFormat,
+    loaded: ArcSwapOption<Loaded>,
+}
+
+struct Loaded {
+    modified: Option<SystemTime>,
+    len: u64,
+    backends: Arc<BTreeSet<Backend>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EndpointsFile {
+    endpoints: Vec<Endpoint>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Endpoint {
+    address: IpAddr,
+    port: u16,
+    #[serde(default = "default_weight")]
+    weight: usize,
+    #[serde(default)]
+    backup: bool,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+impl FileDiscovery {
+    /// Creates a discovery reading `path`. Without an explicit `format` it is
+    /// inferred from the file extension.
+    pub fn new(path: impl Into<PathBuf>, format: Option<FileFormat>) -> eyre::Result<Self> {
+        let path = path.into();
+        let format = match format {
+            Some(format) => format,
+            None => FileFormat::from_path(&path)?,
+        };
+
+        Ok(Self {
+            path,
+            format,
+            loaded: ArcSwapOption::empty(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn parse(&self, content: &str) -> eyre::Result<BTreeSet<Backend>> {
+        let file: EndpointsFile = match self.format {
+            FileFormat::Json => serde_json::from_str(content)?,
+            FileFormat::Yaml => config::Config::builder()
+                .add_source(config::File::from_str(content, config::FileFormat::Yaml))
+                .build()?
+                .try_deserialize()?,
+        };
+
+        let mut backends = BTreeSet::new();
+        for endpoint in file.endpoints {
+            let addr = SocketAddr::new(endpoint.address, endpoint.port);
+            if endpoint.port == 0 {
+                eyre::bail!("Endpoint {} has no port", addr);
+            }
+            if endpoint.weight == 0 {
+                eyre::bail!("Endpoint {} has a zero weight", addr);
+            }
+            backends.insert(Backend {
+                backup: endpoint.backup,
+                metadata: endpoint.metadata,
+                ..Backend::new(addr, endpoint.weight)
+            });
+        }
+
+        if backends.is_empty() {
+            eyre::bail!("No endpoints found");
+        }
+        Ok(backends)
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for FileDiscovery {
+    async fn discover(&self) -> eyre::Result<Arc<BTreeSet<Backend>>> {
+        let loaded = self.loaded.load_full();
+        let metadata = tokio::fs::metadata(&self.path).await;
+        let (modified, len) = match (&metadata, &loaded) {
+            (Ok(metadata), _) => (metadata.modified().ok(), metadata.len()),
+            (Err(e), Some(loaded)) => {
+                warn!(
+                    "Failed to read {}: {}, keeping the last endpoints",
+                    self.path.display(),
+                    e
+                );
+                return Ok(Arc::clone(&loaded.backends));
+            }
+            (Err(e), None) => eyre::bail!("Failed to read {}: {}", self.path.display(), e),
+        };
+
+        if let Some(loaded) = &loaded {
+            if modified.is_some() && loaded.modified == modified && loaded.len == len {
+                return Ok(Arc::clone(&loaded.backends));
+            }
+        }
+
+        let parsed = match tokio::fs::read_to_string(&self.path).await {
+            Ok(content) => self.parse(&content),
+            Err(e) => Err(e.into()),
+        };
+        let backends = match (parsed, loaded) {
+            (Ok(backends), _) => Arc::new(backends),
+            (Err(e), Some(loaded)) => {
+                warn!(
+                    "Ignoring invalid endpoints file {}: {:?}",
+                    self.path.display(),
+                    e
+                );
+                // Remember the rewrite so it is not parsed again until it changes.
+                self.loaded.store(Some(Arc::new(Loaded {
+                    modified,
+                    len,
+                    backends: Arc::clone(&loaded.backends),
+                })));
+                return Ok(Arc::clone(&loaded.backends));
+            }
+            (Err(e), None) => {
+                return Err(e)
+                    .wrap_err_with(|| format!("Invalid endpoints file {}", self.path.display()))
+            }
+        };
+
+        info!(
+            "Loaded {} endpoints from {}",
+            backends.len(),
+            self.path.display()
+        );
+        self.loaded.store(Some(Arc::new(Loaded {
+            modified,
+            len,
+            backends: Arc::clone(&backends),
+        })));
+        Ok(backends)
+    }
+
+    /// Pushes the endpoints whenever a valid rewrite of the file is seen.
+    fn watch(self: Arc<Self>) -> Option<Watch> {
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let mut last: Option<Arc<BTreeSet<Backend>>> = None;
+            while !tx.is_closed() {
+                let (change, interval) = match self.discover().await {
+                    Ok(backends) => {
+                        let changed = !last.as_ref().is_some_and(|l| Arc::ptr_eq(l, &backends));
+                        last = Some(Arc::clone(&backends));
+                        let change = changed.then_some(Ok(Change::Replace(backends)));
+                        (change, FILE_CHECK_INTERVAL)
+                    }
+                    Err(e) => (Some(Err(e)), WATCH_RETRY_INTERVAL),
+                };
+                if let Some(change) = change {
+                    if tx.send(change).await.is_err() {
+                        return;
+                    }
+                }
+                tokio::time::sleep(interval).await;
+            }
+        });
+        Some(ReceiverStream::new(rx).boxed())
+    }
+}
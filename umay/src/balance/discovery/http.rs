@@ -0,0 +1,183 @@
+use bytes::Bytes;
+use eyre::{Context, OptionExt};
+use http::header::HOST;
+use http::{HeaderMap, Request, Response, Uri};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use rustls::pki_types::ServerName;
+use rustls::RootCertStore;
+use rustls_pemfile::{certs, private_key};
+use socket2::{SockRef, TcpKeepalive};
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tracing::{debug, warn};
+
+/// Time allowed to connect to the API, TLS handshake included.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Idle time after which a connection is probed, and interval of the probes,
+/// so that a long polling request or watch on a connection the peer dropped
+/// silently fails instead of waiting forever.
+const KEEPALIVE_TIME: Duration = Duration::from_secs(30);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Minimal HTTP/1.1 client for the discovery sources that talk to a control
+/// plane API, such as the Kubernetes API server or a Consul agent. Every
+/// request uses its own connection, which keeps long polling requests from
+/// holding up one another.
+pub(crate) struct ApiClient {
+    host: String,
+    port: u16,
+    authority: String,
+    tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl ApiClient {
+    /// Creates a client for `base`, an `http://` or `https://` URL. An https
+    /// URL requires `tls`.
+    pub fn new(base: &str, tls: Option<Arc<rustls::ClientConfig>>) -> eyre::Result<Self> {
+        let uri: Uri = base.parse().wrap_err("Invalid API server URL")?;
+        let https = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => eyre::bail!("Unsupported API server URL {}", base),
+        };
+        if https && tls.is_none() {
+            eyre::bail!("{} requires a TLS configuration", base);
+        }
+
+        let authority = uri.authority().ok_or_eyre("API server URL has no host")?;
+        let host = authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let port = authority.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+        Ok(Self {
+            host,
+            port,
+            authority: authority.to_string(),
+            tls: if https { tls } else { None },
+        })
+    }
+
+    pub async fn get(
+        &self,
+        path_and_query: &str,
+        headers: HeaderMap,
+    ) -> eyre::Result<Response<Bytes>> {
+        let (parts, body) = self.stream(path_and_query, headers).await?.into_parts();
+        let body = body.collect().await?.to_bytes();
+        Ok(Response::from_parts(parts, body))
+    }
+
+    /// Like `get`, returning as soon as the response head is received so a
+    /// long-lived body, such as a watch, can be read as it arrives.
+    pub async fn stream(
+        &self,
+        path_and_query: &str,
+        headers: HeaderMap,
+    ) -> eyre::Result<Response<Incoming>> {
+        let mut request = Request::get(path_and_query)
+            .header(HOST, &self.authority)
+            .body(Empty::<Bytes>::new())?;
+        request.headers_mut().extend(headers);
+
+        let tcp = tokio::time::timeout(CONNECT_TIMEOUT, self.connect())
+            .await
+            .wrap_err_with(|| format!("Connecting to {} timed out", self.authority))??;
+        match &self.tls {
+            Some(config) => {
+                let server_name = ServerName::try_from(self.host.clone())?;
+                let tls = tokio::time::timeout(
+                    CONNECT_TIMEOUT,
+                    TlsConnector::from(Arc::clone(config)).connect(server_name, tcp),
+                )
+                .await
+                .wrap_err_with(|| format!("TLS handshake with {} timed out", self.authority))??;
+                send(TokioIo::new(tls), request).await
+            }
+            None => send(TokioIo::new(tcp), request).await,
+        }
+    }
+
+    async fn connect(&self) -> eyre::Result<TcpStream> {
+        let tcp = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let keepalive = TcpKeepalive::new()
+            .with_time(KEEPALIVE_TIME)
+            .with_interval(KEEPALIVE_INTERVAL);
+        if let Err(e) = SockRef::from(&tcp).set_tcp_keepalive(&keepalive) {
+            warn!("Failed to set keepalive on API connection: {}", e);
+        }
+        Ok(tcp)
+    }
+}
+
+async fn send<IO>(
+    io: TokioIo<IO>,
+    request: Request<Empty<Bytes>>,
+) -> eyre::Result<Response<Incoming>>
+where
+    IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(io).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("API connection closed: {:?}", e);
+        }
+    });
+
+    Ok(sender.send_request(request).await?)
+}
+
+/// Percent-encodes `value` for a path segment or query parameter, keeping
+/// only the unreserved characters of RFC 3986.
+pub(crate) fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Builds a TLS client configuration trusting the PEM encoded `ca`
+/// certificates, authenticating with the PEM encoded `client_auth`
+/// certificate chain and key when given.
+pub(crate) fn tls_config(
+    ca: &[u8],
+    client_auth: Option<(&[u8], &[u8])>,
+) -> eyre::Result<Arc<rustls::ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(&mut Cursor::new(ca)) {
+        roots.add(cert.wrap_err("Invalid CA certificate")?)?;
+    }
+    if roots.is_empty() {
+        eyre::bail!("No CA certificate found");
+    }
+
+    let builder = rustls::ClientConfig::builder().with_root_certificates(roots);
+    let config = match client_auth {
+        Some((cert, key)) => {
+            let chain = certs(&mut Cursor::new(cert))
+                .collect::<Result<Vec<_>, _>>()
+                .wrap_err("Invalid client certificate")?;
+            let key = private_key(&mut Cursor::new(key))
+                .wrap_err("Invalid client key")?
+                .ok_or_eyre("No client key found")?;
+            builder.with_client_auth_cert(chain, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,546 @@
+use crate::app::config::KubernetesConfig;
+use crate::balance::discovery::http::{tls_config, ApiClient};
+use crate::balance::discovery::{Change, ServiceDiscovery, Watch, WATCH_RETRY_INTERVAL};
+use crate::balance::Backend;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::BytesMut;
+use eyre::{Context, OptionExt};
+use futures::StreamExt;
+use http::header::AUTHORIZATION;
+use http::{HeaderMap, HeaderValue, StatusCode};
+use http_body_util::BodyExt;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, warn};
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// How long the API server keeps a watch open before it is restarted with a
+/// fresh list.
+const WATCH_TIMEOUT_SECONDS: u64 = 300;
+
+/// Time allowed to list the EndpointSlices, or to start a watch.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time without any event after which a watch is given up and restarted with
+/// a fresh list. The API server ends a healthy watch before, after
+/// `WATCH_TIMEOUT_SECONDS`.
+const WATCH_IDLE_TIMEOUT: Duration = Duration::from_secs(WATCH_TIMEOUT_SECONDS + 30);
+
+/// Discovers the endpoints of a `namespace/service:port` from its
+/// EndpointSlices, through the Kubernetes API server.
+///
+/// Ready endpoints are the primaries. Terminating endpoints that still serve
+/// are kept as backups, so they only take traffic while nothing is ready.
+/// When `zone` is set and every ready endpoint carries topology hints, only
+/// the endpoints hinted for that zone are used, as kube-proxy does.
+///
+/// The watch lists the EndpointSlices and then follows their changes through
+/// the API server, so readiness changes are applied right away. Without a
+/// kubeconfig the in-cluster service account is used.
+pub struct KubernetesDiscovery {
+    client: ApiClient,
+    token: Option<Token>,
+    namespace: String,
+    service: String,
+    port: Option<String>,
+    zone: Option<String>,
+}
+
+enum Token {
+    Static(String),
+    // Re-read on every request, projected service account tokens rotate.
+    File(PathBuf),
+}
+
+#[derive(Deserialize)]
+struct EndpointSliceList {
+    #[serde(default)]
+    metadata: ObjectMeta,
+    items: Vec<EndpointSlice>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectMeta {
+    #[serde(default)]
+    name: String,
+    resource_version: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WatchEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    object: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EndpointSlice {
+    #[serde(default)]
+    metadata: ObjectMeta,
+    address_type: String,
+    #[serde(default)]
+    endpoints: Vec<Endpoint>,
+    #[serde(default)]
+    ports: Vec<EndpointPort>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Endpoint {
+    addresses: Vec<String>,
+    #[serde(default)]
+    conditions: EndpointConditions,
+    node_name: Option<String>,
+    zone: Option<String>,
+    hints: Option<EndpointHints>,
+}
+
+#[derive(Default, Deserialize)]
+struct EndpointConditions {
+    ready: Option<bool>,
+    serving: Option<bool>,
+    terminating: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EndpointHints {
+    #[serde(default)]
+    for_zones: Vec<ForZone>,
+}
+
+#[derive(Deserialize)]
+struct ForZone {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct EndpointPort {
+    name: Option<String>,
+    port: Option<u16>,
+}
+
+impl KubernetesDiscovery {
+    pub fn new(config: KubernetesConfig) -> eyre::Result<Self> {
+        let (namespace, service, port) = parse_service(config.service())?;
+        let (client, token) = match config.kubeconfig() {
+            Some(path) => from_kubeconfig(Path::new(path))?,
+            None => in_cluster()?,
+        };
+
+        Ok(Self {
+            client,
+            token,
+            namespace,
+            service,
+            port,
+            zone: config.zone().map(str::to_string),
+        })
+    }
+
+    async fn headers(&self) -> eyre::Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        if let Some(token) = &self.token {
+            let token = match token {
+                Token::Static(token) => token.clone(),
+                Token::File(path) => tokio::fs::read_to_string(path)
+                    .await
+                    .wrap_err("Failed to read service account token")?,
+            };
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {}", token.trim()))?,
+            );
+        }
+        Ok(headers)
+    }
+
+    fn path(&self) -> String {
+        format!(
+            "/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices?labelSelector=kubernetes.io%2Fservice-name%3D{}",
+            self.namespace, self.service
+        )
+    }
+
+    async fn list(&self) -> eyre::Result<EndpointSliceList> {
+        let response = tokio::time::timeout(
+            REQUEST_TIMEOUT,
+            self.client.get(&self.path(), self.headers().await?),
+        )
+        .await
+        .wrap_err("Listing EndpointSlices timed out")??;
+        if response.status() != StatusCode::OK {
+            eyre::bail!(
+                "Listing EndpointSlices of {}/{} failed with {}",
+                self.namespace,
+                self.service,
+                response.status()
+            );
+        }
+        Ok(serde_json::from_slice(response.body())?)
+    }
+
+    /// Lists the EndpointSlices, then follows a watch from the listed version,
+    /// pushing the backends after every change. Starts over with a new list
+    /// whenever the watch ends or fails.
+    async fn run_watch(&self, tx: mpsc::Sender<eyre::Result<Change>>) {
+        let mut last: Option<Arc<BTreeSet<Backend>>> = None;
+        while !tx.is_closed() {
+            let list = match self.list().await {
+                Ok(list) => list,
+                Err(e) => {
+                    if tx.send(Err(e)).await.is_err() {
+                        return;
+                    }
+                    tokio::time::sleep(WATCH_RETRY_INTERVAL).await;
+                    continue;
+                }
+            };
+            let version = list.metadata.resource_version.unwrap_or_default();
+            let mut slices: HashMap<String, EndpointSlice> = list
+                .items
+                .into_iter()
+                .map(|slice| (slice.metadata.name.clone(), slice))
+                .collect();
+            if !self.push(&slices, &mut last, &tx).await {
+                return;
+            }
+
+            match self.follow(&version, &mut slices, &mut last, &tx).await {
+                Ok(()) => debug!("Watch of {}/{} ended", self.namespace, self.service),
+                Err(e) => {
+                    warn!(
+                        "Watch of {}/{} failed: {:?}",
+                        self.namespace, self.service, e
+                    );
+                    tokio::time::sleep(WATCH_RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    async fn follow(
+        &self,
+        version: &str,
+        slices: &mut HashMap<String, EndpointSlice>,
+        last: &mut Option<Arc<BTreeSet<Backend>>>,
+        tx: &mpsc::Sender<eyre::Result<Change>>,
+    ) -> eyre::Result<()> {
+        let path = format!(
+            "{}&watch=true&allowWatchBookmarks=true&resourceVersion={}&timeoutSeconds={}",
+            self.path(),
+            version,
+            WATCH_TIMEOUT_SECONDS
+        );
+        let response = tokio::time::timeout(
+            REQUEST_TIMEOUT,
+            self.client.stream(&path, self.headers().await?),
+        )
+        .await
+        .wrap_err("Starting the watch timed out")??;
+        if response.status() != StatusCode::OK {
+            eyre::bail!("Watch failed with {}", response.status());
+        }
+
+        let mut body = response.into_body();
+        let mut buf = BytesMut::new();
+        while let Some(frame) = tokio::time::timeout(WATCH_IDLE_TIMEOUT, body.frame())
+            .await
+            .wrap_err("Watch went idle")?
+        {
+            let Ok(data) = frame?.into_data() else {
+                continue;
+            };
+            buf.extend_from_slice(&data);
+            while let Some(end) = buf.iter().position(|b| *b == b'\n') {
+                let line = buf.split_to(end + 1);
+                let event: WatchEvent = serde_json::from_slice(&line)?;
+                match event.kind.as_str() {
+                    "ADDED" | "MODIFIED" => {
+                        let slice: EndpointSlice = serde_json::from_value(event.object)?;
+                        slices.insert(slice.metadata.name.clone(), slice);
+                    }
+                    "DELETED" => {
+                        let slice: EndpointSlice = serde_json::from_value(event.object)?;
+                        slices.remove(&slice.metadata.name);
+                    }
+                    "BOOKMARK" => continue,
+                    // For example 410 Gone once the version is too old.
+                    _ => eyre::bail!("Watch error: {}", event.object),
+                }
+                if !self.push(slices, last, tx).await {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the backends of `slices` when they differ from the `last` ones,
+    /// returning false once nobody is watching anymore.
+    async fn push(
+        &self,
+        slices: &HashMap<String, EndpointSlice>,
+        last: &mut Option<Arc<BTreeSet<Backend>>>,
+        tx: &mpsc::Sender<eyre::Result<Change>>,
+    ) -> bool {
+        let backends = Arc::new(self.backends(slices.values()));
+        if last.as_ref() == Some(&backends) {
+            return true;
+        }
+        *last = Some(Arc::clone(&backends));
+
+        let change = if backends.is_empty() {
+            Err(eyre::eyre!(
+                "No endpoints found for {}/{}",
+                self.namespace,
+                self.service
+            ))
+        } else {
+            Ok(Change::Replace(backends))
+        };
+        tx.send(change).await.is_ok()
+    }
+
+    fn slice_port(&self, slice: &EndpointSlice) -> Option<u16> {
+        let port = match &self.port {
+            None if slice.ports.len() == 1 => slice.ports.first(),
+            None => None,
+            Some(port) => slice.ports.iter().find(|p| match port.parse::<u16>() {
+                Ok(number) => p.port == Some(number),
+                Err(_) => p.name.as_deref() == Some(port.as_str()),
+            }),
+        };
+        port.and_then(|p| p.port)
+    }
+
+    fn backends<'a>(&self, slices: impl Iterator<Item = &'a EndpointSlice>) -> BTreeSet<Backend> {
+        let mut ready = vec![];
+        let mut terminating = vec![];
+        for slice in slices {
+            if slice.address_type == "FQDN" {
+                continue;
+            }
+            let Some(port) = self.slice_port(slice) else {
+                debug!("EndpointSlice of {} has no matching port", self.service);
+                continue;
+            };
+            for endpoint in &slice.endpoints {
+                let conditions = &endpoint.conditions;
+                if conditions.ready.unwrap_or(true) {
+                    ready.push((endpoint, port));
+                } else if conditions.serving == Some(true) && conditions.terminating == Some(true) {
+                    terminating.push((endpoint, port));
+                }
+            }
+        }
+
+        // Hints are only trusted when every ready endpoint has them.
+        if let Some(zone) = &self.zone {
+            let hinted = ready.iter().all(|(e, _)| e.hints.is_some());
+            let for_zone = |e: &Endpoint| {
+                e.hints
+                    .as_ref()
+                    .is_some_and(|h| h.for_zones.iter().any(|z| &z.name == zone))
+            };
+            if hinted && ready.iter().any(|(e, _)| for_zone(e)) {
+                ready.retain(|(e, _)| for_zone(e));
+            }
+        }
+
+        let ready = ready.into_iter().map(|e| (e, false));
+        let terminating = terminating.into_iter().map(|e| (e, true));
+        let mut backends = BTreeSet::new();
+        for ((endpoint, port), backup) in ready.chain(terminating) {
+            let mut metadata = BTreeMap::new();
+            if let Some(zone) = &endpoint.zone {
+                metadata.insert("zone".to_string(), zone.clone());
+            }
+            if let Some(node) = &endpoint.node_name {
+                metadata.insert("node".to_string(), node.clone());
+            }
+            for address in &endpoint.addresses {
+                let Ok(ip) = address.parse::<IpAddr>() else {
+                    warn!("Ignoring invalid endpoint address {}", address);
+                    continue;
+                };
+                backends.insert(Backend {
+                    backup,
+                    metadata: metadata.clone(),
+                    ..Backend::new(SocketAddr::new(ip, port), 1)
+                });
+            }
+        }
+        backends
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for KubernetesDiscovery {
+    async fn discover(&self) -> eyre::Result<Arc<BTreeSet<Backend>>> {
+        let slices = self.list().await?;
+        let backends = self.backends(slices.items.iter());
+        debug!(
+            "Discovered {} endpoints of {}/{}",
+            backends.len(),
+            self.namespace,
+            self.service
+        );
+        if backends.is_empty() {
+            eyre::bail!("No endpoints found for {}/{}", self.namespace, self.service);
+        }
+        Ok(Arc::new(backends))
+    }
+
+    fn watch(self: Arc<Self>) -> Option<Watch> {
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move { self.run_watch(tx).await });
+        Some(ReceiverStream::new(rx).boxed())
+    }
+}
+
+/// Splits `namespace/service:port`, the port being a name or a number and
+/// optional when the service exposes a single port.
+fn parse_service(spec: &str) -> eyre::Result<(String, String, Option<String>)> {
+    let (namespace, rest) = spec
+        .split_once('/')
+        .ok_or_else(|| eyre::eyre!("Expected namespace/service:port, got {}", spec))?;
+    let (service, port) = match rest.split_once(':') {
+        Some((service, port)) => (service, Some(port.to_string())),
+        None => (rest, None),
+    };
+    if namespace.is_empty() || service.is_empty() {
+        eyre::bail!("Expected namespace/service:port, got {}", spec);
+    }
+    Ok((namespace.to_string(), service.to_string(), port))
+}
+
+fn in_cluster() -> eyre::Result<(ApiClient, Option<Token>)> {
+    let host = std::env::var("KUBERNETES_SERVICE_HOST")
+        .wrap_err("Not running in a cluster and no kubeconfig given")?;
+    let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+    let host = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+        _ => host,
+    };
+
+    let dir = Path::new(SERVICE_ACCOUNT_DIR);
+    let ca = std::fs::read(dir.join("ca.crt")).wrap_err("Failed to read service account CA")?;
+    let client = ApiClient::new(
+        &format!("https://{}:{}", host, port),
+        Some(tls_config(&ca, None)?),
+    )?;
+    Ok((client, Some(Token::File(dir.join("token")))))
+}
+
+#[derive(Deserialize)]
+struct Kubeconfig {
+    #[serde(rename = "current-context")]
+    current_context: String,
+    contexts: Vec<Named<KubeContext>>,
+    clusters: Vec<Named<KubeCluster>>,
+    #[serde(default)]
+    users: Vec<Named<KubeUser>>,
+}
+
+#[derive(Deserialize)]
+struct Named<T> {
+    name: String,
+    #[serde(alias = "context", alias = "cluster", alias = "user")]
+    value: T,
+}
+
+#[derive(Deserialize)]
+struct KubeContext {
+    cluster: String,
+    user: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct KubeCluster {
+    server: String,
+    certificate_authority: Option<String>,
+    certificate_authority_data: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct KubeUser {
+    token: Option<String>,
+    token_file: Option<String>,
+    client_certificate: Option<String>,
+    client_certificate_data: Option<String>,
+    client_key: Option<String>,
+    client_key_data: Option<String>,
+}
+
+fn from_kubeconfig(path: &Path) -> eyre::Result<(ApiClient, Option<Token>)> {
+    let content = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read kubeconfig {}", path.display()))?;
+    let kubeconfig: Kubeconfig = config::Config::builder()
+        .add_source(config::File::from_str(&content, config::FileFormat::Yaml))
+        .build()?
+        .try_deserialize()
+        .wrap_err("Invalid kubeconfig")?;
+
+    let context = find(&kubeconfig.contexts, &kubeconfig.current_context)?;
+    let cluster = find(&kubeconfig.clusters, &context.cluster)?;
+    let user = match &context.user {
+        Some(user) => find(&kubeconfig.users, user)?,
+        None => &KubeUser::default(),
+    };
+
+    // Relative file references are relative to the kubeconfig itself.
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let read = |data: &Option<String>, file: &Option<String>| -> eyre::Result<Option<Vec<u8>>> {
+        match (data, file) {
+            (Some(data), _) => Ok(Some(STANDARD.decode(data.trim())?)),
+            (None, Some(file)) => Ok(Some(std::fs::read(dir.join(file))?)),
+            (None, None) => Ok(None),
+        }
+    };
+
+    let tls = match read(
+        &cluster.certificate_authority_data,
+        &cluster.certificate_authority,
+    )? {
+        Some(ca) => {
+            let cert = read(&user.client_certificate_data, &user.client_certificate)?;
+            let key = read(&user.client_key_data, &user.client_key)?;
+            let client_auth = match (&cert, &key) {
+                (Some(cert), Some(key)) => Some((cert.as_slice(), key.as_slice())),
+                _ => None,
+            };
+            Some(tls_config(&ca, client_auth)?)
+        }
+        None => None,
+    };
+
+    let token = match (&user.token, &user.token_file) {
+        (Some(token), _) => Some(Token::Static(token.clone())),
+        (None, Some(file)) => Some(Token::File(dir.join(file))),
+        (None, None) => None,
+    };
+
+    Ok((ApiClient::new(&cluster.server, tls)?, token))
+}
+
+fn find<'a, T>(items: &'a [Named<T>], name: &str) -> eyre::Result<&'a T> {
+    items
+        .iter()
+        .find(|item| item.name == name)
+        .map(|item| &item.value)
+        .ok_or_eyre(format!("{} not found in kubeconfig", name))
+}
@@ -0,0 +1,425 @@
+use crate::app::config::{DnsConfig, RefreshConfig, UpstreamServer};
+use crate::balance::Backend;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use futures::future::join_all;
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+use hickory_resolver::proto::rr::rdata::SRV;
+use hickory_resolver::TokioAsyncResolver;
+use rand::Rng;
+use std::collections::BTreeSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tracing::{debug, info, warn};
+
+pub mod consul;
+pub mod file;
+mod http;
+pub mod kubernetes;
+
+/// Delay before a watch reconnects after its source failed.
+pub(crate) const WATCH_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[async_trait]
+pub trait ServiceDiscovery {
+    async fn discover(&self) -> eyre::Result<Arc<BTreeSet<Backend>>>;
+
+    /// How long the most recently discovered backends stay valid, for sources
+    /// that know it, such as DNS record TTLs.
+    fn ttl(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Streams changes of the backends as soon as the source sees them, for
+    /// sources that can push them. Sources returning `None` are polled with
+    /// `discover` instead, see [`poll`].
+    fn watch(self: Arc<Self>) -> Option<Watch> {
+        None
+    }
+
+    /// The source itself when its backends can be changed at runtime.
+    fn local(&self) -> Option<&LocalDiscovery> {
+        None
+    }
+}
+
+/// Stream of backend changes produced by [`ServiceDiscovery::watch`]. An
+/// error reports a failed attempt of the source, which keeps retrying.
+pub type Watch = BoxStream<'static, eyre::Result<Change>>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// Replaces all backends.
+    Replace(Arc<BTreeSet<Backend>>),
+    /// Adds a backend, or updates the one with the same address.
+    Add(Backend),
+    Remove(SocketAddr),
+}
+
+impl Change {
+    /// Applies the change to `backends`.
+    pub fn apply(self, backends: &BTreeSet<Backend>) -> Arc<BTreeSet<Backend>> {
+        match self {
+            Change::Replace(new_backends) => new_backends,
+            Change::Add(backend) => {
+                let mut new_backends = backends.clone();
+                new_backends.retain(|b| b.addr != backend.addr);
+                new_backends.insert(backend);
+                Arc::new(new_backends)
+            }
+            Change::Remove(addr) => {
+                let mut new_backends = backends.clone();
+                new_backends.retain(|b| b.addr != addr);
+                Arc::new(new_backends)
+            }
+        }
+    }
+}
+
+/// Adapts a pull-only source to a [`Watch`], calling `discover` on the
+/// schedule of `refresh`: following the discovery TTL while it succeeds, and
+/// retrying at the minimum interval after a failure.
+pub fn poll(
+    discovery: Arc<dyn ServiceDiscovery + Send + Sync>,
+    refresh: RefreshConfig,
+    healthy: bool,
+) -> Watch {
+    stream::unfold(healthy, move |healthy| {
+        let discovery = Arc::clone(&discovery);
+        let refresh = refresh.clone();
+        async move {
+            let interval = if healthy {
+                refresh.next_interval(discovery.ttl())
+            } else {
+                refresh.min_interval()
+            };
+            tokio::time::sleep(jittered(interval, refresh.jitter())).await;
+
+            let result = discovery.discover().await.map(Change::Replace);
+            let healthy = result.is_ok();
+            Some((result, healthy))
+        }
+    })
+    .boxed()
+}
+
+/// Spreads refreshes of upstreams sharing a TTL so they do not hit the
+/// resolver at the same time.
+pub(crate) fn jittered(interval: Duration, jitter: f64) -> Duration {
+    if jitter <= 0.0 {
+        return interval;
+    }
+    let factor = rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter);
+    interval.mul_f64(factor)
+}
+
+struct Resolved {
+    backends: Vec<Backend>,
+    valid_until: Option<Instant>,
+}
+
+/// Resolves every configured upstream server concurrently and merges the
+/// results. Literal IP addresses are used as is, and a server that fails to
+/// resolve does not discard the backends of the ones that did.
+pub struct DnsDiscovery {
+    resolver: TokioAsyncResolver,
+    servers: Vec<UpstreamServer>,
+    valid_until: Mutex<Option<Instant>>,
+}
+
+impl DnsDiscovery {
+    pub fn new(servers: Vec<UpstreamServer>, dns_config: Option<DnsConfig>) -> eyre::Result<Self> {
+        if servers.is_empty() {
+            eyre::bail!("No servers found");
+        }
+
+        Ok(Self {
+            resolver: resolver(dns_config)?,
+            servers,
+            valid_until: Mutex::new(None),
+        })
+    }
+
+    async fn resolve(&self, server: &UpstreamServer) -> eyre::Result<Resolved> {
+        let hostname = server.address();
+        if let Ok(ip) = hostname.parse::<IpAddr>() {
+            return Ok(Resolved {
+                backends: vec![backend_for(server, SocketAddr::new(ip, server.port()))],
+                valid_until: None,
+            });
+        }
+
+        let ips = self.resolver.lookup_ip(hostname).await?;
+        debug!("Resolved {} to {:?}", hostname, ips);
+
+        Ok(Resolved {
+            backends: ips
+                .iter()
+                .map(|ip| backend_for(server, SocketAddr::new(ip, server.port())))
+                .collect(),
+            valid_until: Some(ips.valid_until()),
+        })
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for DnsDiscovery {
+    async fn discover(&self) -> eyre::Result<Arc<BTreeSet<Backend>>> {
+        let results = join_all(self.servers.iter().map(|server| self.resolve(server))).await;
+        merge_resolved(&self.servers, results, &self.valid_until)
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        remaining_ttl(&self.valid_until)
+    }
+}
+
+/// Scale of SRV record weights, so that a record of weight 0 can get a much
+/// smaller share than one of weight 1 (RFC 2782).
+const SRV_WEIGHT_SCALE: usize = 100;
+
+/// Resolves `_service._proto.name` SRV records. The target port and weight come
+/// from each record, and record priorities become tiers that are used in
+/// order, each only while no lower one has an available backend.
+pub struct DnsSrvDiscovery {
+    resolver: TokioAsyncResolver,
+    servers: Vec<UpstreamServer>,
+    valid_until: Mutex<Option<Instant>>,
+}
+
+impl DnsSrvDiscovery {
+    pub fn new(servers: Vec<UpstreamServer>, dns_config: Option<DnsConfig>) -> eyre::Result<Self> {
+        if servers.is_empty() {
+            eyre::bail!("No servers found");
+        }
+
+        Ok(Self {
+            resolver: resolver(dns_config)?,
+            servers,
+            valid_until: Mutex::new(None),
+        })
+    }
+
+    async fn resolve(&self, server: &UpstreamServer) -> eyre::Result<Resolved> {
+        let srv = self.resolver.srv_lookup(server.address()).await?;
+        let mut valid_until = srv.as_lookup().valid_until();
+        let records: Vec<&SRV> = srv.iter().collect();
+        debug!("Resolved SRV {} to {:?}", server.address(), records);
+
+        let targets = join_all(
+            records
+                .iter()
+                .map(|r| self.resolver.lookup_ip(r.target().clone())),
+        )
+        .await;
+
+        let mut backends = vec![];
+        for (record, ips) in records.iter().zip(targets) {
+            let ips = match ips {
+                Ok(ips) => {
+                    valid_until = valid_until.min(ips.valid_until());
+                    ips
+                }
+                Err(e) => {
+                    warn!("Failed to resolve SRV target {}: {:?}", record.target(), e);
+                    continue;
+                }
+            };
+            for ip in ips.iter() {
+                let addr = SocketAddr::new(ip, record.port());
+                backends.push(Backend {
+                    weight: srv_weight(record.weight()),
+                    priority: record.priority(),
+                    ..backend_for(server, addr)
+                });
+            }
+        }
+
+        Ok(Resolved {
+            backends,
+            valid_until: Some(valid_until),
+        })
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for DnsSrvDiscovery {
+    async fn discover(&self) -> eyre::Result<Arc<BTreeSet<Backend>>> {
+        let results = join_all(self.servers.iter().map(|server| self.resolve(server))).await;
+        merge_resolved(&self.servers, results, &self.valid_until)
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        remaining_ttl(&self.valid_until)
+    }
+}
+
+/// A record of weight 0 stays selectable, with a very small chance when the
+/// tier has records of a higher weight.
+fn srv_weight(weight: u16) -> usize {
+    (usize::from(weight) * SRV_WEIGHT_SCALE).max(1)
+}
+
+fn resolver(dns_config: Option<DnsConfig>) -> eyre::Result<TokioAsyncResolver> {
+    match dns_config {
+        Some(config) => {
+            info!("Using custom DNS configuration");
+            let cfg = config.into_resolver_config()?;
+            Ok(TokioAsyncResolver::tokio(cfg.0, cfg.1))
+        }
+        None => {
+            info!("Using system default DNS configuration");
+            Ok(TokioAsyncResolver::tokio_from_system_conf()?)
+        }
+    }
+}
+
+/// Merges the per server resolution results, keeping the backends of the
+/// servers that resolved when others failed, and records when the earliest
+/// of the underlying records expires.
+fn merge_resolved(
+    servers: &[UpstreamServer],
+    results: Vec<eyre::Result<Resolved>>,
+    valid_until: &Mutex<Option<Instant>>,
+) -> eyre::Result<Arc<BTreeSet<Backend>>> {
+    let mut backends = BTreeSet::new();
+    let mut earliest: Option<Instant> = None;
+    let mut last_error = None;
+    for (server, result) in servers.iter().zip(results) {
+        match result {
+            Ok(resolved) => {
+                backends.extend(resolved.backends);
+                earliest = match (earliest, resolved.valid_until) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+            }
+            Err(e) => {
+                warn!("Failed to resolve {}: {:?}", server.address(), e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    if backends.is_empty() {
+        debug!("No backends found for servers: {:?}", servers);
+        return Err(last_error.unwrap_or_else(|| eyre::eyre!("No backends found")));
+    }
+
+    *valid_until.lock().unwrap_or_else(|e| e.into_inner()) = earliest;
+    Ok(Arc::new(backends))
+}
+
+fn remaining_ttl(valid_until: &Mutex<Option<Instant>>) -> Option<Duration> {
+    let valid_until = *valid_until.lock().unwrap_or_else(|e| e.into_inner());
+    valid_until.map(|until| until.saturating_duration_since(Instant::now()))
+}
+
+/// Backends managed in process, for example from the configuration or at
+/// runtime. Every change is pushed to watchers right away, in the order it
+/// was applied.
+pub struct LocalDiscovery {
+    backends: ArcSwap<BTreeSet<Backend>>,
+    changes: broadcast::Sender<Change>,
+    // Held while applying and sending a change, so that concurrent changes
+    // reach watchers in the order they were applied.
+    changing: Mutex<()>,
+}
+
+impl Default for LocalDiscovery {
+    fn default() -> Self {
+        Self::with_backends(vec![])
+    }
+}
+
+impl LocalDiscovery {
+    pub fn with_backends(backends: Vec<Backend>) -> Self {
+        Self {
+            backends: ArcSwap::from_pointee(backends.into_iter().collect()),
+            changes: broadcast::channel(64).0,
+            changing: Mutex::new(()),
+        }
+    }
+
+    pub fn add_backend(&self, addr: SocketAddr) {
+        self.put_backend(Backend::new(addr, 1));
+    }
+
+    /// Adds `backend`, or updates the one with the same address.
+    pub fn put_backend(&self, backend: Backend) {
+        self.change(Change::Add(backend));
+    }
+
+    pub fn remove_backend(&self, addr: &SocketAddr) {
+        self.change(Change::Remove(*addr));
+    }
+
+    pub fn set_backends(&self, backends: Vec<SocketAddr>) {
+        let new_backends = backends
+            .into_iter()
+            .map(|addr| Backend::new(addr, 1))
+            .collect();
+        self.change(Change::Replace(Arc::new(new_backends)));
+    }
+
+    pub fn clear_backends(&self) {
+        self.change(Change::Replace(Arc::new(BTreeSet::new())));
+    }
+
+    fn change(&self, change: Change) {
+        let _changing = self.changing.lock().unwrap_or_else(|e| e.into_inner());
+        self.backends
+            .store(change.clone().apply(&self.backends.load()));
+        // Nobody may be watching, which is fine.
+        let _ = self.changes.send(change);
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for LocalDiscovery {
+    async fn discover(&self) -> eyre::Result<Arc<BTreeSet<Backend>>> {
+        Ok(self.backends.load_full())
+    }
+
+    fn watch(self: Arc<Self>) -> Option<Watch> {
+        let (changes, current) = {
+            let _changing = self.changing.lock().unwrap_or_else(|e| e.into_inner());
+            (
+                BroadcastStream::new(self.changes.subscribe()),
+                Change::Replace(self.backends.load_full()),
+            )
+        };
+        Some(
+            stream::once(async move { Ok(current) })
+                .chain(changes.map(move |change| match change {
+                    Ok(change) => Ok(change),
+                    // Fell behind, start over from the current backends.
+                    Err(_) => Ok(Change::Replace(self.backends.load_full())),
+                }))
+                .boxed(),
+        )
+    }
+
+    fn local(&self) -> Option<&LocalDiscovery> {
+        Some(self)
+    }
+}
+
+/// Builds the backend for one resolved address of a configured upstream server,
+/// carrying over its weight, availability settings and metadata.
+pub fn backend_for(server: &UpstreamServer, addr: SocketAddr) -> Backend {
+    Backend {
+        addr,
+        weight: server.weight(),
+        backup: server.backup(),
+        priority: 0,
+        max_fails: server.max_fails(),
+        fail_timeout: server.fail_timeout(),
+        max_conns: server.max_conns(),
+        metadata: server.metadata().clone(),
+    }
+}
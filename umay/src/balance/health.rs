@@ -0,0 +1,323 @@
+use crate::balance::Backend;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Passive health of the backends of one upstream, following the nginx
+/// `max_fails`/`fail_timeout`/`max_conns` semantics: a backend that fails
+/// `max_fails` times within `fail_timeout` is skipped for `fail_timeout`, and a
+/// backend that reached `max_conns` active connections is skipped until one
+/// of them ends.
+///
+/// It also tracks since when each backend is part of the upstream, so newly
+/// added or recovered backends can be ramped up during `slow_start`, and which
+/// backends an operator drained.
+///
+/// The set of tracked backends only changes on `sync`, selections read it
+/// without locking.
+pub struct Health {
+    epoch: Instant,
+    states: ArcSwap<HashMap<SocketAddr, Arc<BackendHealth>>>,
+    ramp: ArcSwapOption<Ramp>,
+}
+
+/// Weights last scaled by `slow_start`, handed out again while neither the
+/// candidates nor their steps change, so that selectors caching per backend
+/// set such as Maglev only rebuild on a new step.
+struct Ramp {
+    candidates: Arc<BTreeSet<Backend>>,
+    steps: Vec<usize>,
+    scaled: Arc<BTreeSet<Backend>>,
+}
+
+/// Number of steps a slow start ramp is split into. Effective weights are
+/// scaled by this factor while any backend ramps up, so even backends with
+/// weight 1 start from a small share of the traffic.
+const SLOW_START_STEPS: usize = 100;
+
+/// Health of one backend as seen by the load balancer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackendStatus {
+    /// Whether the backend can be selected now.
+    pub available: bool,
+    pub drained: bool,
+    /// Connections or requests in flight.
+    pub active: usize,
+}
+
+struct BackendHealth {
+    fails: Mutex<Fails>,
+    /// Nanoseconds since `Health::epoch` until which the backend is down, 0
+    /// when it is up.
+    down_until: AtomicU64,
+    active: AtomicUsize,
+    added_at: Instant,
+    drained: AtomicBool,
+}
+
+struct Fails {
+    count: usize,
+    checked_at: Instant,
+}
+
+impl Default for BackendHealth {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            fails: Mutex::new(Fails {
+                count: 0,
+                checked_at: now,
+            }),
+            down_until: AtomicU64::new(0),
+            active: AtomicUsize::new(0),
+            added_at: now,
+            drained: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            states: ArcSwap::from_pointee(HashMap::new()),
+            ramp: ArcSwapOption::empty(),
+        }
+    }
+}
+
+impl Health {
+    /// Returns the backends eligible for selection: the available backends
+    /// of the first tier that has any, primaries before backups and lower
+    /// priorities first.
+    pub fn candidates(&self, backends: &Arc<BTreeSet<Backend>>) -> Arc<BTreeSet<Backend>> {
+        let now = self.now();
+        let states = self.states.load();
+        let available = |b: &&Backend| match states.get(&b.addr) {
+            Some(state) => state.is_available(b, now),
+            None => true,
+        };
+        let tier = |b: &Backend| (b.backup, b.priority);
+
+        let first = backends.iter().map(tier).min();
+        if backends
+            .iter()
+            .all(|b| Some(tier(b)) == first && available(&b))
+        {
+            return Arc::clone(backends);
+        }
+
+        let Some(best) = backends.iter().filter(available).map(tier).min() else {
+            return Arc::new(BTreeSet::new());
+        };
+        Arc::new(
+            backends
+                .iter()
+                .filter(|b| tier(b) == best)
+                .filter(available)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Scales the weights of `candidates` so that backends added or recovered
+    /// less than `slow_start` ago get a linearly growing share of the traffic.
+    pub fn slow_start(
+        &self,
+        candidates: &Arc<BTreeSet<Backend>>,
+        slow_start: Duration,
+    ) -> Arc<BTreeSet<Backend>> {
+        if slow_start.is_zero() {
+            return Arc::clone(candidates);
+        }
+
+        let now = Instant::now();
+        let states = self.states.load();
+        let step = |b: &Backend| match states.get(&b.addr) {
+            Some(state) => state.slow_start_step(self.epoch, slow_start, now),
+            None => SLOW_START_STEPS,
+        };
+
+        let steps: Vec<usize> = candidates.iter().map(step).collect();
+        if steps.iter().all(|&step| step == SLOW_START_STEPS) {
+            return Arc::clone(candidates);
+        }
+
+        if let Some(ramp) = self.ramp.load().as_ref() {
+            let same_candidates = Arc::ptr_eq(&ramp.candidates, candidates)
+                || ramp.candidates.as_ref() == candidates.as_ref();
+            if same_candidates && ramp.steps == steps {
+                return Arc::clone(&ramp.scaled);
+            }
+        }
+        let scaled: Arc<BTreeSet<Backend>> = Arc::new(
+            candidates
+                .iter()
+                .zip(&steps)
+                .map(|(b, step)| Backend {
+                    weight: b.weight * step,
+                    ..b.clone()
+                })
+                .collect(),
+        );
+        self.ramp.store(Some(Arc::new(Ramp {
+            candidates: Arc::clone(candidates),
+            steps,
+            scaled: Arc::clone(&scaled),
+        })));
+        scaled
+    }
+
+    /// Records the backends returned by the latest discovery round, starting
+    /// the slow start clock of new ones and forgetting removed ones.
+    pub fn sync(&self, backends: &BTreeSet<Backend>) {
+        self.states.rcu(|states| {
+            backends
+                .iter()
+                .map(|b| {
+                    let state = states.get(&b.addr).cloned().unwrap_or_default();
+                    (b.addr, state)
+                })
+                .collect::<HashMap<_, _>>()
+        });
+    }
+
+    /// Stops or resumes selecting `backend`. Connections already made to a
+    /// drained backend are left to finish.
+    pub fn set_drained(&self, backend: &Backend, drained: bool) {
+        self.update(backend, |state| {
+            state.drained.store(drained, Ordering::Release)
+        });
+    }
+
+    pub fn status(&self, backend: &Backend) -> BackendStatus {
+        match self.states.load().get(&backend.addr) {
+            Some(state) => BackendStatus {
+                available: state.is_available(backend, self.now()),
+                drained: state.drained.load(Ordering::Acquire),
+                active: state.active.load(Ordering::Acquire),
+            },
+            None => BackendStatus {
+                available: true,
+                drained: false,
+                active: 0,
+            },
+        }
+    }
+
+    /// Counts a connection or request to `backend` unless it already has
+    /// `max_conns` of them, in one atomic step so that concurrent selections
+    /// cannot go over the limit.
+    pub fn try_start(&self, backend: &Backend) -> bool {
+        let states = self.states.load();
+        let Some(state) = states.get(&backend.addr) else {
+            return true;
+        };
+        if backend.max_conns == 0 {
+            state.active.fetch_add(1, Ordering::AcqRel);
+            return true;
+        }
+        state
+            .active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < backend.max_conns).then_some(active + 1)
+            })
+            .is_ok()
+    }
+
+    /// Counts a connection or request to `backend`, whatever its `max_conns`.
+    pub fn on_start(&self, backend: &Backend) {
+        self.update(backend, |state| {
+            state.active.fetch_add(1, Ordering::AcqRel);
+        });
+    }
+
+    pub fn on_end(&self, backend: &Backend) {
+        self.update(backend, |state| {
+            let _ = state
+                .active
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                    active.checked_sub(1)
+                });
+        });
+    }
+
+    pub fn on_success(&self, backend: &Backend) {
+        self.update(backend, |state| {
+            state.fails.lock().unwrap_or_else(|e| e.into_inner()).count = 0
+        });
+    }
+
+    pub fn on_failure(&self, backend: &Backend) {
+        if backend.max_fails == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        self.update(backend, |state| {
+            let mut fails = state.fails.lock().unwrap_or_else(|e| e.into_inner());
+            if now.saturating_duration_since(fails.checked_at) > backend.fail_timeout {
+                fails.count = 0;
+            }
+            fails.checked_at = now;
+            fails.count += 1;
+
+            if fails.count >= backend.max_fails {
+                warn!(
+                    "Backend {} failed {} times, marking unavailable for {:?}",
+                    backend.addr, fails.count, backend.fail_timeout
+                );
+                fails.count = 0;
+                let until = self.since_epoch(now + backend.fail_timeout);
+                state.down_until.store(until, Ordering::Release);
+            }
+        });
+    }
+
+    /// Applies `f` to the state of `backend`, ignored when discovery no
+    /// longer returns it.
+    fn update<F: FnOnce(&BackendHealth)>(&self, backend: &Backend, f: F) {
+        if let Some(state) = self.states.load().get(&backend.addr) {
+            f(state);
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.since_epoch(Instant::now())
+    }
+
+    fn since_epoch(&self, instant: Instant) -> u64 {
+        // Never 0, which stands for not down.
+        (instant.saturating_duration_since(self.epoch).as_nanos() as u64).max(1)
+    }
+}
+
+impl BackendHealth {
+    fn is_available(&self, backend: &Backend, now: u64) -> bool {
+        if self.drained.load(Ordering::Acquire) || self.down_until.load(Ordering::Acquire) > now {
+            return false;
+        }
+        backend.max_conns == 0 || self.active.load(Ordering::Acquire) < backend.max_conns
+    }
+
+    fn slow_start_step(&self, epoch: Instant, slow_start: Duration, now: Instant) -> usize {
+        let down_until = self.down_until.load(Ordering::Acquire);
+        let recovered_at = epoch + Duration::from_nanos(down_until);
+        let ramp_start = if down_until > 0 && recovered_at > self.added_at {
+            recovered_at
+        } else {
+            self.added_at
+        };
+        let elapsed = now.saturating_duration_since(ramp_start);
+        if elapsed >= slow_start {
+            return SLOW_START_STEPS;
+        }
+
+        let step = elapsed.as_nanos() * SLOW_START_STEPS as u128 / slow_start.as_nanos();
+        (step as usize).max(1)
+    }
+}
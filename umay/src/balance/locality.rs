@@ -0,0 +1,70 @@
+use crate::balance::Backend;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+/// Prefers the backends in the zone of this instance.
+///
+/// The zone of a backend is read from its metadata under `label`. Traffic
+/// stays in the local zone while the healthy local backends hold at least
+/// `spillover_threshold` of the local weight, below that it spills over to
+/// the healthy backends of every zone.
+#[derive(Clone, Debug)]
+pub struct Locality {
+    zone: String,
+    label: String,
+    spillover_threshold: f64,
+}
+
+impl Locality {
+    pub fn new(zone: String, label: String, spillover_threshold: f64) -> Self {
+        Self {
+            zone,
+            label,
+            spillover_threshold: spillover_threshold.clamp(0.0, 1.0),
+        }
+    }
+
+    pub fn zone(&self) -> &str {
+        &self.zone
+    }
+
+    pub fn is_local(&self, backend: &Backend) -> bool {
+        backend.metadata.get(&self.label) == Some(&self.zone)
+    }
+
+    /// Narrows the healthy `candidates` among `backends` down to the local
+    /// ones, unless too little of the local capacity is left.
+    pub fn filter(
+        &self,
+        backends: &BTreeSet<Backend>,
+        candidates: &Arc<BTreeSet<Backend>>,
+    ) -> Arc<BTreeSet<Backend>> {
+        let local_weight = |backends: &BTreeSet<Backend>| -> usize {
+            backends
+                .iter()
+                .filter(|b| !b.backup && self.is_local(b))
+                .map(|b| b.weight)
+                .sum()
+        };
+
+        let total = local_weight(backends);
+        if total == 0 {
+            return Arc::clone(candidates);
+        }
+        let healthy = local_weight(candidates);
+        if healthy == 0 || (healthy as f64) < total as f64 * self.spillover_threshold {
+            return Arc::clone(candidates);
+        }
+
+        if candidates.iter().all(|b| self.is_local(b)) {
+            return Arc::clone(candidates);
+        }
+        Arc::new(
+            candidates
+                .iter()
+                .filter(|b| self.is_local(b))
+                .cloned()
+                .collect(),
+        )
+    }
+}
@@ -1,26 +1,61 @@
-use crate::balance::discovery::ServiceDiscovery;
+use crate::app::config::RefreshConfig;
+use crate::app::metric::UpstreamMetrics;
+use crate::balance::circuit::CircuitBreaker;
+use crate::balance::discovery::{jittered, Change, LocalDiscovery, ServiceDiscovery, Watch};
+use crate::balance::health::{BackendStatus, Health};
+use crate::balance::locality::Locality;
 use crate::balance::selection::SelectionAlgorithm;
+use crate::balance::subset::{Fallback, Subset};
 use arc_swap::ArcSwap;
-use std::collections::BTreeSet;
+use futures::StreamExt;
+use std::collections::{BTreeMap, BTreeSet};
 use std::hash::{DefaultHasher, Hash, Hasher};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::task::JoinHandle;
-use tracing::error;
+use tracing::{error, warn};
 
+pub mod circuit;
 pub mod discovery;
+pub mod health;
+pub mod locality;
 pub mod selection;
+pub mod subset;
+
+/// Number of failed attempts within `fail_timeout` after which a backend is
+/// considered unavailable for the rest of the `fail_timeout`.
+pub const DEFAULT_MAX_FAILS: usize = 1;
+
+pub const DEFAULT_FAIL_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Clone, Hash, PartialEq, PartialOrd, Eq, Ord, Debug)]
 pub struct Backend {
     pub addr: SocketAddr,
     pub weight: usize,
+    pub backup: bool,
+    /// Tier among the primaries or the backups, lower tiers are used first,
+    /// such as SRV record priorities.
+    pub priority: u16,
+    pub max_fails: usize,
+    pub fail_timeout: Duration,
+    pub max_conns: usize,
+    pub metadata: BTreeMap<String, String>,
 }
 
 impl Backend {
     pub fn new(addr: SocketAddr, weight: usize) -> Self {
-        Backend { addr, weight }
+        Backend {
+            addr,
+            weight,
+            backup: false,
+            priority: 0,
+            max_fails: DEFAULT_MAX_FAILS,
+            fail_timeout: DEFAULT_FAIL_TIMEOUT,
+            max_conns: 0,
+            metadata: BTreeMap::new(),
+        }
     }
 
     pub fn hash_key(&self) -> u64 {
@@ -31,15 +66,14 @@ impl Backend {
 }
 
 pub struct Backends {
-    discovery: Box<dyn ServiceDiscovery + Send + Sync + 'static>,
+    discovery: Arc<dyn ServiceDiscovery + Send + Sync + 'static>,
     backends: ArcSwap<BTreeSet<Backend>>,
 }
 
 impl Backends {
     pub fn new(discovery: Box<dyn ServiceDiscovery + Send + Sync + 'static>) -> Self {
-        //TODO: May call refresh here after initialization
         Self {
-            discovery,
+            discovery: Arc::from(discovery),
             backends: ArcSwap::from_pointee(BTreeSet::new()),
         }
     }
@@ -53,11 +87,47 @@ impl Backends {
     pub fn get_backends(&self) -> Arc<BTreeSet<Backend>> {
         self.backends.load_full()
     }
+
+    /// How long the current backends stay valid according to discovery.
+    pub fn ttl(&self) -> Option<Duration> {
+        self.discovery.ttl()
+    }
+
+    /// Returns the changes pushed by discovery, or polls it on the schedule
+    /// of `refresh` when it cannot push them.
+    pub fn watch(&self, refresh: &RefreshConfig) -> Watch {
+        let healthy = !self.get_backends().is_empty();
+        Arc::clone(&self.discovery).watch().unwrap_or_else(|| {
+            discovery::poll(Arc::clone(&self.discovery), refresh.clone(), healthy)
+        })
+    }
+
+    pub fn apply(&self, change: Change) {
+        self.backends.rcu(|backends| change.clone().apply(backends));
+    }
+
+    pub fn clear(&self) {
+        self.backends.store(Arc::new(BTreeSet::new()));
+    }
+
+    /// The discovery source when its backends are managed in process.
+    pub fn local_discovery(&self) -> Option<&LocalDiscovery> {
+        self.discovery.local()
+    }
 }
 
 pub struct LoadBalancer {
     selection: Arc<dyn SelectionAlgorithm + Send + Sync>,
     backends: Arc<Backends>,
+    health: Arc<Health>,
+    slow_start: Duration,
+    refresh: RefreshConfig,
+    locality: Option<Locality>,
+    subset_fallback: Fallback,
+    circuit_breaker: Arc<CircuitBreaker>,
+    retries: usize,
+    metrics: UpstreamMetrics,
+    discovered: AtomicBool,
 }
 
 impl LoadBalancer {
@@ -65,27 +135,236 @@ impl LoadBalancer {
         Self {
             selection,
             backends: Arc::new(backends),
+            health: Arc::new(Health::default()),
+            slow_start: Duration::ZERO,
+            refresh: RefreshConfig::default(),
+            locality: None,
+            subset_fallback: Fallback::default(),
+            circuit_breaker: Arc::new(CircuitBreaker::default()),
+            retries: 0,
+            metrics: UpstreamMetrics::default(),
+            discovered: AtomicBool::new(false),
         }
     }
 
+    /// Ramps the weight of new or recovered backends up over `slow_start`.
+    pub fn set_slow_start(&mut self, slow_start: Duration) {
+        self.slow_start = slow_start;
+    }
+
+    pub fn set_refresh(&mut self, refresh: RefreshConfig) {
+        self.refresh = refresh;
+    }
+
+    /// Keeps selections in the local zone while enough of it is healthy.
+    pub fn set_locality(&mut self, locality: Option<Locality>) {
+        self.locality = locality;
+    }
+
+    /// What `select_subset` picks from when the subset has no backends.
+    pub fn set_subset_fallback(&mut self, fallback: Fallback) {
+        self.subset_fallback = fallback;
+    }
+
+    pub fn set_circuit_breaker(&mut self, circuit_breaker: CircuitBreaker) {
+        self.circuit_breaker = Arc::new(circuit_breaker);
+    }
+
+    /// Number of other backends tried after a failed connect.
+    pub fn set_retries(&mut self, retries: usize) {
+        self.retries = retries;
+    }
+
+    pub fn set_metrics(&mut self, metrics: UpstreamMetrics) {
+        self.metrics = metrics;
+    }
+
+    pub fn metrics(&self) -> &UpstreamMetrics {
+        &self.metrics
+    }
+
+    pub fn circuit_breaker(&self) -> Arc<CircuitBreaker> {
+        Arc::clone(&self.circuit_breaker)
+    }
+
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    /// Whether discovery succeeded at least once.
+    pub fn discovered(&self) -> bool {
+        self.discovered.load(Ordering::Acquire)
+    }
+
     pub async fn select(&self, key: Option<&str>) -> Option<Backend> {
+        self.select_subset(key, &Subset::new()).await
+    }
+
+    /// Selects among the backends whose metadata matches `subset`, every
+    /// backend when it is empty.
+    pub async fn select_subset(&self, key: Option<&str>, subset: &Subset) -> Option<Backend> {
         let backends = self.backends.get_backends();
         if backends.is_empty() {
             return None;
         }
+        let backends = subset::filter(&backends, subset, &self.subset_fallback)?;
+
+        let candidates = self.health.candidates(&backends);
+        if candidates.is_empty() {
+            return None;
+        }
+        let candidates = match &self.locality {
+            Some(locality) => locality.filter(&backends, &candidates),
+            None => candidates,
+        };
+        let candidates = self.health.slow_start(&candidates, self.slow_start);
 
-        self.selection.select(&backends).await
+        let backend = self.selection.select(&candidates, key).await;
+        if let Some(backend) = &backend {
+            self.metrics.on_select(backend.addr);
+        }
+        backend
+    }
+
+    pub fn backends(&self) -> Arc<Backends> {
+        Arc::clone(&self.backends)
     }
 
-    pub fn start_refresh_task(self: Arc<Self>, duration: Duration) -> JoinHandle<()> {
+    pub fn backend_status(&self, backend: &Backend) -> BackendStatus {
+        self.health.status(backend)
+    }
+
+    /// Stops or resumes selecting the backend at `addr`, returning false when
+    /// the upstream has no such backend.
+    pub fn set_drained(&self, addr: SocketAddr, drained: bool) -> bool {
+        let backends = self.backends.get_backends();
+        match backends.iter().find(|b| b.addr == addr) {
+            Some(backend) => {
+                self.health.set_drained(backend, drained);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Selects a backend like `select_subset` and starts tracking it, taking
+    /// one of its `max_conns` in the same step. A backend filled up by a
+    /// concurrent selection in the meantime is skipped.
+    pub async fn acquire(&self, key: Option<&str>, subset: &Subset) -> Option<InFlight> {
+        let attempts = self.backends.get_backends().len().max(1);
+        for _ in 0..attempts {
+            let backend = self.select_subset(key, subset).await?;
+            if self.health.try_start(&backend) {
+                self.selection.on_start(&backend);
+                return Some(self.in_flight(backend));
+            }
+        }
+        None
+    }
+
+    /// Starts tracking a connection or request to `backend`, feeding the
+    /// selection algorithm and the backend health until the returned guard
+    /// is dropped.
+    pub fn track(&self, backend: &Backend) -> InFlight {
+        self.selection.on_start(backend);
+        self.health.on_start(backend);
+        self.in_flight(backend.clone())
+    }
+
+    fn in_flight(&self, backend: Backend) -> InFlight {
+        InFlight {
+            selection: Arc::clone(&self.selection),
+            health: Arc::clone(&self.health),
+            backend,
+        }
+    }
+
+    /// Runs one discovery round and starts tracking the backends it found.
+    pub async fn refresh(&self) -> eyre::Result<()> {
+        if let Err(e) = self.backends.refresh().await {
+            self.metrics.on_refresh("failure");
+            return Err(e);
+        }
+        self.metrics.on_refresh("success");
+        self.sync();
+        Ok(())
+    }
+
+    fn sync(&self) {
+        self.discovered.store(true, Ordering::Release);
+        let backends = self.backends.get_backends();
+        self.health.sync(&backends);
+        self.selection.on_sync(&backends);
+        self.circuit_breaker.sync(&backends);
+        self.metrics
+            .sync(backends.iter().map(|backend| backend.addr));
+    }
+
+    /// Keeps the backends up to date, applying changes as discovery pushes
+    /// them or polling it following its TTL within the configured bounds. On
+    /// errors the last known backends are kept for the stale grace period.
+    pub fn start_refresh_task(self: Arc<Self>) -> JoinHandle<()> {
         tokio::spawn(async move {
-            let mut ticker = tokio::time::interval(duration);
+            let mut last_success = Instant::now();
             loop {
-                ticker.tick().await;
-                if let Err(e) = self.backends.refresh().await {
-                    error!("Failed to refresh backends: {:?}", e);
+                let mut changes = self.backends.watch(&self.refresh);
+                while let Some(change) = changes.next().await {
+                    match change {
+                        Ok(change) => {
+                            self.backends.apply(change);
+                            self.sync();
+                            self.metrics.on_refresh("success");
+                            last_success = Instant::now();
+                        }
+                        Err(e) => {
+                            if last_success.elapsed() < self.refresh.stale_grace_period() {
+                                warn!("Failed to refresh backends, keeping stale ones: {:?}", e);
+                                self.metrics.on_refresh("stale");
+                            } else {
+                                error!("Failed to refresh backends: {:?}", e);
+                                self.metrics.on_refresh("failure");
+                                self.backends.clear();
+                                self.sync();
+                            }
+                        }
+                    }
                 }
+
+                // The source closed its watch, start a new one.
+                let interval = self.refresh.min_interval();
+                tokio::time::sleep(jittered(interval, self.refresh.jitter())).await;
             }
         })
     }
 }
+
+pub struct InFlight {
+    selection: Arc<dyn SelectionAlgorithm + Send + Sync>,
+    health: Arc<Health>,
+    backend: Backend,
+}
+
+impl InFlight {
+    pub fn backend(&self) -> &Backend {
+        &self.backend
+    }
+
+    /// Records a successful connect or response and its latency.
+    pub fn observe_latency(&self, latency: Duration) {
+        self.selection.on_latency(&self.backend, latency);
+        self.health.on_success(&self.backend);
+    }
+
+    /// Records a failed connect or response, counting towards `max_fails`.
+    pub fn observe_failure(&self) {
+        self.selection.on_failure(&self.backend);
+        self.health.on_failure(&self.backend);
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.selection.on_end(&self.backend);
+        self.health.on_end(&self.backend);
+    }
+}
@@ -1,16 +1,34 @@
 use crate::balance::Backend;
-use arc_swap::ArcSwap;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use async_trait::async_trait;
 use rand::Rng;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::hash::{DefaultHasher, Hash, Hasher};
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 #[async_trait]
 pub trait SelectionAlgorithm: Send + Sync {
-    async fn select(&self, backends: &Arc<BTreeSet<Backend>>) -> Option<Backend>;
+    async fn select(&self, backends: &Arc<BTreeSet<Backend>>, key: Option<&str>)
+        -> Option<Backend>;
+
+    /// Called by the proxy once a connection or request to `backend` starts.
+    fn on_start(&self, _backend: &Backend) {}
+
+    /// Called with the connect or response latency observed for `backend`.
+    fn on_latency(&self, _backend: &Backend, _latency: Duration) {}
+
+    /// Called when a connect or response of `backend` failed.
+    fn on_failure(&self, _backend: &Backend) {}
+
+    /// Called once the connection or request started with `on_start` ends.
+    fn on_end(&self, _backend: &Backend) {}
+
+    /// Called with the backends of the upstream whenever discovery changed
+    /// them, so that state kept for removed backends can be dropped.
+    fn on_sync(&self, _backends: &BTreeSet<Backend>) {}
 }
 
 pub struct RoundRobin {
@@ -26,7 +44,11 @@ impl Default for RoundRobin {
 }
 #[async_trait]
 impl SelectionAlgorithm for RoundRobin {
-    async fn select(&self, backends: &Arc<BTreeSet<Backend>>) -> Option<Backend> {
+    async fn select(
+        &self,
+        backends: &Arc<BTreeSet<Backend>>,
+        _key: Option<&str>,
+    ) -> Option<Backend> {
         let len = backends.len();
         if len == 0 {
             return None;
@@ -35,33 +57,50 @@ impl SelectionAlgorithm for RoundRobin {
         backends.iter().nth(index).cloned()
     }
 }
+/// nginx-style smooth weighted round robin. Every pick raises each backend's
+/// current weight by its configured weight, selects the highest one and lowers
+/// it by the total weight, so weights `{a: 5, b: 1, c: 1}` yield
+/// `a a b a c a a` instead of five consecutive picks of `a`. Current weights are
+/// kept per address, so a refresh that returns the same backends does not
+/// restart the sequence.
+#[derive(Default)]
 pub struct WeightedRoundRobin {
-    index: AtomicUsize,
-}
-
-impl Default for WeightedRoundRobin {
-    fn default() -> Self {
-        Self {
-            index: AtomicUsize::new(0),
-        }
-    }
+    current_weights: Mutex<HashMap<SocketAddr, isize>>,
 }
 
 #[async_trait]
 impl SelectionAlgorithm for WeightedRoundRobin {
-    async fn select(&self, backends: &Arc<BTreeSet<Backend>>) -> Option<Backend> {
-        let total_weight: usize = backends.iter().map(|b| b.weight).sum();
-        if total_weight == 0 {
-            return None;
-        }
-        let mut index = self.index.fetch_add(1, Ordering::Relaxed) % total_weight;
-        for backend in backends.iter() {
-            if index < backend.weight {
-                return Some(backend.clone());
+    async fn select(
+        &self,
+        backends: &Arc<BTreeSet<Backend>>,
+        _key: Option<&str>,
+    ) -> Option<Backend> {
+        let mut current_weights = self
+            .current_weights
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        let mut total_weight = 0;
+        let mut selected: Option<(&Backend, isize)> = None;
+        for backend in backends.iter().filter(|b| b.weight > 0) {
+            let weight = backend.weight as isize;
+            let current = current_weights.entry(backend.addr).or_insert(0);
+            *current += weight;
+            total_weight += weight;
+            if selected.is_none_or(|(_, best)| *current > best) {
+                selected = Some((backend, *current));
             }
-            index -= backend.weight;
         }
-        None
+
+        if current_weights.len() > backends.len() {
+            current_weights.retain(|addr, _| backends.iter().any(|b| b.addr == *addr));
+        }
+
+        let (backend, _) = selected?;
+        if let Some(current) = current_weights.get_mut(&backend.addr) {
+            *current -= total_weight;
+        }
+        Some(backend.clone())
     }
 }
 
@@ -101,13 +140,25 @@ impl LeastConnections {
 
 #[async_trait]
 impl SelectionAlgorithm for LeastConnections {
-    async fn select(&self, backends: &Arc<BTreeSet<Backend>>) -> Option<Backend> {
+    async fn select(
+        &self,
+        backends: &Arc<BTreeSet<Backend>>,
+        _key: Option<&str>,
+    ) -> Option<Backend> {
         let connections = self.connections.load();
         backends
             .iter()
             .min_by_key(|b| connections.get(&b.addr).unwrap_or(&0))
             .cloned()
     }
+
+    fn on_start(&self, backend: &Backend) {
+        self.increment(&backend.addr);
+    }
+
+    fn on_end(&self, backend: &Backend) {
+        self.decrement(&backend.addr);
+    }
 }
 
 #[derive(Default)]
@@ -115,7 +166,11 @@ pub struct Random;
 
 #[async_trait]
 impl SelectionAlgorithm for Random {
-    async fn select(&self, backends: &Arc<BTreeSet<Backend>>) -> Option<Backend> {
+    async fn select(
+        &self,
+        backends: &Arc<BTreeSet<Backend>>,
+        _key: Option<&str>,
+    ) -> Option<Backend> {
         if backends.is_empty() {
             return None;
         }
@@ -125,6 +180,305 @@ impl SelectionAlgorithm for Random {
     }
 }
 
+/// Default decay window of the latency estimate used by [`P2cEwma`].
+pub const DEFAULT_EWMA_DECAY: Duration = Duration::from_secs(10);
+
+/// Latency assumed for backends that have not been measured yet.
+pub const DEFAULT_EWMA_RTT: Duration = Duration::from_millis(30);
+
+/// Latency a failure counts as at least, so that a backend refusing
+/// connections right away does not look like the fastest one.
+pub const EWMA_FAILURE_PENALTY: Duration = Duration::from_secs(1);
+
+/// Power of two choices over a peak-sensitive EWMA of the observed latency, as
+/// done by linkerd and Finagle. Two backends are sampled at random and the one
+/// with the lower `ewma * (in_flight + 1) / weight` cost wins, which steers
+/// traffic away from slow or busy backends without scanning the whole set.
+/// Failures count as a latency of at least [`EWMA_FAILURE_PENALTY`].
+pub struct P2cEwma {
+    decay: Duration,
+    default_rtt: Duration,
+    loads: Mutex<HashMap<SocketAddr, EwmaLoad>>,
+}
+
+struct EwmaLoad {
+    rtt_ns: f64,
+    updated_at: Instant,
+    in_flight: usize,
+}
+
+impl Default for P2cEwma {
+    fn default() -> Self {
+        Self::new(DEFAULT_EWMA_DECAY, DEFAULT_EWMA_RTT)
+    }
+}
+
+impl P2cEwma {
+    pub fn new(decay: Duration, default_rtt: Duration) -> Self {
+        Self {
+            decay,
+            default_rtt,
+            loads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn cost(&self, loads: &HashMap<SocketAddr, EwmaLoad>, backend: &Backend) -> f64 {
+        let (rtt_ns, in_flight) = loads
+            .get(&backend.addr)
+            .map(|load| (load.rtt_ns, load.in_flight))
+            .unwrap_or((self.default_rtt.as_nanos() as f64, 0));
+        rtt_ns * (in_flight + 1) as f64 / backend.weight.max(1) as f64
+    }
+
+    fn update<F: FnOnce(&mut EwmaLoad)>(&self, backend: &Backend, f: F) {
+        let mut loads = self.loads.lock().unwrap_or_else(|e| e.into_inner());
+        let load = loads.entry(backend.addr).or_insert_with(|| EwmaLoad {
+            rtt_ns: self.default_rtt.as_nanos() as f64,
+            updated_at: Instant::now(),
+            in_flight: 0,
+        });
+        f(load);
+    }
+}
+
+#[async_trait]
+impl SelectionAlgorithm for P2cEwma {
+    async fn select(
+        &self,
+        backends: &Arc<BTreeSet<Backend>>,
+        _key: Option<&str>,
+    ) -> Option<Backend> {
+        let len = backends.len();
+        if len < 2 {
+            return backends.iter().next().cloned();
+        }
+
+        let (a, b) = {
+            let mut rng = rand::thread_rng();
+            let a = rng.gen_range(0..len);
+            let b = (a + rng.gen_range(1..len)) % len;
+            (a, b)
+        };
+        let a = backends.iter().nth(a)?;
+        let b = backends.iter().nth(b)?;
+
+        let loads = self.loads.lock().unwrap_or_else(|e| e.into_inner());
+        if self.cost(&loads, a) <= self.cost(&loads, b) {
+            Some(a.clone())
+        } else {
+            Some(b.clone())
+        }
+    }
+
+    fn on_start(&self, backend: &Backend) {
+        self.update(backend, |load| load.in_flight += 1);
+    }
+
+    fn on_latency(&self, backend: &Backend, latency: Duration) {
+        let decay_ns = self.decay.as_nanos().max(1) as f64;
+        self.update(backend, |load| {
+            let now = Instant::now();
+            let rtt_ns = latency.as_nanos() as f64;
+            if rtt_ns > load.rtt_ns {
+                // Peak sensitivity: react to a slow backend immediately and
+                // only let the estimate recover gradually.
+                load.rtt_ns = rtt_ns;
+            } else {
+                let elapsed_ns = now.saturating_duration_since(load.updated_at).as_nanos() as f64;
+                let w = (-elapsed_ns / decay_ns).exp();
+                load.rtt_ns = load.rtt_ns * w + rtt_ns * (1.0 - w);
+            }
+            load.updated_at = now;
+        });
+    }
+
+    fn on_failure(&self, backend: &Backend) {
+        let penalty_ns = EWMA_FAILURE_PENALTY.as_nanos() as f64;
+        self.update(backend, |load| {
+            load.rtt_ns = load.rtt_ns.max(penalty_ns);
+            load.updated_at = Instant::now();
+        });
+    }
+
+    fn on_end(&self, backend: &Backend) {
+        let mut loads = self.loads.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(load) = loads.get_mut(&backend.addr) {
+            load.in_flight = load.in_flight.saturating_sub(1);
+        }
+    }
+
+    /// Forgets removed backends once nothing is in flight to them anymore.
+    fn on_sync(&self, backends: &BTreeSet<Backend>) {
+        let current: HashSet<SocketAddr> = backends.iter().map(|b| b.addr).collect();
+        self.loads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|addr, load| current.contains(addr) || load.in_flight > 0);
+    }
+}
+
+/// Default Maglev lookup table size. It has to be prime, and the Maglev paper
+/// recommends keeping it well above 100x the number of backends.
+pub const DEFAULT_MAGLEV_TABLE_SIZE: usize = 65537;
+
+const EMPTY_SLOT: usize = usize::MAX;
+
+/// Maglev hashing (Eisenbud et al., NSDI '16). Each backend fills a prime sized
+/// lookup table following its own permutation, which gives O(1) lookups, an
+/// even spread of keys and only a small share of remapped keys when the
+/// backend set changes. Backend weights are honoured by letting heavier
+/// backends claim table slots more often.
+pub struct Maglev {
+    table_size: usize,
+    table: ArcSwapOption<MaglevTable>,
+}
+
+struct MaglevTable {
+    backends: Arc<BTreeSet<Backend>>,
+    lookup: Arc<MaglevLookup>,
+}
+
+struct MaglevLookup {
+    nodes: Vec<Backend>,
+    slots: Vec<usize>,
+}
+
+impl Default for Maglev {
+    fn default() -> Self {
+        Self {
+            table_size: DEFAULT_MAGLEV_TABLE_SIZE,
+            table: ArcSwapOption::empty(),
+        }
+    }
+}
+
+impl Maglev {
+    pub fn new(table_size: usize) -> eyre::Result<Self> {
+        if !is_prime(table_size) {
+            eyre::bail!(
+                "Maglev table size must be a prime number, got {}",
+                table_size
+            );
+        }
+        Ok(Self {
+            table_size,
+            table: ArcSwapOption::empty(),
+        })
+    }
+
+    fn lookup(&self, backends: &Arc<BTreeSet<Backend>>) -> Arc<MaglevLookup> {
+        if let Some(table) = self.table.load_full() {
+            if Arc::ptr_eq(&table.backends, backends) {
+                return Arc::clone(&table.lookup);
+            }
+            // Backends::refresh swaps in a fresh set on every tick, so only
+            // rebuild when the content actually changed.
+            if table.backends == *backends {
+                self.table.store(Some(Arc::new(MaglevTable {
+                    backends: Arc::clone(backends),
+                    lookup: Arc::clone(&table.lookup),
+                })));
+                return Arc::clone(&table.lookup);
+            }
+        }
+
+        let lookup = Arc::new(MaglevLookup::build(backends, self.table_size));
+        self.table.store(Some(Arc::new(MaglevTable {
+            backends: Arc::clone(backends),
+            lookup: Arc::clone(&lookup),
+        })));
+        lookup
+    }
+}
+
+impl MaglevLookup {
+    fn build(backends: &BTreeSet<Backend>, table_size: usize) -> Self {
+        let nodes: Vec<Backend> = backends.iter().filter(|b| b.weight > 0).cloned().collect();
+        let mut slots = vec![EMPTY_SLOT; table_size];
+        if nodes.is_empty() {
+            return Self { nodes, slots };
+        }
+
+        let m = table_size as u64;
+        let permutations: Vec<(u64, u64)> = nodes
+            .iter()
+            .map(|b| {
+                let offset = hash_of(&(0u8, &b.addr)) % m;
+                let skip = hash_of(&(1u8, &b.addr)) % (m - 1) + 1;
+                (offset, skip)
+            })
+            .collect();
+        let max_weight = nodes.iter().map(|b| b.weight).max().unwrap_or(1);
+
+        let mut next = vec![0u64; nodes.len()];
+        let mut credits = vec![0usize; nodes.len()];
+        let mut filled = 0;
+        'fill: loop {
+            for (i, node) in nodes.iter().enumerate() {
+                credits[i] += node.weight;
+                if credits[i] < max_weight {
+                    continue;
+                }
+                credits[i] -= max_weight;
+
+                let (offset, skip) = permutations[i];
+                loop {
+                    let slot = ((offset + skip * next[i]) % m) as usize;
+                    next[i] += 1;
+                    if slots[slot] == EMPTY_SLOT {
+                        slots[slot] = i;
+                        filled += 1;
+                        break;
+                    }
+                }
+                if filled == table_size {
+                    break 'fill;
+                }
+            }
+        }
+
+        Self { nodes, slots }
+    }
+}
+
+#[async_trait]
+impl SelectionAlgorithm for Maglev {
+    async fn select(
+        &self,
+        backends: &Arc<BTreeSet<Backend>>,
+        key: Option<&str>,
+    ) -> Option<Backend> {
+        if backends.is_empty() {
+            return None;
+        }
+        let lookup = self.lookup(backends);
+        if lookup.nodes.is_empty() {
+            return None;
+        }
+
+        let slot = match key {
+            Some(key) => (hash_of(&key) % self.table_size as u64) as usize,
+            None => rand::thread_rng().gen_range(0..self.table_size),
+        };
+        lookup.nodes.get(lookup.slots[slot]).cloned()
+    }
+}
+
+fn hash_of<T: Hash>(t: &T) -> u64 {
+    let mut s = DefaultHasher::new();
+    t.hash(&mut s);
+    s.finish()
+}
+
+fn is_prime(n: usize) -> bool {
+    if n < 2 {
+        return false;
+    }
+    (2..)
+        .take_while(|i| i * i <= n)
+        .all(|i| !n.is_multiple_of(i))
+}
+
 pub struct ConsistentHashing {
     virtual_nodes: usize,
 }
@@ -143,7 +497,11 @@ impl ConsistentHashing {
 
 #[async_trait]
 impl SelectionAlgorithm for ConsistentHashing {
-    async fn select(&self, backends: &Arc<BTreeSet<Backend>>) -> Option<Backend> {
+    async fn select(
+        &self,
+        backends: &Arc<BTreeSet<Backend>>,
+        _key: Option<&str>,
+    ) -> Option<Backend> {
         todo!()
     }
 }
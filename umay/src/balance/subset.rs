@@ -0,0 +1,55 @@
+use crate::balance::Backend;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+/// Labels a backend must carry, with the same values, to belong to a subset.
+pub type Subset = BTreeMap<String, String>;
+
+/// What to select from when no backend belongs to the requested subset.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Fallback {
+    /// Any backend of the upstream.
+    #[default]
+    Any,
+    /// Nothing, the connection or request is rejected.
+    None,
+    /// The backends of another subset, such as the stable version.
+    Subset(Subset),
+}
+
+pub fn contains(subset: &Subset, backend: &Backend) -> bool {
+    subset
+        .iter()
+        .all(|(label, value)| backend.metadata.get(label) == Some(value))
+}
+
+/// Narrows `backends` down to `subset`, falling back as configured when the
+/// subset is empty. Returns `None` when nothing is left to select from.
+pub fn filter(
+    backends: &Arc<BTreeSet<Backend>>,
+    subset: &Subset,
+    fallback: &Fallback,
+) -> Option<Arc<BTreeSet<Backend>>> {
+    if subset.is_empty() {
+        return Some(Arc::clone(backends));
+    }
+
+    let members = |subset: &Subset| -> BTreeSet<Backend> {
+        backends
+            .iter()
+            .filter(|b| contains(subset, b))
+            .cloned()
+            .collect()
+    };
+
+    let selected = members(subset);
+    if !selected.is_empty() {
+        return Some(Arc::new(selected));
+    }
+
+    match fallback {
+        Fallback::Any => Some(Arc::clone(backends)),
+        Fallback::None => None,
+        Fallback::Subset(default) => Some(Arc::new(members(default))).filter(|b| !b.is_empty()),
+    }
+}
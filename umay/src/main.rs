@@ -1,46 +1,27 @@
-use crate::app::config::UmayConfig;
-use crate::app::server::UmayServer;
-use crate::app::signal;
 use eyre::WrapErr;
 use std::sync::Arc;
 use tokio::runtime::Builder;
-use tracing::level_filters::LevelFilter;
-use tracing::{error, info};
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{fmt, EnvFilter, Registry};
+use tracing::{error, info, warn};
+use umay::app::config::UmayConfig;
+use umay::app::logging;
+use umay::app::server::UmayServer;
+use umay::app::signal;
+use umay::app::telemetry;
 
 #[global_allocator]
 static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
-mod app;
-mod balance;
-mod proxy;
-mod tls;
-
 fn main() -> eyre::Result<()> {
-    init_logger(LevelFilter::DEBUG);
-
     let config = Arc::new(UmayConfig::load()?);
-
     let runtime = build_runtime(config.worker_threads())?;
-    runtime.block_on(run_server(config))
-}
-
-fn init_logger(default_level: LevelFilter) {
-    let filter_layer = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| EnvFilter::new(default_level.to_string()));
-
-    let fmt_layer = fmt::layer()
-        .with_thread_names(true)
-        .with_thread_ids(true)
-        .with_line_number(true)
-        .compact();
-
-    Registry::default()
-        .with(filter_layer)
-        .with(fmt_layer)
-        .init();
+    // The span exporter runs on the runtime.
+    let guard = runtime.enter();
+    logging::init(config.logging(), config.telemetry())?;
+    drop(guard);
+
+    let result = runtime.block_on(run_server(config));
+    telemetry::shutdown();
+    result
 }
 
 fn build_runtime(worker_threads: usize) -> eyre::Result<tokio::runtime::Runtime> {
@@ -54,6 +35,9 @@ fn build_runtime(worker_threads: usize) -> eyre::Result<tokio::runtime::Runtime>
 }
 
 async fn run_server(config: Arc<UmayConfig>) -> eyre::Result<()> {
+    if let Err(e) = logging::toggle_debug_on_signal() {
+        warn!("Log level cannot be toggled by signal: {:?}", e);
+    }
     match UmayServer::try_from(config.clone()) {
         Ok(umay) => {
             let shutdown_signal = signal::shutdown().await;
@@ -0,0 +1,213 @@
+use crate::app::config::{AclConfig, FileFormat};
+use arc_swap::ArcSwap;
+use eyre::{Context, Result};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// How often a watched ACL file is checked for changes.
+const FILE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Why a client was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// In a `deny` range.
+    Denied,
+    /// In none of the `allow` ranges.
+    NotAllowed,
+}
+
+impl Rejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::Denied => "denied",
+            Rejection::NotAllowed => "not_allowed",
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Client {} by the ACL", self.as_str().replace('_', " "))
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+#[derive(Debug, Default, Deserialize)]
+struct Rules {
+    #[serde(default, deserialize_with = "networks")]
+    allow: Vec<IpNet>,
+    #[serde(default, deserialize_with = "networks")]
+    deny: Vec<IpNet>,
+}
+
+impl Rules {
+    fn parse(allow: &[String], deny: &[String]) -> Result<Self> {
+        Ok(Self {
+            allow: allow
+                .iter()
+                .map(|s| parse_network(s))
+                .collect::<Result<_>>()?,
+            deny: deny
+                .iter()
+                .map(|s| parse_network(s))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    fn extend(&mut self, other: &Rules) {
+        self.allow.extend(&other.allow);
+        self.deny.extend(&other.deny);
+    }
+
+    fn check(&self, client: IpAddr) -> Result<(), Rejection> {
+        if self.deny.iter().any(|network| network.contains(&client)) {
+            return Err(Rejection::Denied);
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|network| network.contains(&client)) {
+            return Err(Rejection::NotAllowed);
+        }
+        Ok(())
+    }
+}
+
+/// A CIDR range, or a single address.
+fn parse_network(s: &str) -> Result<IpNet> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| eyre::eyre!("Invalid address or CIDR range '{}'", s))
+}
+
+fn networks<'de, D>(deserializer: D) -> Result<Vec<IpNet>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| parse_network(s).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+struct RulesFile {
+    path: PathBuf,
+    format: FileFormat,
+    loaded: Mutex<Option<(Option<SystemTime>, u64)>>,
+}
+
+impl RulesFile {
+    /// Reads the file when it changed since it was last read.
+    fn read_if_changed(&self) -> Result<Option<Rules>> {
+        let metadata = std::fs::metadata(&self.path)
+            .wrap_err_with(|| format!("Failed to read {}", self.path.display()))?;
+        let version = (metadata.modified().ok(), metadata.len());
+        let mut loaded = self.loaded.lock().unwrap_or_else(|e| e.into_inner());
+        if version.0.is_some() && *loaded == Some(version) {
+            return Ok(None);
+        }
+        // Remembered even when invalid, so it is not parsed again until it changes.
+        *loaded = Some(version);
+        let content = std::fs::read_to_string(&self.path)?;
+        let rules = match self.format {
+            FileFormat::Json => serde_json::from_str(&content)?,
+            FileFormat::Yaml => config::Config::builder()
+                .add_source(config::File::from_str(&content, config::FileFormat::Yaml))
+                .build()?
+                .try_deserialize()?,
+        };
+        Ok(Some(rules))
+    }
+}
+
+/// Allow and deny rules of a listener, checked against the address of each
+/// accepted client before any TLS work. Rules of a file are swapped in as a
+/// whole once a rewrite parses, an invalid one keeps the last valid rules.
+pub struct Acl {
+    inline: Rules,
+    file: Option<RulesFile>,
+    rules: ArcSwap<Rules>,
+}
+
+impl Acl {
+    pub fn new(config: &AclConfig) -> Result<Self> {
+        let inline = Rules::parse(config.allow(), config.deny())?;
+        let file = match config.file() {
+            Some(path) => {
+                let path = PathBuf::from(path);
+                Some(RulesFile {
+                    format: FileFormat::from_path(&path)?,
+                    path,
+                    loaded: Mutex::new(None),
+                })
+            }
+            None => None,
+        };
+
+        let mut rules = Rules::default();
+        rules.extend(&inline);
+        if let Some(file) = &file {
+            let from_file = file
+                .read_if_changed()
+                .wrap_err_with(|| format!("Invalid ACL file {}", file.path.display()))?;
+            rules.extend(&from_file.unwrap_or_default());
+        }
+        Ok(Self {
+            inline,
+            file,
+            rules: ArcSwap::from_pointee(rules),
+        })
+    }
+
+    pub fn check(&self, client: IpAddr) -> Result<(), Rejection> {
+        self.rules.load().check(client)
+    }
+
+    /// Reloads the rules whenever the file changes, for as long as the ACL
+    /// is in use.
+    pub fn watch(self: &Arc<Self>) {
+        if self.file.is_none() {
+            return;
+        }
+        let acl = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(FILE_CHECK_INTERVAL).await;
+                let Some(acl) = acl.upgrade() else {
+                    return;
+                };
+                acl.reload();
+            }
+        });
+    }
+
+    fn reload(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        match file.read_if_changed() {
+            Ok(Some(from_file)) => {
+                let mut rules = Rules::default();
+                rules.extend(&self.inline);
+                rules.extend(&from_file);
+                info!(
+                    "Loaded {} allow and {} deny rules from {}",
+                    from_file.allow.len(),
+                    from_file.deny.len(),
+                    file.path.display()
+                );
+                self.rules.store(Arc::new(rules));
+            }
+            Ok(None) => {}
+            Err(e) => warn!(
+                "Ignoring ACL file {}, keeping the last rules: {:?}",
+                file.path.display(),
+                e
+            ),
+        }
+    }
+}
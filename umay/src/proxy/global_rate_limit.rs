@@ -0,0 +1,254 @@
+use crate::app::config::{DescriptorSource, FailureMode, GlobalRateLimitConfig};
+use crate::proxy::rate_limit::{RateLimit, RateLimited};
+use eyre::{Context, Result};
+use http::uri::PathAndQuery;
+use http::HeaderMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tonic::codec::ProstCodec;
+use tonic::transport::{Channel, Endpoint};
+use tracing::{debug, warn};
+
+/// Least time between two warnings about the service failing, the failures
+/// in between only being counted.
+const WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Messages of the Envoy rate limit service, `envoy.service.ratelimit.v3`,
+/// limited to the fields Umay sends and reads.
+pub mod proto {
+    pub const SHOULD_RATE_LIMIT: &str =
+        "/envoy.service.ratelimit.v3.RateLimitService/ShouldRateLimit";
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RateLimitRequest {
+        #[prost(string, tag = "1")]
+        pub domain: String,
+        #[prost(message, repeated, tag = "2")]
+        pub descriptors: Vec<RateLimitDescriptor>,
+        #[prost(uint32, tag = "3")]
+        pub hits_addend: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RateLimitDescriptor {
+        #[prost(message, repeated, tag = "1")]
+        pub entries: Vec<Entry>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Entry {
+        #[prost(string, tag = "1")]
+        pub key: String,
+        #[prost(string, tag = "2")]
+        pub value: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RateLimitResponse {
+        #[prost(enumeration = "Code", tag = "1")]
+        pub overall_code: i32,
+        #[prost(message, repeated, tag = "2")]
+        pub statuses: Vec<DescriptorStatus>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DescriptorStatus {
+        #[prost(enumeration = "Code", tag = "1")]
+        pub code: i32,
+        #[prost(uint32, tag = "3")]
+        pub limit_remaining: u32,
+        #[prost(message, optional, tag = "4")]
+        pub duration_until_reset: Option<Duration>,
+    }
+
+    /// `google.protobuf.Duration`
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct Duration {
+        #[prost(int64, tag = "1")]
+        pub seconds: i64,
+        #[prost(int32, tag = "2")]
+        pub nanos: i32,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Code {
+        Unknown = 0,
+        Ok = 1,
+        OverLimit = 2,
+    }
+}
+
+/// What descriptor values are taken from. Path and headers are only known
+/// for HTTP requests.
+pub struct Subject<'a> {
+    pub client: IpAddr,
+    pub sni: Option<&'a str>,
+    pub path: Option<&'a str>,
+    pub headers: Option<&'a HeaderMap>,
+}
+
+/// Why [`GlobalRateLimiter::check`] turned a connection or request away.
+#[derive(Debug)]
+pub enum Denied {
+    /// The service answered over limit.
+    OverLimit(RateLimited),
+    /// The service could not be asked and the failure mode is `deny`.
+    Unavailable,
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denied::OverLimit(limited) => limited.fmt(f),
+            Denied::Unavailable => write!(f, "Rate limit service unavailable"),
+        }
+    }
+}
+
+impl std::error::Error for Denied {}
+
+#[derive(Default)]
+struct Warnings {
+    last: Option<Instant>,
+    suppressed: u64,
+}
+
+/// Asks a rate limit service, shared by all instances, whether a connection
+/// or request is within the limits.
+pub struct GlobalRateLimiter {
+    channel: Channel,
+    config: GlobalRateLimitConfig,
+    warnings: Mutex<Warnings>,
+}
+
+impl GlobalRateLimiter {
+    /// Connects to the service on first use, so that Umay starts while the
+    /// service is down.
+    pub fn new(config: &GlobalRateLimitConfig) -> Result<Self> {
+        let channel = Endpoint::from_shared(config.endpoint().to_string())
+            .wrap_err_with(|| format!("Invalid rate limit service {}", config.endpoint()))?
+            .timeout(config.timeout())
+            .connect_lazy();
+        Ok(Self {
+            channel,
+            config: config.clone(),
+            warnings: Mutex::default(),
+        })
+    }
+
+    /// Fails when the service answers over limit, or cannot be asked and the
+    /// failure mode is `deny`.
+    pub async fn check(&self, subject: &Subject<'_>) -> Result<(), Denied> {
+        let descriptors = self.descriptors(subject);
+        if descriptors.is_empty() {
+            return Ok(());
+        }
+        let request = proto::RateLimitRequest {
+            domain: self.config.domain().to_string(),
+            descriptors,
+            hits_addend: 1,
+        };
+        let response =
+            match tokio::time::timeout(self.config.timeout(), self.should_rate_limit(request))
+                .await
+                .unwrap_or_else(|_| Err(eyre::eyre!("Rate limit service timed out")))
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    self.warn_failure(&e);
+                    return match self.config.failure_mode() {
+                        FailureMode::Allow => Ok(()),
+                        FailureMode::Deny => Err(Denied::Unavailable),
+                    };
+                }
+            };
+
+        if response.overall_code != proto::Code::OverLimit as i32 {
+            return Ok(());
+        }
+        let retry_after = response
+            .statuses
+            .iter()
+            .filter(|status| status.code == proto::Code::OverLimit as i32)
+            .filter_map(|status| status.duration_until_reset)
+            .map(|reset| Duration::new(reset.seconds.max(0) as u64, reset.nanos.max(0) as u32))
+            .max()
+            .unwrap_or_default();
+        Err(Denied::OverLimit(RateLimited {
+            limit: RateLimit::Global,
+            retry_after,
+        }))
+    }
+
+    /// Warns about a failure to ask the service, at most every
+    /// [`WARN_INTERVAL`] as every request fails during an outage.
+    fn warn_failure(&self, e: &eyre::Report) {
+        let now = Instant::now();
+        let mut warnings = self.warnings.lock().unwrap_or_else(|e| e.into_inner());
+        if warnings
+            .last
+            .is_some_and(|last| now.duration_since(last) < WARN_INTERVAL)
+        {
+            warnings.suppressed += 1;
+            debug!("Failed to ask the rate limit service: {:?}", e);
+            return;
+        }
+        let suppressed = std::mem::take(&mut warnings.suppressed);
+        warnings.last = Some(now);
+        drop(warnings);
+        warn!(
+            "Failed to ask the rate limit service, {} more failures since the last warning: {:?}",
+            suppressed, e
+        );
+    }
+
+    fn descriptors(&self, subject: &Subject<'_>) -> Vec<proto::RateLimitDescriptor> {
+        self.config
+            .descriptors()
+            .iter()
+            .filter_map(|descriptor| {
+                let entries = descriptor
+                    .entries()
+                    .iter()
+                    .map(|entry| {
+                        let value = match entry.source() {
+                            DescriptorSource::ClientIp => Some(subject.client.to_string()),
+                            DescriptorSource::Sni => subject.sni.map(str::to_string),
+                            DescriptorSource::Path => subject.path.map(str::to_string),
+                            DescriptorSource::Header { header } => subject
+                                .headers
+                                .and_then(|headers| headers.get(header))
+                                .and_then(|value| value.to_str().ok())
+                                .map(str::to_string),
+                            DescriptorSource::Value { value } => Some(value.clone()),
+                        }?;
+                        Some(proto::Entry {
+                            key: entry.key().to_string(),
+                            value,
+                        })
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some(proto::RateLimitDescriptor { entries })
+            })
+            .collect()
+    }
+
+    async fn should_rate_limit(
+        &self,
+        request: proto::RateLimitRequest,
+    ) -> Result<proto::RateLimitResponse> {
+        let mut client = tonic::client::Grpc::new(self.channel.clone());
+        client.ready().await?;
+        let response = client
+            .unary(
+                tonic::Request::new(request),
+                PathAndQuery::from_static(proto::SHOULD_RATE_LIMIT),
+                ProstCodec::default(),
+            )
+            .await?;
+        Ok(response.into_inner())
+    }
+}
@@ -1 +1,570 @@
-pub struct HttpProxy {}
+use crate::app::access_log::{AccessLog, Record};
+use crate::app::config::HttpServer;
+use crate::app::metric::ListenerMetrics;
+use crate::app::telemetry;
+use crate::balance::circuit::{Limit, Overflow, Permit};
+use crate::balance::{InFlight, LoadBalancer};
+use crate::proxy::acl::Acl;
+use crate::proxy::global_rate_limit::{Denied, GlobalRateLimiter, Subject};
+use crate::proxy::metered::{Metered, Transferred};
+use crate::proxy::pool::{ConnectionPool, Pooled};
+use crate::proxy::rate_limit::RateLimiter;
+use crate::proxy::route::{Attributes, Routes};
+use crate::proxy::{self, Connected, NoBackend};
+use crate::tls::server::{Server, TlsTerminator};
+use bytes::Bytes;
+use eyre::Result;
+use futures::future::BoxFuture;
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::{Request, Response, StatusCode, Uri, Version};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioIo, TokioTimer};
+use opentelemetry::trace::TraceContextExt;
+use pin_project::pin_project;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tower::Service;
+use tracing::{debug, field, info_span, warn, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+pub type ProxyBody = BoxBody<Bytes, hyper::Error>;
+
+/// Headers that only apply to a single connection, RFC 9110 section 7.6.1,
+/// plus the legacy ones still sent in the wild.
+const HOP_BY_HOP: [HeaderName; 9] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// Proxies HTTP/1.1 requests under the location path, selecting a backend for
+/// every request so that routes can target a subset of the upstream by
+/// request headers.
+pub struct HttpProxy {
+    http_config: Arc<HttpServer>,
+    version: Version,
+    /// Header from `proxy_set_header`, removed when its value is empty.
+    set_header: Option<(HeaderName, HeaderValue)>,
+    tls_server: Option<Arc<Server>>,
+    load_balancer: Arc<LoadBalancer>,
+    routes: Arc<Routes>,
+    metrics: ListenerMetrics,
+    access_log: AccessLog,
+    rate_limiter: Arc<RateLimiter>,
+    global_rate_limiter: Option<Arc<GlobalRateLimiter>>,
+    acl: Option<Arc<Acl>>,
+    pool: Arc<ConnectionPool>,
+}
+
+impl HttpProxy {
+    pub fn new(
+        http_config: Arc<HttpServer>,
+        tls_server: Option<Arc<Server>>,
+        load_balancer: Arc<LoadBalancer>,
+        routes: Routes,
+    ) -> Self {
+        let version = match http_config.proxy_http_version() {
+            "1.0" => Version::HTTP_10,
+            _ => Version::HTTP_11,
+        };
+        let set_header = http_config.proxy_header().and_then(|(name, value)| {
+            Some((
+                HeaderName::try_from(name).ok()?,
+                HeaderValue::try_from(value).ok()?,
+            ))
+        });
+        Self {
+            http_config,
+            version,
+            set_header,
+            tls_server,
+            load_balancer,
+            routes: Arc::new(routes),
+            metrics: ListenerMetrics::default(),
+            access_log: AccessLog::default(),
+            rate_limiter: Arc::default(),
+            global_rate_limiter: None,
+            acl: None,
+            pool: Arc::default(),
+        }
+    }
+
+    pub fn set_metrics(&mut self, metrics: ListenerMetrics) {
+        self.metrics = metrics;
+    }
+
+    pub fn set_access_log(&mut self, access_log: AccessLog) {
+        self.access_log = access_log;
+    }
+
+    pub fn set_rate_limiter(&mut self, rate_limiter: RateLimiter) {
+        self.rate_limiter = Arc::new(rate_limiter);
+    }
+
+    pub fn set_global_rate_limiter(&mut self, global_rate_limiter: GlobalRateLimiter) {
+        self.global_rate_limiter = Some(Arc::new(global_rate_limiter));
+    }
+
+    pub fn set_acl(&mut self, acl: Acl) {
+        self.acl = Some(Arc::new(acl));
+    }
+
+    async fn handle_connection<IO>(
+        &self,
+        client_io: IO,
+        client_addr: SocketAddr,
+        record: &mut Record,
+    ) -> Result<()>
+    where
+        IO: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+    {
+        match &self.tls_server {
+            Some(tls_server) => {
+                record.set_termination("tls_handshake_failed");
+                let handshake_start = Instant::now();
+                let (_, tls_stream) = tls_server
+                    .terminate(client_io)
+                    .instrument(info_span!("tls_handshake"))
+                    .await
+                    .inspect(|_| self.metrics.on_handshake(true))
+                    .inspect_err(|_| self.metrics.on_handshake(false))?;
+                record.set_handshake(handshake_start.elapsed());
+                let attributes = Attributes::from_tls(&tls_stream);
+                record.set_tls(&attributes);
+                record.set_termination("client_error");
+                self.serve(tls_stream, client_addr, attributes).await
+            }
+            None => {
+                record.set_termination("client_error");
+                self.serve(client_io, client_addr, Attributes::default())
+                    .await
+            }
+        }
+    }
+
+    async fn serve<IO>(&self, io: IO, client_addr: SocketAddr, attributes: Attributes) -> Result<()>
+    where
+        IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let this = self.clone();
+        let attributes = Arc::new(attributes);
+        let service = service_fn(move |request: Request<Incoming>| {
+            let this = this.clone();
+            let attributes = Arc::clone(&attributes);
+            async move {
+                let span = request_span(&request);
+                let response = this
+                    .proxy_request(request, client_addr, &attributes)
+                    .instrument(span.clone())
+                    .await;
+                span.record("status", response.status().as_u16());
+                Ok::<_, Infallible>(response)
+            }
+        });
+
+        let keepalive_timeout = self.http_config.keepalive_timeout() as u64;
+        let mut builder = hyper::server::conn::http1::Builder::new();
+        builder
+            .timer(TokioTimer::new())
+            .keep_alive(keepalive_timeout > 0);
+        if keepalive_timeout > 0 {
+            // Also bounds the wait for the next request on an idle connection.
+            builder.header_read_timeout(Duration::from_secs(keepalive_timeout));
+        }
+        builder.serve_connection(TokioIo::new(io), service).await?;
+        Ok(())
+    }
+
+    async fn proxy_request(
+        &self,
+        request: Request<Incoming>,
+        client_addr: SocketAddr,
+        attributes: &Attributes,
+    ) -> Response<ProxyBody> {
+        let mut log = RequestLog {
+            access_log: self.access_log.clone(),
+            record: Record::request(
+                self.http_config.name(),
+                self.http_config.proxy_pass(),
+                client_addr,
+            ),
+            start: Instant::now(),
+            transferred: Arc::default(),
+        };
+        log.record.set_tls(attributes);
+        log.record
+            .set_request(request.method(), request.uri().path());
+
+        if !request
+            .uri()
+            .path()
+            .starts_with(self.http_config.location().path())
+        {
+            debug!("No location for {}", request.uri().path());
+            log.record.set_termination("no_location");
+            log.record.set_status(StatusCode::NOT_FOUND);
+            return error_response(StatusCode::NOT_FOUND);
+        }
+
+        let limited = match (
+            self.rate_limiter.request(client_addr.ip()),
+            &self.global_rate_limiter,
+        ) {
+            (Err(limited), _) => Some(limited),
+            (Ok(()), Some(global_rate_limiter)) => {
+                let subject = Subject {
+                    client: client_addr.ip(),
+                    sni: attributes.sni.as_deref(),
+                    path: Some(request.uri().path()),
+                    headers: Some(request.headers()),
+                };
+                match global_rate_limiter.check(&subject).await {
+                    Ok(()) => None,
+                    Err(Denied::OverLimit(limited)) => Some(limited),
+                    // Like Envoy failing closed, an outage is not a limit.
+                    Err(denied @ Denied::Unavailable) => {
+                        debug!("Rejecting request from {}: {}", client_addr, denied);
+                        log.record.set_termination("rate_limit_unavailable");
+                        log.record.set_status(StatusCode::SERVICE_UNAVAILABLE);
+                        return error_response(StatusCode::SERVICE_UNAVAILABLE);
+                    }
+                }
+            }
+            (Ok(()), None) => None,
+        };
+        if let Some(limited) = limited {
+            debug!("Rejecting request from {}: {}", client_addr, limited);
+            self.metrics.on_rate_limited(limited.limit.as_str());
+            log.record.set_termination("rate_limited");
+            log.record.set_status(StatusCode::TOO_MANY_REQUESTS);
+            return too_many_requests(limited.retry_after);
+        }
+
+        let subset = self.routes.subset(attributes, Some(request.headers()));
+        let affinity_key = client_addr.ip().to_string();
+        let upstream = proxy::checkout(
+            &self.load_balancer,
+            &affinity_key,
+            subset,
+            &[Limit::Requests],
+            |backend| async move {
+                let acquire =
+                    || proxy::acquire(&self.load_balancer, Limit::Connections, Some(&backend));
+                self.pool.checkout(backend.addr, acquire).await
+            },
+        )
+        .await;
+        let Connected {
+            io: mut connection,
+            in_flight,
+            permits,
+        } = match upstream {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                log.record.set_connect_error(&e);
+                let status = if e.is::<Overflow>() || e.is::<NoBackend>() {
+                    debug!("Rejecting request: {}", e);
+                    StatusCode::SERVICE_UNAVAILABLE
+                } else {
+                    warn!("Failed to connect to backend: {:?}", e);
+                    StatusCode::BAD_GATEWAY
+                };
+                log.record.set_status(status);
+                return error_response(status);
+            }
+        };
+        log.record.set_connect(log.start.elapsed());
+        log.record.set_backend(in_flight.backend().addr);
+        debug!("Connected to backend: {:?}", in_flight.backend());
+
+        let transferred = Arc::clone(&log.transferred);
+        let mut request = request.map(|body| {
+            body.map_frame(move |frame| {
+                if let Some(data) = frame.data_ref() {
+                    transferred.add_received(data.len() as u64);
+                }
+                frame
+            })
+            .boxed()
+        });
+        to_origin_form(&mut request);
+        *request.version_mut() = self.version;
+        remove_hop_by_hop(request.headers_mut());
+        append_forwarded_for(request.headers_mut(), client_addr.ip());
+        if let Some((name, value)) = &self.set_header {
+            if value.is_empty() {
+                request.headers_mut().remove(name);
+            } else {
+                request.headers_mut().insert(name, value.clone());
+            }
+        }
+        telemetry::inject(&Span::current().context(), request.headers_mut());
+        let send_start = Instant::now();
+        match connection
+            .send(request)
+            .instrument(info_span!("proxy"))
+            .await
+        {
+            Ok(response) => {
+                // Time to the response headers, whether the connection is
+                // new or pooled.
+                in_flight.observe_latency(send_start.elapsed());
+                log.record.set_status(response.status());
+                log.record.set_termination("completed");
+                let (mut parts, body) = response.into_parts();
+                remove_hop_by_hop(&mut parts.headers);
+                let body = UpstreamBody {
+                    inner: body,
+                    log,
+                    _connection: connection,
+                    _in_flight: in_flight,
+                    _permits: permits,
+                };
+                Response::from_parts(parts, body.boxed())
+            }
+            Err(e) => {
+                in_flight.observe_failure();
+                warn!(
+                    "Failed to proxy request to {}: {:?}",
+                    in_flight.backend().addr,
+                    e
+                );
+                log.record.set_termination("upstream_error");
+                log.record.set_status(StatusCode::BAD_GATEWAY);
+                error_response(StatusCode::BAD_GATEWAY)
+            }
+        }
+    }
+
+    pub fn acl(&self) -> Option<Arc<Acl>> {
+        self.acl.clone()
+    }
+
+    pub fn metrics(&self) -> ListenerMetrics {
+        self.metrics.clone()
+    }
+
+    pub fn load_balancer(&self) -> Arc<LoadBalancer> {
+        Arc::clone(&self.load_balancer)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.http_config.listen().port()
+    }
+}
+
+/// Span of one request, continuing the trace of the client when the request
+/// carries a `traceparent` header.
+fn request_span<B>(request: &Request<B>) -> Span {
+    let span = info_span!(
+        "request",
+        method = %request.method(),
+        path = request.uri().path(),
+        status = field::Empty,
+    );
+    let parent = telemetry::extract(request.headers());
+    if parent.span().span_context().is_valid() {
+        span.set_parent(parent);
+    }
+    span
+}
+
+/// Access log record of one request, written when dropped together with the
+/// response body.
+struct RequestLog {
+    access_log: AccessLog,
+    record: Record,
+    start: Instant,
+    transferred: Arc<Transferred>,
+}
+
+impl Drop for RequestLog {
+    fn drop(&mut self) {
+        let mut record = self.record.clone();
+        record.set_transferred(&self.transferred);
+        record.set_duration(self.start.elapsed());
+        self.access_log.log(record);
+    }
+}
+
+/// Response body of a backend. The backend stays tracked, the permits held,
+/// the connection out of the pool and the request unlogged until it is done.
+#[pin_project]
+struct UpstreamBody {
+    #[pin]
+    inner: Incoming,
+    log: RequestLog,
+    _connection: Pooled,
+    _in_flight: InFlight,
+    _permits: Vec<Permit>,
+}
+
+impl Body for UpstreamBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        let this = self.project();
+        let frame = ready!(this.inner.poll_frame(cx));
+        if let Some(data) = frame.as_ref().and_then(|f| f.as_ref().ok()?.data_ref()) {
+            this.log.transferred.add_sent(data.len() as u64);
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Rewrites the URI of `request` in origin form, as sent to a backend.
+fn to_origin_form<B>(request: &mut Request<B>) {
+    if let Some(path_and_query) = request.uri().path_and_query() {
+        *request.uri_mut() = Uri::from(path_and_query.clone());
+    }
+}
+
+/// Removes the headers of the previous hop, including those the `Connection`
+/// header names.
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::try_from(name.trim()).ok())
+        .collect();
+    for name in listed.iter().chain(HOP_BY_HOP.iter()) {
+        headers.remove(name);
+    }
+}
+
+/// Appends `client` to the `X-Forwarded-For` chain of the request.
+fn append_forwarded_for(headers: &mut HeaderMap, client: IpAddr) {
+    let forwarded_for = match headers.get(X_FORWARDED_FOR).map(HeaderValue::to_str) {
+        Some(Ok(previous)) => format!("{}, {}", previous, client),
+        _ => client.to_string(),
+    };
+    if let Ok(value) = HeaderValue::try_from(forwarded_for) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+}
+
+/// A 429 telling the client to retry after `retry_after`, in whole seconds
+/// rounded up so that the retry is not rejected again.
+fn too_many_requests(retry_after: Duration) -> Response<ProxyBody> {
+    let mut response = error_response(StatusCode::TOO_MANY_REQUESTS);
+    let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    response
+        .headers_mut()
+        .insert(http::header::RETRY_AFTER, retry_after.into());
+    response
+}
+
+pub fn error_response(status: StatusCode) -> Response<ProxyBody> {
+    let mut response = Response::new(
+        Empty::<Bytes>::new()
+            .map_err(|never| match never {})
+            .boxed(),
+    );
+    *response.status_mut() = status;
+    response
+}
+
+impl Service<TcpStream> for HttpProxy {
+    type Response = ();
+    type Error = eyre::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: TcpStream) -> Self::Future {
+        let this = self.clone();
+        Box::pin(async move {
+            let start = Instant::now();
+            let client_addr = req.peer_addr()?;
+            let mut record = Record::connection(
+                this.http_config.name(),
+                this.http_config.proxy_pass(),
+                client_addr,
+            );
+            let _permit = match this.rate_limiter.accept(client_addr.ip()) {
+                Ok(permit) => permit,
+                Err(limited) => {
+                    debug!("Closing connection from {}: {}", client_addr, limited);
+                    this.metrics.on_rate_limited(limited.limit.as_str());
+                    record.set_termination("rate_limited");
+                    record.set_duration(start.elapsed());
+                    this.access_log.log(record);
+                    return Ok(());
+                }
+            };
+            this.metrics.on_accept();
+            let client_io = Metered::new(req, &this.metrics);
+            let transferred = client_io.transferred();
+            let span = info_span!(
+                "connection",
+                listener = this.http_config.name(),
+                client_addr = %client_addr,
+            );
+            let result = this
+                .handle_connection(client_io, client_addr, &mut record)
+                .instrument(span)
+                .await;
+            this.metrics.on_close();
+
+            if result.is_ok() {
+                record.set_termination("client_closed");
+            }
+            record.set_transferred(&transferred);
+            record.set_duration(start.elapsed());
+            this.access_log.log(record);
+            result
+        })
+    }
+}
+
+impl Clone for HttpProxy {
+    fn clone(&self) -> Self {
+        Self {
+            http_config: Arc::clone(&self.http_config),
+            version: self.version,
+            set_header: self.set_header.clone(),
+            tls_server: self.tls_server.clone(),
+            load_balancer: Arc::clone(&self.load_balancer),
+            routes: Arc::clone(&self.routes),
+            metrics: self.metrics.clone(),
+            rate_limiter: Arc::clone(&self.rate_limiter),
+            global_rate_limiter: self.global_rate_limiter.clone(),
+            acl: self.acl.clone(),
+            access_log: self.access_log.clone(),
+            pool: Arc::clone(&self.pool),
+        }
+    }
+}
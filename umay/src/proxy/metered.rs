@@ -0,0 +1,120 @@
+use crate::app::metric::ListenerMetrics;
+use pin_project::pin_project;
+use prometheus_client::metrics::counter::Counter;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Bytes received from and sent to one client, by a connection or a request.
+#[derive(Debug, Default)]
+pub struct Transferred {
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl Transferred {
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn add_received(&self, bytes: u64) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_sent(&self, bytes: u64) {
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Counts the bytes read from and written to a client connection, both in
+/// the listener metrics and for the connection itself.
+#[pin_project]
+pub struct Metered<T> {
+    #[pin]
+    inner: T,
+    received: Counter,
+    sent: Counter,
+    transferred: Arc<Transferred>,
+}
+
+impl<T> Metered<T> {
+    pub fn new(inner: T, metrics: &ListenerMetrics) -> Self {
+        Self {
+            inner,
+            received: metrics.received_bytes(),
+            sent: metrics.sent_bytes(),
+            transferred: Arc::default(),
+        }
+    }
+
+    pub fn transferred(&self) -> Arc<Transferred> {
+        Arc::clone(&self.transferred)
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Metered<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        let poll = this.inner.poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let received = (buf.filled().len() - before) as u64;
+            this.received.inc_by(received);
+            this.transferred.add_received(received);
+        }
+        poll
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for Metered<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let poll = this.inner.poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            this.sent.inc_by(written as u64);
+            this.transferred.add_sent(written as u64);
+        }
+        poll
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let poll = this.inner.poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(written)) = poll {
+            this.sent.inc_by(written as u64);
+            this.transferred.add_sent(written as u64);
+        }
+        poll
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
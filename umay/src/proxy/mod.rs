@@ -1,2 +1,155 @@
+use crate::balance::circuit::{Limit, Overflow, Permit};
+use crate::balance::subset::Subset;
+use crate::balance::{Backend, InFlight, LoadBalancer};
+use futures::TryFutureExt;
+use std::fmt;
+use std::future::Future;
+use std::time::Instant;
+use tracing::{info_span, warn, Instrument};
+
+pub mod acl;
+pub mod global_rate_limit;
 pub mod http;
+pub mod metered;
+pub mod pool;
+pub mod rate_limit;
+pub mod route;
 pub mod stream;
+
+/// Returned when the upstream has no backend to select.
+#[derive(Debug)]
+pub struct NoBackend;
+
+impl fmt::Display for NoBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No backends available")
+    }
+}
+
+impl std::error::Error for NoBackend {}
+
+/// A connection to a backend, tracked by the load balancer and holding its
+/// circuit breaker permits until dropped.
+pub struct Connected<T> {
+    pub io: T,
+    pub in_flight: InFlight,
+    pub permits: Vec<Permit>,
+}
+
+/// How a connection to the selected backend was obtained.
+pub enum Connection<T> {
+    /// Newly established, its connect duration is measured.
+    New(T),
+    /// Reused from a pool.
+    Reused(T),
+}
+
+/// Selects a backend of `subset` and connects to it with `connect`, taking
+/// a permit of each of `limits` for the time the connection is held. After
+/// a failed connect another backend is tried while the upstream retries and
+/// their circuit breaker allow it. The connect latency feeds the selection
+/// and health of the backend.
+pub async fn connect<T, F, Fut>(
+    load_balancer: &LoadBalancer,
+    key: &str,
+    subset: &Subset,
+    limits: &[Limit],
+    connect: F,
+) -> eyre::Result<Connected<T>>
+where
+    F: Fn(Backend) -> Fut,
+    Fut: Future<Output = eyre::Result<T>>,
+{
+    let Connected {
+        io: (io, latency),
+        in_flight,
+        permits,
+    } = checkout(load_balancer, key, subset, limits, |backend| {
+        let start = Instant::now();
+        connect(backend).map_ok(move |io| Connection::New((io, start.elapsed())))
+    })
+    .await?;
+    in_flight.observe_latency(latency);
+    Ok(Connected {
+        io,
+        in_flight,
+        permits,
+    })
+}
+
+/// Like [`connect`], with `checkout` reusing pooled connections when it can.
+/// Only failed checkouts are reported to the backend, the caller observes
+/// the outcome of what it does with the connection.
+pub async fn checkout<T, F, Fut>(
+    load_balancer: &LoadBalancer,
+    key: &str,
+    subset: &Subset,
+    limits: &[Limit],
+    checkout: F,
+) -> eyre::Result<Connected<T>>
+where
+    F: Fn(Backend) -> Fut,
+    Fut: Future<Output = eyre::Result<Connection<T>>>,
+{
+    let mut attempt = 0;
+    let mut _retry: Option<Permit> = None;
+    loop {
+        let in_flight = load_balancer
+            .acquire(Some(key), subset)
+            .instrument(info_span!("select_backend", attempt))
+            .await
+            .ok_or(NoBackend)?;
+        let backend = in_flight.backend().clone();
+        let permits = limits
+            .iter()
+            .map(|limit| acquire(load_balancer, *limit, Some(&backend)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let pending = acquire(load_balancer, Limit::Pending, Some(&backend))?;
+
+        let connect_start = Instant::now();
+        let span = info_span!("connect", backend = %backend.addr, attempt);
+        match checkout(backend.clone()).instrument(span).await {
+            Ok(connection) => {
+                drop(pending);
+                let io = match connection {
+                    Connection::New(io) => {
+                        load_balancer
+                            .metrics()
+                            .observe_connect(backend.addr, connect_start.elapsed());
+                        io
+                    }
+                    Connection::Reused(io) => io,
+                };
+                return Ok(Connected {
+                    io,
+                    in_flight,
+                    permits,
+                });
+            }
+            // A limit reached by the checkout is not the backend failing.
+            Err(e) if e.is::<Overflow>() => return Err(e),
+            Err(e) => {
+                in_flight.observe_failure();
+                if attempt >= load_balancer.retries() {
+                    return Err(e);
+                }
+                attempt += 1;
+                warn!("Failed to connect to {}, retrying: {:?}", backend.addr, e);
+                _retry = Some(acquire(load_balancer, Limit::Retries, None)?);
+            }
+        }
+    }
+}
+
+/// Takes one unit of `limit` from the circuit breaker of the upstream,
+/// counting the overflows.
+pub fn acquire(
+    load_balancer: &LoadBalancer,
+    limit: Limit,
+    backend: Option<&Backend>,
+) -> Result<Permit, Overflow> {
+    load_balancer
+        .circuit_breaker()
+        .try_acquire(limit, backend)
+        .inspect_err(|overflow| load_balancer.metrics().on_overflow(overflow.0.as_str()))
+}
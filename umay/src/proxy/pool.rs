@@ -0,0 +1,194 @@
+use crate::balance::circuit::{Overflow, Permit};
+use crate::proxy::http::ProxyBody;
+use crate::proxy::Connection;
+use eyre::Result;
+use http::{Request, Response};
+use hyper::body::Incoming;
+use hyper::client::conn::http1::{self, SendRequest};
+use hyper_util::rt::TokioIo;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+/// How long a connection stays idle in the pool before it is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Idle connections kept per backend, more are closed once released.
+const MAX_IDLE_PER_BACKEND: usize = 32;
+
+/// Time allowed to a released connection to be ready for another request,
+/// as hyper only allows one once the previous response was read.
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct Idle {
+    sender: SendRequest<ProxyBody>,
+    permit: Permit,
+    since: Instant,
+}
+
+/// Idle HTTP/1.1 connections to the backends of a listener, reused by the
+/// next requests to the same backend instead of connecting for each one.
+/// Every connection holds its connection permit until closed, idle or not.
+#[derive(Default)]
+pub struct ConnectionPool {
+    idle: Mutex<HashMap<SocketAddr, Vec<Idle>>>,
+    sweeping: AtomicBool,
+}
+
+impl ConnectionPool {
+    /// Takes an idle connection to `addr`, or opens a new one once `acquire`
+    /// grants it a connection permit.
+    pub async fn checkout(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        acquire: impl FnOnce() -> Result<Permit, Overflow>,
+    ) -> Result<Connection<Pooled>> {
+        if let Some((sender, permit)) = self.take(addr) {
+            debug!("Reusing connection to {}", addr);
+            return Ok(Connection::Reused(Pooled {
+                sender: Some(sender),
+                permit: Some(permit),
+                addr,
+                reused: true,
+                pool: Arc::clone(self),
+            }));
+        }
+        let permit = acquire()?;
+        Ok(Connection::New(Pooled {
+            sender: Some(handshake(addr).await?),
+            permit: Some(permit),
+            addr,
+            reused: false,
+            pool: Arc::clone(self),
+        }))
+    }
+
+    /// Idle connections currently pooled for `addr`.
+    pub fn idle(&self, addr: SocketAddr) -> usize {
+        let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        idle.get(&addr).map_or(0, Vec::len)
+    }
+
+    fn take(&self, addr: SocketAddr) -> Option<(SendRequest<ProxyBody>, Permit)> {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        let connections = idle.get_mut(&addr)?;
+        let mut sender = None;
+        while let Some(connection) = connections.pop() {
+            if connection.since.elapsed() < IDLE_TIMEOUT && connection.sender.is_ready() {
+                sender = Some((connection.sender, connection.permit));
+                break;
+            }
+        }
+        if connections.is_empty() {
+            idle.remove(&addr);
+        }
+        sender
+    }
+
+    fn put(self: &Arc<Self>, addr: SocketAddr, sender: SendRequest<ProxyBody>, permit: Permit) {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        let connections = idle.entry(addr).or_default();
+        if connections.len() < MAX_IDLE_PER_BACKEND {
+            connections.push(Idle {
+                sender,
+                permit,
+                since: Instant::now(),
+            });
+        }
+        drop(idle);
+        self.start_sweeping();
+    }
+
+    /// Closes connections idle for too long, also those to backends that
+    /// are gone and will not be asked for again.
+    fn start_sweeping(self: &Arc<Self>) {
+        if self.sweeping.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let pool = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(IDLE_TIMEOUT / 2);
+            loop {
+                interval.tick().await;
+                let Some(pool) = pool.upgrade() else {
+                    return;
+                };
+                let mut idle = pool.idle.lock().unwrap_or_else(|e| e.into_inner());
+                idle.retain(|_, connections| {
+                    connections
+                        .retain(|c| c.since.elapsed() < IDLE_TIMEOUT && !c.sender.is_closed());
+                    !connections.is_empty()
+                });
+            }
+        });
+    }
+}
+
+async fn handshake(addr: SocketAddr) -> Result<SendRequest<ProxyBody>> {
+    let tcp = TcpStream::connect(addr).await?;
+    if let Err(e) = tcp.set_nodelay(true) {
+        debug!("Failed to set nodelay on connection to {}: {}", addr, e);
+    }
+    let (sender, connection) = http1::handshake(TokioIo::new(tcp)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Upstream connection closed: {:?}", e);
+        }
+    });
+    Ok(sender)
+}
+
+/// A connection taken from the pool, given back once dropped if it can take
+/// another request by then.
+pub struct Pooled {
+    sender: Option<SendRequest<ProxyBody>>,
+    permit: Option<Permit>,
+    addr: SocketAddr,
+    reused: bool,
+    pool: Arc<ConnectionPool>,
+}
+
+impl Pooled {
+    /// Sends `request`, on a new connection when the backend closed the
+    /// reused one before it could be sent.
+    pub async fn send(&mut self, request: Request<ProxyBody>) -> Result<Response<Incoming>> {
+        let Some(sender) = self.sender.as_mut() else {
+            eyre::bail!("Connection to {} already released", self.addr);
+        };
+        match sender.try_send_request(request).await {
+            Ok(response) => Ok(response),
+            Err(mut e) => match e.take_message() {
+                Some(request) if self.reused => {
+                    debug!("Pooled connection to {} closed, reconnecting", self.addr);
+                    self.reused = false;
+                    let sender = self.sender.insert(handshake(self.addr).await?);
+                    Ok(sender.send_request(request).await?)
+                }
+                _ => Err(e.into_error().into()),
+            },
+        }
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        let (Some(mut sender), Some(permit)) = (self.sender.take(), self.permit.take()) else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let (pool, addr) = (Arc::clone(&self.pool), self.addr);
+        // A connection whose response was not read to the end is closed by
+        // hyper and never gets ready again.
+        runtime.spawn(async move {
+            if let Ok(Ok(())) = tokio::time::timeout(RELEASE_TIMEOUT, sender.ready()).await {
+                pool.put(addr, sender, permit);
+            }
+        });
+    }
+}
@@ -0,0 +1,332 @@
+use crate::app::config::{RateConfig, RateLimitConfig};
+use ipnet::IpNet;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Limit a client went over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimit {
+    /// New connections per second.
+    ConnectionRate,
+    /// Connections open at once.
+    Connections,
+    /// HTTP requests per second.
+    RequestRate,
+    /// Clients tracked at once, all of them having connections.
+    Clients,
+    /// Limits of the rate limit service shared by all instances.
+    Global,
+}
+
+impl RateLimit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RateLimit::ConnectionRate => "connection_rate",
+            RateLimit::Connections => "connections",
+            RateLimit::RequestRate => "request_rate",
+            RateLimit::Clients => "clients",
+            RateLimit::Global => "global",
+        }
+    }
+}
+
+/// Returned when a client goes over a limit, with the time until it would
+/// be allowed again.
+#[derive(Debug)]
+pub struct RateLimited {
+    pub limit: RateLimit,
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Rate limited on {}", self.limit.as_str())
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+#[derive(Clone, Copy, Debug)]
+struct Rate {
+    per_second: f64,
+    burst: f64,
+}
+
+impl From<&RateConfig> for Rate {
+    fn from(config: &RateConfig) -> Self {
+        Self {
+            per_second: config.per_second(),
+            burst: config.burst() as f64,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: &Rate, now: Instant) -> Self {
+        Self {
+            tokens: rate.burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: &Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_second).min(rate.burst);
+        self.updated = now;
+    }
+
+    /// Takes a token, or tells how long until one is available.
+    fn try_take(&mut self, rate: &Rate, now: Instant) -> Result<(), Duration> {
+        self.refill(rate, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            // Tiny rates overflow a duration, waiting forever then.
+            Err(
+                Duration::try_from_secs_f64((1.0 - self.tokens) / rate.per_second)
+                    .unwrap_or(Duration::MAX),
+            )
+        }
+    }
+
+    fn is_full(&mut self, rate: &Rate, now: Instant) -> bool {
+        self.refill(rate, now);
+        self.tokens >= rate.burst
+    }
+}
+
+/// Clients looked at for eviction, starting from the one seen longest ago.
+const EVICTION_CANDIDATES: usize = 16;
+
+#[derive(Debug)]
+struct Client {
+    connections: Option<TokenBucket>,
+    requests: Option<TokenBucket>,
+    active: usize,
+    last_seen: Instant,
+}
+
+/// Clients by network, also ordered by when they were last seen so that
+/// eviction only looks at the oldest.
+#[derive(Debug, Default)]
+struct Clients {
+    by_network: HashMap<IpNet, Client>,
+    by_last_seen: BTreeSet<(Instant, IpNet)>,
+}
+
+/// Token buckets and open connections of each client network. Clients whose
+/// buckets are full and connections closed hold no state worth keeping, and
+/// are forgotten first once `max_clients` are tracked. Clients with open
+/// connections are never forgotten, as their connections would no longer
+/// count, so new clients are rejected when there is no room for them.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    connection_rate: Option<Rate>,
+    max_connections: usize,
+    request_rate: Option<Rate>,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    max_clients: usize,
+    clients: Mutex<Clients>,
+}
+
+impl RateLimiter {
+    /// Limits clients as configured, with `request_rate` replacing the
+    /// request rate of `config`.
+    pub fn new(config: &RateLimitConfig, request_rate: Option<&RateConfig>) -> Self {
+        Self {
+            connection_rate: config.connection_rate().map(Rate::from),
+            max_connections: config.max_connections(),
+            request_rate: request_rate.map(Rate::from),
+            ipv4_prefix: config.ipv4_prefix(),
+            ipv6_prefix: config.ipv6_prefix(),
+            max_clients: config.max_clients(),
+            clients: Mutex::default(),
+        }
+    }
+
+    /// Admits a new connection of `client`, counted as open until the
+    /// returned permit is dropped.
+    pub fn accept(self: &Arc<Self>, client: IpAddr) -> Result<ConnectionPermit, RateLimited> {
+        if self.connection_rate.is_none() && self.max_connections == 0 {
+            return Ok(ConnectionPermit {
+                limiter: Arc::clone(self),
+                network: None,
+            });
+        }
+        let network = self.network(client);
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        let state = self.client(&mut clients, network, now)?;
+        if self.max_connections > 0 && state.active >= self.max_connections {
+            return Err(RateLimited {
+                limit: RateLimit::Connections,
+                retry_after: Duration::ZERO,
+            });
+        }
+        if let Some(rate) = &self.connection_rate {
+            state
+                .connections
+                .get_or_insert_with(|| TokenBucket::new(rate, now))
+                .try_take(rate, now)
+                .map_err(|retry_after| RateLimited {
+                    limit: RateLimit::ConnectionRate,
+                    retry_after,
+                })?;
+        }
+        state.active += 1;
+        Ok(ConnectionPermit {
+            limiter: Arc::clone(self),
+            network: Some(network),
+        })
+    }
+
+    /// Admits an HTTP request of `client`.
+    pub fn request(&self, client: IpAddr) -> Result<(), RateLimited> {
+        let Some(rate) = &self.request_rate else {
+            return Ok(());
+        };
+        let network = self.network(client);
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        self.client(&mut clients, network, now)?
+            .requests
+            .get_or_insert_with(|| TokenBucket::new(rate, now))
+            .try_take(rate, now)
+            .map_err(|retry_after| RateLimited {
+                limit: RateLimit::RequestRate,
+                retry_after,
+            })
+    }
+
+    /// Clients currently tracked.
+    pub fn clients(&self) -> usize {
+        self.clients
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .by_network
+            .len()
+    }
+
+    fn network(&self, client: IpAddr) -> IpNet {
+        let prefix = match client {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        };
+        IpNet::new(client, prefix)
+            .map(|network| network.trunc())
+            .unwrap_or_else(|_| IpNet::from(client))
+    }
+
+    /// State of the client `network`, tracked from now on when there is room
+    /// for it.
+    fn client<'a>(
+        &self,
+        clients: &'a mut Clients,
+        network: IpNet,
+        now: Instant,
+    ) -> Result<&'a mut Client, RateLimited> {
+        if !clients.by_network.contains_key(&network)
+            && clients.by_network.len() >= self.max_clients
+            && !self.evict(clients, now)
+        {
+            return Err(RateLimited {
+                limit: RateLimit::Clients,
+                retry_after: Duration::ZERO,
+            });
+        }
+        let client = clients.by_network.entry(network).or_insert_with(|| Client {
+            connections: None,
+            requests: None,
+            active: 0,
+            last_seen: now,
+        });
+        clients.by_last_seen.remove(&(client.last_seen, network));
+        clients.by_last_seen.insert((now, network));
+        client.last_seen = now;
+        Ok(client)
+    }
+
+    /// Makes room for a new client among the few seen longest ago, forgetting
+    /// the first idle one or else the first without connections. Those with
+    /// connections are counted as seen now, so that long lived connections
+    /// do not keep the next evictions from looking further.
+    fn evict(&self, clients: &mut Clients, now: Instant) -> bool {
+        let candidates: Vec<(Instant, IpNet)> = clients
+            .by_last_seen
+            .iter()
+            .take(EVICTION_CANDIDATES)
+            .copied()
+            .collect();
+        let mut unconnected = None;
+        let mut evicted = None;
+        for candidate in &candidates {
+            let Some(client) = clients.by_network.get_mut(&candidate.1) else {
+                continue;
+            };
+            if client.active > 0 {
+                client.last_seen = now;
+                clients.by_last_seen.remove(candidate);
+                clients.by_last_seen.insert((now, candidate.1));
+                continue;
+            }
+            if self.is_idle(client, now) {
+                evicted = Some(*candidate);
+                break;
+            }
+            unconnected.get_or_insert(*candidate);
+        }
+        let Some(candidate) = evicted.or(unconnected) else {
+            return false;
+        };
+        clients.by_last_seen.remove(&candidate);
+        clients.by_network.remove(&candidate.1);
+        true
+    }
+
+    /// Whether `client` has no connections and full buckets.
+    fn is_idle(&self, client: &mut Client, now: Instant) -> bool {
+        client.active == 0
+            && client
+                .connections
+                .as_mut()
+                .zip(self.connection_rate.as_ref())
+                .is_none_or(|(bucket, rate)| bucket.is_full(rate, now))
+            && client
+                .requests
+                .as_mut()
+                .zip(self.request_rate.as_ref())
+                .is_none_or(|(bucket, rate)| bucket.is_full(rate, now))
+    }
+
+    fn release(&self, network: IpNet) {
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(client) = clients.by_network.get_mut(&network) {
+            client.active = client.active.saturating_sub(1);
+        }
+    }
+}
+
+/// An open connection of a client, given back when dropped.
+pub struct ConnectionPermit {
+    limiter: Arc<RateLimiter>,
+    network: Option<IpNet>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some(network) = self.network {
+            self.limiter.release(network);
+        }
+    }
+}
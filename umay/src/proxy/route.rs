@@ -0,0 +1,126 @@
+use crate::app::config::RouteConfig;
+use crate::balance::subset::Subset;
+use crate::tls;
+use eyre::Context;
+use http::{HeaderMap, HeaderName, HeaderValue};
+use tokio_rustls::server::TlsStream;
+
+/// What is known about a client connection once its TLS handshake is done.
+#[derive(Clone, Debug, Default)]
+pub struct Attributes {
+    pub sni: Option<String>,
+    pub alpn: Option<Vec<u8>>,
+    pub client_names: Vec<String>,
+}
+
+impl Attributes {
+    pub fn from_tls<I>(tls_stream: &TlsStream<I>) -> Self {
+        let (_io, session) = tls_stream.get_ref();
+        Self {
+            sni: session.server_name().map(str::to_string),
+            alpn: session.alpn_protocol().map(<[u8]>::to_vec),
+            client_names: tls::client_identity(tls_stream)
+                .map(|id| id.dns_names())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Sends connections or requests to a subset of the upstream backends.
+///
+/// The first route whose conditions all hold picks the subset, a route
+/// without conditions matches everything. Header conditions only hold for
+/// HTTP requests.
+#[derive(Debug, Default)]
+pub struct Routes {
+    routes: Vec<Route>,
+    all: Subset,
+}
+
+#[derive(Debug)]
+struct Route {
+    sni: Option<String>,
+    alpn: Option<Vec<u8>>,
+    client_id: Option<String>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    subset: Subset,
+}
+
+impl Routes {
+    pub fn new(routes: &[RouteConfig]) -> eyre::Result<Self> {
+        let routes = routes
+            .iter()
+            .map(|route| {
+                let conditions = route.conditions();
+                let headers = conditions
+                    .headers()
+                    .iter()
+                    .map(|(name, value)| {
+                        Ok((
+                            HeaderName::try_from(name.as_str())?,
+                            HeaderValue::try_from(value.as_str())?,
+                        ))
+                    })
+                    .collect::<eyre::Result<Vec<_>>>()
+                    .wrap_err("Invalid route header")?;
+                Ok(Route {
+                    sni: conditions.sni().map(str::to_ascii_lowercase),
+                    alpn: conditions.alpn().map(|alpn| alpn.as_bytes().to_vec()),
+                    client_id: conditions.client_id().map(str::to_string),
+                    headers,
+                    subset: route.subset().clone(),
+                })
+            })
+            .collect::<eyre::Result<_>>()?;
+
+        Ok(Self {
+            routes,
+            all: Subset::new(),
+        })
+    }
+
+    /// The subset targeted by a connection, or by one of its requests when
+    /// `headers` are given. Empty when no route matches.
+    pub fn subset(&self, attributes: &Attributes, headers: Option<&HeaderMap>) -> &Subset {
+        self.routes
+            .iter()
+            .find(|route| route.matches(attributes, headers))
+            .map_or(&self.all, |route| &route.subset)
+    }
+}
+
+impl Route {
+    fn matches(&self, attributes: &Attributes, headers: Option<&HeaderMap>) -> bool {
+        let sni = self.sni.as_ref().is_none_or(|pattern| {
+            attributes
+                .sni
+                .as_ref()
+                .is_some_and(|sni| matches_host(pattern, &sni.to_ascii_lowercase()))
+        });
+        let alpn = self
+            .alpn
+            .as_ref()
+            .is_none_or(|alpn| attributes.alpn.as_ref() == Some(alpn));
+        let client_id = self
+            .client_id
+            .as_ref()
+            .is_none_or(|id| attributes.client_names.contains(id));
+        let headers = self.headers.is_empty()
+            || headers.is_some_and(|headers| {
+                self.headers
+                    .iter()
+                    .all(|(name, value)| headers.get_all(name).iter().any(|v| v == value))
+            });
+
+        sni && alpn && client_id && headers
+    }
+}
+
+/// Matches `host` against `pattern`, either a name or a `*.` wildcard
+/// covering one or more leading labels.
+fn matches_host(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix('*') {
+        Some(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+        None => pattern == host,
+    }
+}
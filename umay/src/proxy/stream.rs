@@ -1,24 +1,41 @@
+use crate::app::access_log::{AccessLog, Record};
 use crate::app::config::{Protocol, StreamServer};
+use crate::app::metric::ListenerMetrics;
+use crate::balance::circuit::Limit;
 use crate::balance::LoadBalancer;
+use crate::proxy;
+use crate::proxy::acl::Acl;
+use crate::proxy::global_rate_limit::{Denied, GlobalRateLimiter, Subject};
+use crate::proxy::metered::Metered;
+use crate::proxy::rate_limit::RateLimiter;
+use crate::proxy::route::{Attributes, Routes};
 use crate::tls::server::{Server, TlsTerminator};
 use crate::tls::ServerTls;
 use eyre::Result;
 use futures::future::BoxFuture;
 use futures::SinkExt;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::task::Poll;
+use std::time::Instant;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio_rustls::server::TlsStream;
 use tokio_stream::StreamExt;
 use tokio_tungstenite::{accept_async, connect_async, MaybeTlsStream, WebSocketStream};
 use tower::Service;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, info_span, Instrument};
 
 pub struct StreamProxy {
     stream_config: Arc<StreamServer>,
     tls_server: Arc<Server>,
     load_balancer: Arc<LoadBalancer>,
+    routes: Arc<Routes>,
+    metrics: ListenerMetrics,
+    access_log: AccessLog,
+    rate_limiter: Arc<RateLimiter>,
+    global_rate_limiter: Option<Arc<GlobalRateLimiter>>,
+    acl: Option<Arc<Acl>>,
 }
 
 impl StreamProxy {
@@ -26,20 +43,61 @@ impl StreamProxy {
         stream_config: Arc<StreamServer>,
         tls_server: Arc<Server>,
         load_balancer: Arc<LoadBalancer>,
+        routes: Routes,
     ) -> Self {
         Self {
             stream_config,
             tls_server,
             load_balancer,
+            routes: Arc::new(routes),
+            metrics: ListenerMetrics::default(),
+            access_log: AccessLog::default(),
+            rate_limiter: Arc::default(),
+            global_rate_limiter: None,
+            acl: None,
         }
     }
 
+    pub fn set_metrics(&mut self, metrics: ListenerMetrics) {
+        self.metrics = metrics;
+    }
+
+    pub fn set_access_log(&mut self, access_log: AccessLog) {
+        self.access_log = access_log;
+    }
+
+    pub fn set_rate_limiter(&mut self, rate_limiter: RateLimiter) {
+        self.rate_limiter = Arc::new(rate_limiter);
+    }
+
+    pub fn set_global_rate_limiter(&mut self, global_rate_limiter: GlobalRateLimiter) {
+        self.global_rate_limiter = Some(Arc::new(global_rate_limiter));
+    }
+
+    pub fn set_acl(&mut self, acl: Acl) {
+        self.acl = Some(Arc::new(acl));
+    }
+
     //TODO : make this function as tower Service and implement the call method
-    async fn handle_connection<IO>(&self, client_io: IO) -> Result<()>
+    async fn handle_connection<IO>(
+        &self,
+        client_io: IO,
+        client_addr: SocketAddr,
+        record: &mut Record,
+    ) -> Result<()>
     where
         IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Sync + Unpin + 'static,
     {
-        let (server_tls, tls_stream) = self.tls_server.terminate(client_io).await?;
+        record.set_termination("tls_handshake_failed");
+        let handshake_start = Instant::now();
+        let (server_tls, tls_stream) = self
+            .tls_server
+            .terminate(client_io)
+            .instrument(info_span!("tls_handshake"))
+            .await
+            .inspect(|_| self.metrics.on_handshake(true))
+            .inspect_err(|_| self.metrics.on_handshake(false))?;
+        record.set_handshake(handshake_start.elapsed());
 
         match server_tls {
             ServerTls::Established {
@@ -57,37 +115,93 @@ impl StreamProxy {
         }
 
         //TODO: make this section tower layer and implement the call method
-        match self.load_balancer.select(None).await {
-            Some(backend) => {
-                debug!("Selected backend: {:?}", backend);
-                match self.stream_config.listen().protocol().clone() {
-                    Protocol::Tcp => {
-                        let upstream = TcpStream::connect(backend.addr).await?;
-                        // TODO:: make this function as tower Service and implement the call method
-                        self.proxy_tcp(tls_stream, upstream).await?;
-                    }
-                    Protocol::Ws => {
-                        let client_ws = accept_async(tls_stream).await?;
+        let attributes = Attributes::from_tls(&tls_stream);
+        record.set_tls(&attributes);
+        if let Some(global_rate_limiter) = &self.global_rate_limiter {
+            let subject = Subject {
+                client: client_addr.ip(),
+                sni: attributes.sni.as_deref(),
+                path: None,
+                headers: None,
+            };
+            match global_rate_limiter.check(&subject).await {
+                Ok(()) => {}
+                Err(Denied::OverLimit(limited)) => {
+                    debug!("Closing connection from {}: {}", client_addr, limited);
+                    self.metrics.on_rate_limited(limited.limit.as_str());
+                    record.set_termination("rate_limited");
+                    return Ok(());
+                }
+                Err(denied @ Denied::Unavailable) => {
+                    debug!("Closing connection from {}: {}", client_addr, denied);
+                    record.set_termination("rate_limit_unavailable");
+                    return Ok(());
+                }
+            }
+        }
+        let subset = self.routes.subset(&attributes, None);
+        let affinity_key = client_addr.ip().to_string();
+        let limits = [Limit::Connections];
+        match self.stream_config.listen().protocol().clone() {
+            Protocol::Tcp => {
+                let connect_start = Instant::now();
+                let upstream = proxy::connect(
+                    &self.load_balancer,
+                    &affinity_key,
+                    subset,
+                    &limits,
+                    |backend| async move { Ok(TcpStream::connect(backend.addr).await?) },
+                )
+                .await
+                .inspect_err(|e| record.set_connect_error(e))?;
+                record.set_connect(connect_start.elapsed());
+                record.set_backend(upstream.in_flight.backend().addr);
+                debug!("Connected to backend: {:?}", upstream.in_flight.backend());
+                // TODO:: make this function as tower Service and implement the call method
+                record.set_termination(
+                    self.proxy_tcp(tls_stream, upstream.io)
+                        .instrument(info_span!("proxy"))
+                        .await?,
+                );
+            }
+            Protocol::Ws => {
+                record.set_termination("websocket_handshake_failed");
+                let client_ws = accept_async(tls_stream).await?;
+                let connect_start = Instant::now();
+                let upstream = proxy::connect(
+                    &self.load_balancer,
+                    &affinity_key,
+                    subset,
+                    &limits,
+                    |backend| async move {
                         let upstream_url =
                             format!("ws://{}:{}", backend.addr.ip(), backend.addr.port());
-                        let (upstream_ws, response) = connect_async(&upstream_url).await?;
-                        debug!("Connected to upstream: {:?}", response);
-                        // TODO:: make this function as tower Service and implement the call method
-                        self.proxy_ws(client_ws, upstream_ws).await?;
-                    }
-                    _ => {
-                        return Err(eyre::eyre!("Unsupported protocol"));
-                    }
-                }
+                        Ok(connect_async(&upstream_url).await?)
+                    },
+                )
+                .await
+                .inspect_err(|e| record.set_connect_error(e))?;
+                record.set_connect(connect_start.elapsed());
+                record.set_backend(upstream.in_flight.backend().addr);
+                let (upstream_ws, response) = upstream.io;
+                debug!("Connected to upstream: {:?}", response);
+                record.set_termination("proxy_error");
+                // TODO:: make this function as tower Service and implement the call method
+                self.proxy_ws(client_ws, upstream_ws)
+                    .instrument(info_span!("proxy"))
+                    .await?;
+            }
+            _ => {
+                return Err(eyre::eyre!("Unsupported protocol"));
             }
-            None => return Err(eyre::eyre!("No backends available")),
         }
 
         Ok(())
     }
 
+    /// Copies data both ways until one side closes, returning which one.
     // TODO:: make this function as tower Service and implement the call method
-    async fn proxy_tcp<IO>(&self, client: TlsStream<IO>, server: TcpStream) -> Result<()>
+    async fn proxy_tcp<IO>(&self, client: TlsStream<IO>, server: TcpStream) -> Result<&'static str>
     where
         IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Sync + Unpin + 'static,
     {
@@ -97,20 +211,26 @@ impl StreamProxy {
         let client_to_server = tokio::io::copy(&mut client_reader, &mut server_writer);
         let server_to_client = tokio::io::copy(&mut server_reader, &mut client_writer);
 
-        tokio::select! {
+        let termination = tokio::select! {
             result = client_to_server => {
                 if let Err(e) = result {
                     error!("Error in client to server communication: {:?}", e);
+                    "client_error"
+                } else {
+                    "client_closed"
                 }
             }
             result = server_to_client => {
                 if let Err(e) = result {
                     error!("Error in server to client communication: {:?}", e);
+                    "upstream_error"
+                } else {
+                    "upstream_closed"
                 }
             }
-        }
+        };
 
-        Ok(())
+        Ok(termination)
     }
 
     // TODO:: make this function as tower Service and implement the call method
@@ -136,6 +256,14 @@ impl StreamProxy {
         }
     }
 
+    pub fn acl(&self) -> Option<Arc<Acl>> {
+        self.acl.clone()
+    }
+
+    pub fn metrics(&self) -> ListenerMetrics {
+        self.metrics.clone()
+    }
+
     pub fn load_balancer(&self) -> Arc<LoadBalancer> {
         Arc::clone(&self.load_balancer)
     }
@@ -145,10 +273,7 @@ impl StreamProxy {
     }
 }
 
-impl<IO> Service<IO> for StreamProxy
-where
-    IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Sync + Unpin + 'static,
-{
+impl Service<TcpStream> for StreamProxy {
     type Response = ();
     type Error = eyre::Error;
     type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
@@ -157,9 +282,46 @@ where
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, req: IO) -> Self::Future {
+    fn call(&mut self, req: TcpStream) -> Self::Future {
         let this = self.clone();
-        Box::pin(async move { this.handle_connection(req).await })
+        Box::pin(async move {
+            let start = Instant::now();
+            let client_addr = req.peer_addr()?;
+            let mut record = Record::connection(
+                this.stream_config.name(),
+                this.stream_config.proxy_pass(),
+                client_addr,
+            );
+            let _permit = match this.rate_limiter.accept(client_addr.ip()) {
+                Ok(permit) => permit,
+                Err(limited) => {
+                    debug!("Closing connection from {}: {}", client_addr, limited);
+                    this.metrics.on_rate_limited(limited.limit.as_str());
+                    record.set_termination("rate_limited");
+                    record.set_duration(start.elapsed());
+                    this.access_log.log(record);
+                    return Ok(());
+                }
+            };
+            this.metrics.on_accept();
+            let client_io = Metered::new(req, &this.metrics);
+            let transferred = client_io.transferred();
+            let span = info_span!(
+                "connection",
+                listener = this.stream_config.name(),
+                client_addr = %client_addr,
+            );
+            let result = this
+                .handle_connection(client_io, client_addr, &mut record)
+                .instrument(span)
+                .await;
+            this.metrics.on_close();
+
+            record.set_transferred(&transferred);
+            record.set_duration(start.elapsed());
+            this.access_log.log(record);
+            result
+        })
     }
 }
 
@@ -169,6 +331,12 @@ impl Clone for StreamProxy {
             stream_config: Arc::clone(&self.stream_config),
             tls_server: Arc::clone(&self.tls_server),
             load_balancer: Arc::clone(&self.load_balancer),
+            routes: Arc::clone(&self.routes),
+            metrics: self.metrics.clone(),
+            rate_limiter: Arc::clone(&self.rate_limiter),
+            global_rate_limiter: self.global_rate_limiter.clone(),
+            acl: self.acl.clone(),
+            access_log: self.access_log.clone(),
         }
     }
 }
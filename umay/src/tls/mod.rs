@@ -1,4 +1,4 @@
-use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
 use tokio_rustls::server::TlsStream;
 
 pub mod client;
@@ -9,6 +9,17 @@ pub mod server;
 #[derive(Clone, Debug)]
 pub struct ClientId(pub Vec<u8>);
 
+impl ClientId {
+    /// DNS names the client certificate was issued for, which identify the
+    /// client, e.g. `default.default.serviceaccount.identity.umay.cluster.local`.
+    pub fn dns_names(&self) -> Vec<String> {
+        let der = CertificateDer::from(self.0.as_slice());
+        webpki::EndEntityCert::try_from(&der)
+            .map(|cert| cert.valid_dns_names().map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+}
+
 pub enum ServerTls {
     Established {
         client_id: Option<ClientId>,
@@ -22,7 +33,7 @@ pub enum ServerTls {
 #[derive(Clone, Debug)]
 pub struct NegotiatedProtocol(pub Vec<u8>);
 
-fn client_identity<I>(tls_stream: &TlsStream<I>) -> Option<ClientId> {
+pub(crate) fn client_identity<I>(tls_stream: &TlsStream<I>) -> Option<ClientId> {
     let (_io, session) = tls_stream.get_ref();
     session
         .peer_certificates()
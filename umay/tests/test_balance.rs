@@ -0,0 +1,466 @@
+use std::collections::BTreeSet;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use umay::app::config::RefreshConfig;
+use umay::app::metric::Metrics;
+use umay::app::readiness::Readiness;
+use umay::balance::circuit::{CircuitBreaker, Limit, Limits};
+use umay::balance::discovery::{poll, Change, LocalDiscovery, ServiceDiscovery, Watch};
+use umay::balance::health::Health;
+use umay::balance::locality::Locality;
+use umay::balance::selection::{RoundRobin, WeightedRoundRobin};
+use umay::balance::subset::{Fallback, Subset};
+use umay::balance::{Backend, Backends, LoadBalancer};
+
+struct SharedDiscovery(Arc<LocalDiscovery>);
+
+#[async_trait]
+impl ServiceDiscovery for SharedDiscovery {
+    async fn discover(&self) -> eyre::Result<Arc<BTreeSet<Backend>>> {
+        self.0.discover().await
+    }
+}
+
+/// Shares a `LocalDiscovery` including its watch.
+struct PushingDiscovery(Arc<LocalDiscovery>);
+
+#[async_trait]
+impl ServiceDiscovery for PushingDiscovery {
+    async fn discover(&self) -> eyre::Result<Arc<BTreeSet<Backend>>> {
+        self.0.discover().await
+    }
+
+    fn watch(self: Arc<Self>) -> Option<Watch> {
+        Arc::clone(&self.0).watch()
+    }
+}
+
+fn backend(last_octet: u8) -> Backend {
+    Backend::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, last_octet).into(), 8080),
+        1,
+    )
+}
+
+async fn load_balancer(backends: Vec<Backend>) -> eyre::Result<LoadBalancer> {
+    let discovery = LocalDiscovery::with_backends(backends);
+    let lb = LoadBalancer::new(
+        Backends::new(Box::new(discovery)),
+        Arc::new(RoundRobin::default()),
+    );
+    lb.refresh().await?;
+    Ok(lb)
+}
+
+#[tokio::test]
+async fn test_backup_used_only_when_primaries_unavailable() -> eyre::Result<()> {
+    let primary = backend(1);
+    let backup = Backend {
+        backup: true,
+        ..backend(2)
+    };
+    let lb = load_balancer(vec![primary.clone(), backup.clone()]).await?;
+
+    for _ in 0..4 {
+        assert_eq!(lb.select(None).await, Some(primary.clone()));
+    }
+
+    lb.track(&primary).observe_failure();
+    assert_eq!(lb.select(None).await, Some(backup));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_priorities_are_used_in_order() -> eyre::Result<()> {
+    let tiers: Vec<Backend> = [(1, 10), (2, 20), (3, 30)]
+        .into_iter()
+        .map(|(last_octet, priority)| Backend {
+            priority,
+            ..backend(last_octet)
+        })
+        .collect();
+    let lb = load_balancer(tiers.clone()).await?;
+
+    for tier in &tiers {
+        for _ in 0..4 {
+            assert_eq!(lb.select(None).await.as_ref(), Some(tier));
+        }
+        lb.track(tier).observe_failure();
+    }
+    assert_eq!(lb.select(None).await, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_fails_within_fail_timeout() -> eyre::Result<()> {
+    let flaky = Backend {
+        max_fails: 2,
+        ..backend(1)
+    };
+    let lb = load_balancer(vec![flaky.clone()]).await?;
+
+    lb.track(&flaky).observe_failure();
+    assert_eq!(lb.select(None).await, Some(flaky.clone()));
+
+    lb.track(&flaky).observe_failure();
+    assert_eq!(lb.select(None).await, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_conns_caps_active_connections() -> eyre::Result<()> {
+    let capped = Backend {
+        max_conns: 1,
+        ..backend(1)
+    };
+    let other = backend(2);
+    let lb = load_balancer(vec![capped.clone(), other.clone()]).await?;
+
+    let in_flight = lb.track(&capped);
+    for _ in 0..4 {
+        assert_eq!(lb.select(None).await, Some(other.clone()));
+    }
+
+    drop(in_flight);
+    let mut selected = vec![];
+    for _ in 0..2 {
+        selected.push(lb.select(None).await.unwrap());
+    }
+    assert!(selected.contains(&capped));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_acquire_reserves_max_conns() -> eyre::Result<()> {
+    let capped = Backend {
+        max_conns: 2,
+        ..backend(1)
+    };
+    let lb = load_balancer(vec![capped.clone()]).await?;
+    let subset = Subset::new();
+
+    let acquired = futures::future::join_all((0..8).map(|_| lb.acquire(None, &subset))).await;
+    let held: Vec<_> = acquired.into_iter().flatten().collect();
+    assert_eq!(held.len(), 2);
+    assert_eq!(lb.backend_status(&capped).active, 2);
+
+    drop(held);
+    assert!(lb.acquire(None, &subset).await.is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_slow_start_ramps_up_new_backend() -> eyre::Result<()> {
+    let slow_start = Duration::from_millis(500);
+    let discovery = Arc::new(LocalDiscovery::with_backends(vec![backend(1)]));
+    let mut lb = LoadBalancer::new(
+        Backends::new(Box::new(SharedDiscovery(Arc::clone(&discovery)))),
+        Arc::new(WeightedRoundRobin::default()),
+    );
+    lb.set_slow_start(slow_start);
+    lb.refresh().await?;
+    lb.select(None).await;
+    tokio::time::sleep(slow_start).await;
+
+    let added = backend(2);
+    discovery.add_backend(added.addr);
+    lb.refresh().await?;
+
+    let mut ramping = 0;
+    for _ in 0..100 {
+        if lb.select(None).await.unwrap().addr == added.addr {
+            ramping += 1;
+        }
+    }
+    assert!(ramping < 20, "new backend got {} of 100 picks", ramping);
+
+    tokio::time::sleep(slow_start).await;
+    let mut ramped = 0;
+    for _ in 0..100 {
+        if lb.select(None).await.unwrap().addr == added.addr {
+            ramped += 1;
+        }
+    }
+    assert!(
+        (45..=55).contains(&ramped),
+        "ramped backend got {} of 100 picks",
+        ramped
+    );
+    Ok(())
+}
+
+#[test]
+fn test_slow_start_reuses_weights_within_a_step() {
+    let health = Health::default();
+    let backends: Arc<BTreeSet<Backend>> = Arc::new([backend(1), backend(2)].into());
+    health.sync(&backends);
+
+    let slow_start = Duration::from_secs(3600);
+    let first = health.slow_start(&backends, slow_start);
+    let second = health.slow_start(&Arc::new((*backends).clone()), slow_start);
+    assert!(Arc::ptr_eq(&first, &second));
+    assert!(first.iter().all(|b| b.weight == backend(1).weight));
+
+    let restarted = Health::default();
+    restarted.sync(&backends);
+    std::thread::sleep(Duration::from_millis(20));
+    let ramped = restarted.slow_start(&backends, Duration::from_millis(10));
+    assert!(Arc::ptr_eq(&ramped, &backends));
+}
+
+#[tokio::test]
+async fn test_locality_spills_over_below_threshold() -> eyre::Result<()> {
+    let in_zone = |last_octet: u8, zone: &str| {
+        let mut backend = backend(last_octet);
+        backend
+            .metadata
+            .insert("zone".to_string(), zone.to_string());
+        backend
+    };
+    let local = [in_zone(1, "a"), in_zone(2, "a")];
+    let remote = [in_zone(3, "b"), in_zone(4, "b")];
+    let mut lb = load_balancer(local.iter().chain(&remote).cloned().collect()).await?;
+    lb.set_locality(Some(Locality::new(
+        "a".to_string(),
+        "zone".to_string(),
+        0.5,
+    )));
+
+    for _ in 0..4 {
+        assert!(local.contains(&lb.select(None).await.unwrap()));
+    }
+
+    // Half of the local weight is still healthy, which meets the threshold.
+    lb.track(&local[0]).observe_failure();
+    for _ in 0..4 {
+        assert_eq!(lb.select(None).await, Some(local[1].clone()));
+    }
+
+    lb.track(&local[1]).observe_failure();
+    for _ in 0..4 {
+        assert!(remote.contains(&lb.select(None).await.unwrap()));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subset_falls_back_when_empty() -> eyre::Result<()> {
+    let versioned = |last_octet: u8, version: &str| {
+        let mut backend = backend(last_octet);
+        backend
+            .metadata
+            .insert("version".to_string(), version.to_string());
+        backend
+    };
+    let (blue, green) = (versioned(1, "v1"), versioned(2, "v2"));
+    let mut lb = load_balancer(vec![blue.clone(), green.clone()]).await?;
+    let subset = |version: &str| Subset::from([("version".to_string(), version.to_string())]);
+
+    assert_eq!(lb.select_subset(None, &subset("v2")).await, Some(green));
+    assert!(lb.select_subset(None, &subset("v3")).await.is_some());
+
+    lb.set_subset_fallback(Fallback::None);
+    assert_eq!(lb.select_subset(None, &subset("v3")).await, None);
+
+    lb.set_subset_fallback(Fallback::Subset(subset("v1")));
+    assert_eq!(lb.select_subset(None, &subset("v3")).await, Some(blue));
+    Ok(())
+}
+
+#[test]
+fn test_circuit_breaker_caps_upstream_and_backends() {
+    let breaker = CircuitBreaker::new(
+        Limits {
+            max_connections: 2,
+            ..Limits::default()
+        },
+        Limits {
+            max_connections: 1,
+            ..Limits::default()
+        },
+    );
+    let (first, second, third) = (backend(1), backend(2), backend(3));
+
+    let held = breaker.try_acquire(Limit::Connections, Some(&first));
+    assert!(held.is_ok());
+    assert!(breaker
+        .try_acquire(Limit::Connections, Some(&first))
+        .is_err());
+    let _second = breaker.try_acquire(Limit::Connections, Some(&second));
+    assert!(breaker
+        .try_acquire(Limit::Connections, Some(&third))
+        .is_err());
+    assert_eq!(breaker.active(Limit::Connections), 2);
+    assert_eq!(breaker.overflows(Limit::Connections), 2);
+
+    drop(held);
+    assert!(breaker
+        .try_acquire(Limit::Connections, Some(&third))
+        .is_ok());
+    // Unset limits never overflow.
+    assert!(breaker.try_acquire(Limit::Requests, Some(&first)).is_ok());
+}
+
+#[tokio::test]
+async fn test_refresh_task_applies_pushed_changes() -> eyre::Result<()> {
+    let discovery = Arc::new(LocalDiscovery::with_backends(vec![backend(1)]));
+    let mut lb = LoadBalancer::new(
+        Backends::new(Box::new(PushingDiscovery(Arc::clone(&discovery)))),
+        Arc::new(RoundRobin::default()),
+    );
+    // Polling alone would not pick the change up within the test.
+    let mut refresh = RefreshConfig::default();
+    refresh.set_interval(Some(3600));
+    lb.set_refresh(refresh);
+    let lb = Arc::new(lb);
+    lb.refresh().await?;
+    let task = Arc::clone(&lb).start_refresh_task();
+
+    discovery.remove_backend(&backend(1).addr);
+    discovery.add_backend(backend(2).addr);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    task.abort();
+
+    assert_eq!(lb.select(None).await, Some(backend(2)));
+    Ok(())
+}
+
+/// Fails every discovery once `failing` is set.
+struct FailingDiscovery {
+    backends: Arc<BTreeSet<Backend>>,
+    failing: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl ServiceDiscovery for FailingDiscovery {
+    async fn discover(&self) -> eyre::Result<Arc<BTreeSet<Backend>>> {
+        if self.failing.load(Ordering::Relaxed) {
+            eyre::bail!("discovery unavailable");
+        }
+        Ok(Arc::clone(&self.backends))
+    }
+}
+
+#[tokio::test]
+async fn test_refresh_task_drops_backends_after_stale_grace_period() -> eyre::Result<()> {
+    let metrics = Metrics::new();
+    let failing = Arc::new(AtomicBool::new(false));
+    let mut lb = LoadBalancer::new(
+        Backends::new(Box::new(FailingDiscovery {
+            backends: Arc::new(BTreeSet::from([backend(1)])),
+            failing: Arc::clone(&failing),
+        })),
+        Arc::new(RoundRobin::default()),
+    );
+    let mut refresh = RefreshConfig::default();
+    refresh.set_interval(Some(0));
+    refresh.set_min_interval(0);
+    refresh.set_stale_grace_period(0);
+    lb.set_refresh(refresh);
+    lb.set_metrics(metrics.upstream("app"));
+    let lb = Arc::new(lb);
+    lb.refresh().await?;
+    assert_eq!(lb.select(None).await, Some(backend(1)));
+    assert!(metrics.encode()?.contains("backend=\"10.0.0.1:8080\""));
+
+    failing.store(true, Ordering::Relaxed);
+    let task = Arc::clone(&lb).start_refresh_task();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    task.abort();
+
+    assert!(lb.backends().get_backends().is_empty());
+    assert_eq!(lb.select(None).await, None);
+    assert!(!metrics.encode()?.contains("backend=\"10.0.0.1:8080\""));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_polling_adapter_discovers_on_schedule() -> eyre::Result<()> {
+    let discovery = Arc::new(LocalDiscovery::with_backends(vec![backend(1)]));
+    let mut refresh = RefreshConfig::default();
+    refresh.set_interval(Some(0));
+    let mut changes = poll(
+        Arc::new(SharedDiscovery(Arc::clone(&discovery))),
+        refresh,
+        true,
+    );
+
+    let expected = Arc::new(BTreeSet::from([backend(1)]));
+    assert_eq!(changes.next().await.unwrap()?, Change::Replace(expected));
+
+    discovery.add_backend(backend(2).addr);
+    let expected = Arc::new(BTreeSet::from([backend(1), backend(2)]));
+    assert_eq!(changes.next().await.unwrap()?, Change::Replace(expected));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_local_discovery_pushes_deltas() -> eyre::Result<()> {
+    let discovery = Arc::new(LocalDiscovery::with_backends(vec![backend(1)]));
+    let mut changes = Arc::clone(&discovery)
+        .watch()
+        .expect("local discovery pushes");
+
+    let current = Arc::new(BTreeSet::from([backend(1)]));
+    assert_eq!(changes.next().await.unwrap()?, Change::Replace(current));
+
+    discovery.add_backend(backend(2).addr);
+    discovery.remove_backend(&backend(1).addr);
+    assert_eq!(changes.next().await.unwrap()?, Change::Add(backend(2)));
+    assert_eq!(
+        changes.next().await.unwrap()?,
+        Change::Remove(backend(1).addr)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ready_after_first_discovery_until_shutdown() -> eyre::Result<()> {
+    let lb = Arc::new(LoadBalancer::new(
+        Backends::new(Box::new(LocalDiscovery::with_backends(vec![backend(1)]))),
+        Arc::new(RoundRobin::default()),
+    ));
+    let readiness = Readiness::new(1, vec![Arc::clone(&lb)]);
+
+    readiness.on_bound();
+    assert!(!readiness.is_ready());
+    lb.refresh().await?;
+    assert!(readiness.is_ready());
+    readiness.on_shutdown();
+    assert!(!readiness.is_ready());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_local_discovery_pushes_concurrent_changes_in_order() -> eyre::Result<()> {
+    let discovery = Arc::new(LocalDiscovery::default());
+    let mut changes = Arc::clone(&discovery)
+        .watch()
+        .expect("local discovery pushes");
+
+    let writers: Vec<_> = (1..=4)
+        .map(|weight| {
+            let discovery = Arc::clone(&discovery);
+            std::thread::spawn(move || {
+                for _ in 0..8 {
+                    discovery.put_backend(Backend::new(backend(1).addr, weight));
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().expect("writer");
+    }
+
+    let mut seen = Arc::new(BTreeSet::new());
+    for _ in 0..=32 {
+        seen = changes.next().await.unwrap()?.apply(&seen);
+    }
+    assert_eq!(seen, discovery.discover().await?);
+    Ok(())
+}
@@ -0,0 +1,191 @@
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::StreamExt;
+use http::{Request, Response, StatusCode};
+use http_body_util::Full;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use umay::app::config::ConsulConfig;
+use umay::balance::discovery::consul::ConsulDiscovery;
+use umay::balance::discovery::{Change, ServiceDiscovery};
+
+const INSTANCES: &str = r#"[
+  {
+    "Node": {"Node": "node-1", "Address": "10.0.0.1", "Datacenter": "dc1"},
+    "Service": {"Service": "web", "Address": "", "Port": 8080, "Tags": ["primary"],
+                "Meta": {"version": "2"}, "Weights": {"Passing": 3, "Warning": 1}}
+  },
+  {
+    "Node": {"Node": "node-2", "Address": "10.0.0.2", "Datacenter": "dc1"},
+    "Service": {"Service": "web", "Address": "10.0.1.2", "Port": 8081, "Tags": null, "Meta": null}
+  }
+]"#;
+
+const CHANGED_INSTANCES: &str = r#"[
+  {
+    "Node": {"Node": "node-2", "Address": "10.0.0.2", "Datacenter": "dc1"},
+    "Service": {"Service": "web", "Address": "10.0.1.2", "Port": 8081}
+  }
+]"#;
+
+/// Stands in for the health endpoint of a Consul agent: index 10 holds
+/// `INSTANCES`, and a blocking query on it returns `CHANGED_INSTANCES`.
+async fn spawn_fake_consul(queries: Arc<Mutex<Vec<String>>>) -> eyre::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let queries = Arc::clone(&queries);
+            let service = service_fn(move |request: Request<hyper::body::Incoming>| {
+                let queries = Arc::clone(&queries);
+                async move {
+                    let query = request.uri().to_string();
+                    queries.lock().unwrap().push(query.clone());
+                    let (status, index, body) = if !query.starts_with("/v1/health/service/web?") {
+                        (StatusCode::NOT_FOUND, "0", "")
+                    } else if query.contains("index=10") {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        (StatusCode::OK, "11", CHANGED_INSTANCES)
+                    } else {
+                        (StatusCode::OK, "10", INSTANCES)
+                    };
+                    let mut response = Response::new(Full::new(Bytes::from(body)));
+                    *response.status_mut() = status;
+                    response
+                        .headers_mut()
+                        .insert("X-Consul-Index", index.parse().unwrap());
+                    Ok::<_, Infallible>(response)
+                }
+            });
+            tokio::spawn(
+                hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service),
+            );
+        }
+    });
+    Ok(addr)
+}
+
+async fn consul_discovery(
+    service: &str,
+) -> eyre::Result<(ConsulDiscovery, Arc<Mutex<Vec<String>>>)> {
+    let queries = Arc::new(Mutex::new(vec![]));
+    let agent = spawn_fake_consul(Arc::clone(&queries)).await?;
+    let mut config = ConsulConfig::new(service.to_string());
+    config.set_address(format!("http://{}", agent));
+    config.set_wait(5);
+    Ok((ConsulDiscovery::new(config)?, queries))
+}
+
+#[tokio::test]
+async fn test_consul_discovery_maps_passing_instances() -> eyre::Result<()> {
+    let (discovery, queries) = consul_discovery("web").await?;
+    let backends = discovery.discover().await?;
+
+    let first = backends
+        .iter()
+        .find(|b| b.addr == SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 8080))
+        .expect("instance without an address uses its node address");
+    assert_eq!(first.weight, 3);
+    assert_eq!(
+        first.metadata.get("tag:primary").map(String::as_str),
+        Some("true")
+    );
+    assert_eq!(first.metadata.get("version").map(String::as_str), Some("2"));
+    assert_eq!(
+        first.metadata.get("datacenter").map(String::as_str),
+        Some("dc1")
+    );
+
+    let second = backends
+        .iter()
+        .find(|b| b.addr == SocketAddr::new(Ipv4Addr::new(10, 0, 1, 2).into(), 8081))
+        .expect("instance address");
+    assert_eq!(second.weight, 1);
+
+    let queries = queries.lock().unwrap();
+    assert_eq!(queries.as_slice(), ["/v1/health/service/web?passing=true"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_consul_discovery_uses_blocking_queries() -> eyre::Result<()> {
+    let (discovery, queries) = consul_discovery("web").await?;
+    assert_eq!(discovery.discover().await?.len(), 2);
+
+    let changed = discovery.discover().await?;
+    assert_eq!(changed.len(), 1);
+    assert_eq!(
+        queries.lock().unwrap().last().map(String::as_str),
+        Some("/v1/health/service/web?passing=true&index=10&wait=5s")
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_consul_discovery_resets_index_going_backwards() -> eyre::Result<()> {
+    let (discovery, queries) = consul_discovery("web").await?;
+    discovery.discover().await?;
+    discovery.discover().await?;
+    // Answered with index 10 again, lower than the 11 it blocked on.
+    discovery.discover().await?;
+    discovery.discover().await?;
+
+    let queries = queries.lock().unwrap();
+    assert_eq!(
+        queries[2..],
+        [
+            "/v1/health/service/web?passing=true&index=11&wait=5s",
+            "/v1/health/service/web?passing=true",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_consul_discovery_encodes_query() -> eyre::Result<()> {
+    let queries = Arc::new(Mutex::new(vec![]));
+    let agent = spawn_fake_consul(Arc::clone(&queries)).await?;
+    let mut config = ConsulConfig::new("web".to_string());
+    config.set_address(format!("http://{}", agent));
+    config.set_tag(Some("blue green&dc=eu".to_string()));
+    ConsulDiscovery::new(config)?.discover().await?;
+
+    assert_eq!(
+        queries.lock().unwrap().as_slice(),
+        ["/v1/health/service/web?passing=true&tag=blue%20green%26dc%3Deu"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_consul_discovery_fails_for_unknown_service() -> eyre::Result<()> {
+    let (discovery, _) = consul_discovery("unknown").await?;
+    assert!(discovery.discover().await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_consul_discovery_pushes_changes() -> eyre::Result<()> {
+    let (discovery, _) = consul_discovery("web").await?;
+    let mut changes = Arc::new(discovery)
+        .watch()
+        .expect("consul discovery pushes");
+
+    let timeout = Duration::from_secs(5);
+    for expected in [2, 1] {
+        match tokio::time::timeout(timeout, changes.next())
+            .await?
+            .unwrap()?
+        {
+            Change::Replace(backends) => assert_eq!(backends.len(), expected),
+            change => panic!("unexpected change {:?}", change),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,305 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use umay::app::config::{DnsConfig, RefreshConfig, UpstreamServer};
+use umay::balance::discovery::file::FileDiscovery;
+use umay::balance::discovery::{Change, DnsDiscovery, DnsSrvDiscovery, ServiceDiscovery};
+
+#[tokio::test]
+async fn test_dns_discovery_merges_all_servers() -> eyre::Result<()> {
+    let mut weighted = UpstreamServer::new("127.0.0.2".to_string(), 8081);
+    weighted.set_weight(3);
+    let servers = vec![
+        UpstreamServer::new("localhost".to_string(), 8080),
+        weighted,
+        UpstreamServer::new("127.0.0.3".to_string(), 8082),
+    ];
+
+    let backends = DnsDiscovery::new(servers, None)?.discover().await?;
+
+    let localhost = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8080);
+    assert!(backends.iter().any(|b| b.addr == localhost));
+
+    let weighted = backends
+        .iter()
+        .find(|b| b.addr == SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), 8081))
+        .expect("literal IP should be used without a lookup");
+    assert_eq!(weighted.weight, 3);
+
+    assert!(backends
+        .iter()
+        .any(|b| b.addr == SocketAddr::new(Ipv4Addr::new(127, 0, 0, 3).into(), 8082)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dns_discovery_keeps_partial_results() -> eyre::Result<()> {
+    let servers = vec![
+        UpstreamServer::new("127.0.0.1".to_string(), 8080),
+        UpstreamServer::new("umay-test.invalid".to_string(), 8080),
+    ];
+
+    let backends = DnsDiscovery::new(servers, None)?.discover().await?;
+
+    assert_eq!(backends.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dns_discovery_fails_when_nothing_resolves() -> eyre::Result<()> {
+    let servers = vec![UpstreamServer::new("umay-test.invalid".to_string(), 8080)];
+
+    assert!(DnsDiscovery::new(servers, None)?.discover().await.is_err());
+    Ok(())
+}
+
+#[test]
+fn test_refresh_interval_follows_ttl_within_bounds() {
+    let mut refresh = RefreshConfig::default();
+    refresh.set_min_interval(5);
+    refresh.set_max_interval(300);
+
+    assert_eq!(
+        refresh.next_interval(Some(Duration::from_secs(60))),
+        Duration::from_secs(60)
+    );
+    assert_eq!(
+        refresh.next_interval(Some(Duration::from_secs(1))),
+        Duration::from_secs(5)
+    );
+    assert_eq!(
+        refresh.next_interval(Some(Duration::from_secs(86_400))),
+        Duration::from_secs(300)
+    );
+    assert_eq!(refresh.next_interval(None), RefreshConfig::DEFAULT_INTERVAL);
+
+    refresh.set_interval(Some(10));
+    assert_eq!(
+        refresh.next_interval(Some(Duration::from_secs(60))),
+        Duration::from_secs(10)
+    );
+}
+
+fn from_yaml(yaml: &str) -> eyre::Result<DnsConfig> {
+    Ok(config::Config::builder()
+        .add_source(config::File::from_str(yaml, config::FileFormat::Yaml))
+        .build()?
+        .try_deserialize()?)
+}
+
+/// Answers SRV and A queries for `umay.test.` names over UDP.
+async fn spawn_fake_nameserver() -> eyre::Result<SocketAddr> {
+    use hickory_resolver::proto::op::{Message, MessageType};
+    use hickory_resolver::proto::rr::rdata::{A, SRV};
+    use hickory_resolver::proto::rr::{Name, RData, Record, RecordType};
+    use hickory_resolver::proto::serialize::binary::{BinDecodable, BinEncodable};
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+    let addr = socket.local_addr()?;
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+            let Ok(request) = Message::from_bytes(&buf[..len]) else {
+                continue;
+            };
+            let mut response = Message::new();
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response)
+                .set_recursion_available(true)
+                .add_queries(request.queries().to_vec());
+
+            for query in request.queries() {
+                let name = query.name().clone();
+                let srv = |priority, weight, port, target: &str| {
+                    let target = Name::from_ascii(target).unwrap();
+                    RData::SRV(SRV::new(priority, weight, port, target))
+                };
+                let answers = match (name.to_ascii().as_str(), query.query_type()) {
+                    ("_svc._tcp.umay.test.", RecordType::SRV) => vec![
+                        srv(10, 5, 9001, "a.umay.test."),
+                        srv(10, 0, 9002, "b.umay.test."),
+                        srv(20, 1, 9003, "c.umay.test."),
+                        srv(30, 1, 9004, "d.umay.test."),
+                    ],
+                    ("a.umay.test.", RecordType::A) => vec![RData::A(A::new(127, 0, 1, 1))],
+                    ("b.umay.test.", RecordType::A) => vec![RData::A(A::new(127, 0, 1, 2))],
+                    ("c.umay.test.", RecordType::A) => vec![RData::A(A::new(127, 0, 1, 3))],
+                    ("d.umay.test.", RecordType::A) => vec![RData::A(A::new(127, 0, 1, 4))],
+                    _ => vec![],
+                };
+                for rdata in answers {
+                    response.add_answer(Record::from_rdata(name.clone(), 60, rdata));
+                }
+            }
+
+            if let Ok(bytes) = response.to_bytes() {
+                let _ = socket.send_to(&bytes, peer).await;
+            }
+        }
+    });
+    Ok(addr)
+}
+
+#[tokio::test]
+async fn test_dns_srv_discovery_with_custom_nameserver() -> eyre::Result<()> {
+    let nameserver = spawn_fake_nameserver().await?;
+    let dns = from_yaml(&format!(
+        r#"
+nameservers:
+  - address: "{}"
+    protocol: udp
+timeout: 1000
+attempts: 1
+ip_strategy: ipv4_only
+"#,
+        nameserver
+    ))?;
+
+    let servers = vec![UpstreamServer::new("_svc._tcp.umay.test".to_string(), 0)];
+    let discovery = DnsSrvDiscovery::new(servers, Some(dns))?;
+    let backends = discovery.discover().await?;
+
+    let record = |last_octet: u8, port: u16| {
+        backends
+            .iter()
+            .find(|b| b.addr == SocketAddr::new(Ipv4Addr::new(127, 0, 1, last_octet).into(), port))
+            .map(|b| (b.priority, b.weight, b.backup))
+    };
+    // Weights keep their ratio, a zero weight stays far below the others.
+    assert_eq!(record(1, 9001), Some((10, 500, false)));
+    assert_eq!(record(2, 9002), Some((10, 1, false)));
+    // Every priority is a tier of its own.
+    assert_eq!(record(3, 9003), Some((20, 100, false)));
+    assert_eq!(record(4, 9004), Some((30, 100, false)));
+
+    assert!(discovery
+        .ttl()
+        .is_some_and(|ttl| ttl <= Duration::from_secs(60)));
+    Ok(())
+}
+
+#[test]
+fn test_dns_config_requires_tls_name_for_tls() -> eyre::Result<()> {
+    let dns = from_yaml(
+        r#"
+nameservers:
+  - address: "1.1.1.1:853"
+    protocol: tls
+"#,
+    )?;
+    assert!(dns.into_resolver_config().is_err());
+    Ok(())
+}
+
+fn endpoints_file(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("umay-{}-{}", std::process::id(), name))
+}
+
+#[tokio::test]
+async fn test_file_discovery_reads_endpoints() -> eyre::Result<()> {
+    let path = endpoints_file("endpoints.yaml");
+    std::fs::write(
+        &path,
+        r#"
+endpoints:
+  - address: 10.0.0.1
+    port: 8080
+    weight: 2
+    metadata:
+      zone: eu-west-1a
+  - address: "::1"
+    port: 8081
+    backup: true
+"#,
+    )?;
+
+    let backends = FileDiscovery::new(&path, None)?.discover().await?;
+    std::fs::remove_file(&path)?;
+
+    let first = backends
+        .iter()
+        .find(|b| b.addr == SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 8080))
+        .expect("first endpoint");
+    assert_eq!(first.weight, 2);
+    assert_eq!(
+        first.metadata.get("zone").map(String::as_str),
+        Some("eu-west-1a")
+    );
+
+    let second = backends.iter().find(|b| b.addr.port() == 8081).unwrap();
+    assert_eq!(second.weight, 1);
+    assert!(second.backup);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_file_discovery_ignores_invalid_rewrites() -> eyre::Result<()> {
+    let path = endpoints_file("endpoints.json");
+    std::fs::write(
+        &path,
+        r#"{"endpoints": [{"address": "10.0.0.1", "port": 8080}]}"#,
+    )?;
+    let discovery = FileDiscovery::new(&path, None)?;
+    assert_eq!(discovery.discover().await?.len(), 1);
+
+    // A half written file and an invalid endpoint both keep the last endpoints.
+    std::fs::write(&path, r#"{"endpoints": [{"address": "10.0.0.1", "#)?;
+    assert_eq!(discovery.discover().await?.len(), 1);
+    std::fs::write(
+        &path,
+        r#"{"endpoints": [{"address": "10.0.0.1", "port": 8080, "weight": 0}]}"#,
+    )?;
+    assert_eq!(discovery.discover().await?.len(), 1);
+
+    std::fs::write(
+        &path,
+        r#"{"endpoints": [{"address": "10.0.0.1", "port": 8080}, {"address": "10.0.0.2", "port": 8080}]}"#,
+    )?;
+    let backends = discovery.discover().await?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(backends.len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_file_discovery_fails_without_valid_endpoints() -> eyre::Result<()> {
+    let path = endpoints_file("missing.json");
+    assert!(FileDiscovery::new(&path, None)?.discover().await.is_err());
+    assert!(FileDiscovery::new(endpoints_file("endpoints.txt"), None).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_file_discovery_pushes_rewrites() -> eyre::Result<()> {
+    let path = endpoints_file("watched.json");
+    std::fs::write(
+        &path,
+        r#"{"endpoints": [{"address": "10.0.0.1", "port": 8080}]}"#,
+    )?;
+    let discovery = Arc::new(FileDiscovery::new(&path, None)?);
+    let mut changes = discovery.watch().expect("file discovery pushes");
+
+    let timeout = Duration::from_secs(5);
+    let first = tokio::time::timeout(timeout, changes.next())
+        .await?
+        .unwrap()?;
+    std::fs::write(
+        &path,
+        r#"{"endpoints": [{"address": "10.0.0.1", "port": 8080}, {"address": "10.0.0.2", "port": 8080}]}"#,
+    )?;
+    let second = tokio::time::timeout(timeout, changes.next())
+        .await?
+        .unwrap()?;
+    std::fs::remove_file(&path)?;
+
+    for (change, expected) in [(first, 1), (second, 2)] {
+        match change {
+            Change::Replace(backends) => assert_eq!(backends.len(), expected),
+            change => panic!("unexpected change {:?}", change),
+        }
+    }
+    Ok(())
+}
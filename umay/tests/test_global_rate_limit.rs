@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use http::{Request, Response, StatusCode};
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::server::{Grpc, NamedService, UnaryService};
+use umay::app::config::{
+    DescriptorConfig, DescriptorEntry, DescriptorSource, FailureMode, GlobalRateLimitConfig,
+    HttpConfig, HttpServer, ListenConfig, LoadBalancer, LocationConfig, Protocol, ServiceDiscovery,
+    UmayConfig, Upstream, UpstreamServer,
+};
+use umay::app::server::UmayServer;
+use umay::proxy::global_rate_limit::proto::{
+    Code, DescriptorStatus, Duration as ProtoDuration, RateLimitRequest, RateLimitResponse,
+};
+
+/// Rate limit service answering over limit for the `blocked` tenant, and
+/// keeping the descriptors it was asked about.
+#[derive(Clone, Default)]
+struct MockRateLimitService {
+    requests: Arc<Mutex<Vec<RateLimitRequest>>>,
+}
+
+impl UnaryService<RateLimitRequest> for MockRateLimitService {
+    type Response = RateLimitResponse;
+    type Future = BoxFuture<'static, Result<tonic::Response<RateLimitResponse>, tonic::Status>>;
+
+    fn call(&mut self, request: tonic::Request<RateLimitRequest>) -> Self::Future {
+        let request = request.into_inner();
+        let blocked = request.descriptors.iter().any(|descriptor| {
+            descriptor
+                .entries
+                .iter()
+                .any(|entry| entry.key == "tenant" && entry.value == "blocked")
+        });
+        self.requests.lock().unwrap().push(request);
+        let (code, reset) = if blocked {
+            (
+                Code::OverLimit,
+                Some(ProtoDuration {
+                    seconds: 3,
+                    nanos: 0,
+                }),
+            )
+        } else {
+            (Code::Ok, None)
+        };
+        let response = RateLimitResponse {
+            overall_code: code as i32,
+            statuses: vec![DescriptorStatus {
+                code: code as i32,
+                limit_remaining: 0,
+                duration_until_reset: reset,
+            }],
+        };
+        Box::pin(async move { Ok(tonic::Response::new(response)) })
+    }
+}
+
+impl tower::Service<Request<BoxBody>> for MockRateLimitService {
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<BoxBody>) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move {
+            Ok(Grpc::new(ProstCodec::default())
+                .unary(service, request)
+                .await)
+        })
+    }
+}
+
+impl NamedService for MockRateLimitService {
+    const NAME: &'static str = "envoy.service.ratelimit.v3.RateLimitService";
+}
+
+async fn start_rate_limit_service(service: MockRateLimitService) -> eyre::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(service)
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+    Ok(addr)
+}
+
+async fn start_http_backend() -> eyre::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let service = service_fn(|_: Request<hyper::body::Incoming>| async {
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("ok"))))
+            });
+            tokio::spawn(
+                hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service),
+            );
+        }
+    });
+    Ok(addr)
+}
+
+fn http_server(port: u16, global_rate_limit: GlobalRateLimitConfig) -> HttpServer {
+    let mut http_server = HttpServer::new(
+        format!("http-{}", port),
+        ListenConfig::new(port, Protocol::Http),
+        None,
+        "backend".to_string(),
+        LocationConfig::new("/".to_string()),
+        "1.1".to_string(),
+        String::new(),
+        60,
+    );
+    http_server.set_global_rate_limit(Some(global_rate_limit));
+    http_server
+}
+
+fn descriptors() -> Vec<DescriptorConfig> {
+    vec![
+        DescriptorConfig::new(vec![
+            DescriptorEntry::new("remote_address".to_string(), DescriptorSource::ClientIp),
+            DescriptorEntry::new("path".to_string(), DescriptorSource::Path),
+        ]),
+        DescriptorConfig::new(vec![DescriptorEntry::new(
+            "tenant".to_string(),
+            DescriptorSource::Header {
+                header: "x-tenant".to_string(),
+            },
+        )]),
+    ]
+}
+
+async fn get(proxy_addr: SocketAddr, tenant: Option<&str>) -> eyre::Result<Response<()>> {
+    let stream = TcpStream::connect(proxy_addr).await?;
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+
+    let mut request = Request::get("/orders").header(http::header::HOST, "umay.test");
+    if let Some(tenant) = tenant {
+        request = request.header("x-tenant", tenant);
+    }
+    let response = sender
+        .send_request(request.body(Empty::<Bytes>::new())?)
+        .await?;
+    let (parts, body) = response.into_parts();
+    body.collect().await?;
+    Ok(Response::from_parts(parts, ()))
+}
+
+#[tokio::test]
+async fn test_global_rate_limit_asks_service_and_fails_as_configured() -> eyre::Result<()> {
+    let service = MockRateLimitService::default();
+    let service_addr = start_rate_limit_service(service.clone()).await?;
+    // Nothing listens on the port of a dropped listener.
+    let dead_addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+    let backend = start_http_backend().await?;
+
+    let limited_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9986);
+    let open_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9985);
+    let closed_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9980);
+    let global_rate_limit = |addr: SocketAddr, failure_mode: FailureMode| {
+        let mut config = GlobalRateLimitConfig::new(
+            format!("http://{}", addr),
+            "umay".to_string(),
+            descriptors(),
+        );
+        config.set_timeout(Duration::from_millis(500));
+        config.set_failure_mode(failure_mode);
+        config
+    };
+    let upstream = Upstream::new(
+        LoadBalancer::RoundRobin,
+        ServiceDiscovery::Local,
+        vec![UpstreamServer::new("127.0.0.1".to_string(), backend.port())],
+    );
+    let http_config = HttpConfig::new(
+        HashMap::from([("backend".to_string(), upstream)]),
+        vec![
+            http_server(
+                limited_addr.port(),
+                global_rate_limit(service_addr, FailureMode::Deny),
+            ),
+            http_server(
+                open_addr.port(),
+                global_rate_limit(dead_addr, FailureMode::Allow),
+            ),
+            http_server(
+                closed_addr.port(),
+                global_rate_limit(dead_addr, FailureMode::Deny),
+            ),
+        ],
+    );
+    let config = UmayConfig::new(4, 1, 1, 1, None, Some(http_config));
+    let server = UmayServer::try_from(Arc::new(config))?;
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let server_handle = tokio::spawn(async move { server.run(shutdown_rx).await });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    assert_eq!(get(limited_addr, None).await?.status(), StatusCode::OK);
+    let response = get(limited_addr, Some("blocked")).await?;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[http::header::RETRY_AFTER], "3");
+
+    let requests = service.requests.lock().unwrap().clone();
+    assert_eq!(requests.len(), 2);
+    assert!(requests.iter().all(|request| request.domain == "umay"));
+    // The tenant descriptor is left out without the header.
+    assert_eq!(requests[0].descriptors.len(), 1);
+    let entries = &requests[0].descriptors[0].entries;
+    assert_eq!(
+        (entries[0].key.as_str(), entries[0].value.as_str()),
+        ("remote_address", "127.0.0.1")
+    );
+    assert_eq!(
+        (entries[1].key.as_str(), entries[1].value.as_str()),
+        ("path", "/orders")
+    );
+    assert_eq!(requests[1].descriptors.len(), 2);
+
+    assert_eq!(get(open_addr, None).await?.status(), StatusCode::OK);
+    // Failing closed answers an outage as such, not as a limit.
+    assert_eq!(
+        get(closed_addr, None).await?.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    shutdown_tx.send(())?;
+    server_handle.await??;
+    Ok(())
+}
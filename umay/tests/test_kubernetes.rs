@@ -0,0 +1,225 @@
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::StreamExt;
+use http::{Request, Response, StatusCode};
+use http_body_util::Full;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use umay::app::config::KubernetesConfig;
+use umay::balance::discovery::kubernetes::KubernetesDiscovery;
+use umay::balance::discovery::{Change, ServiceDiscovery};
+use umay::balance::Backend;
+
+const ENDPOINT_SLICES: &str = r#"{
+  "kind": "EndpointSliceList",
+  "metadata": {"resourceVersion": "100"},
+  "items": [
+    {
+      "metadata": {"name": "web-ipv4"},
+      "addressType": "IPv4",
+      "ports": [{"name": "http", "port": 8080}, {"name": "metrics", "port": 9090}],
+      "endpoints": [
+        {"addresses": ["10.0.0.1"], "conditions": {"ready": true}, "zone": "a", "nodeName": "node-1",
+         "hints": {"forZones": [{"name": "a"}]}},
+        {"addresses": ["10.0.0.2"], "conditions": {"ready": true}, "zone": "b",
+         "hints": {"forZones": [{"name": "b"}]}},
+        {"addresses": ["10.0.0.3"], "conditions": {"ready": false}, "zone": "a"},
+        {"addresses": ["10.0.0.4"], "conditions": {"ready": false, "serving": true, "terminating": true},
+         "zone": "a"}
+      ]
+    },
+    {
+      "metadata": {"name": "web-fqdn"},
+      "addressType": "FQDN",
+      "ports": [{"name": "http", "port": 8080}],
+      "endpoints": [{"addresses": ["example.com"]}]
+    }
+  ]
+}"#;
+
+/// A bookmark, then the first slice modified to 10.0.0.1 ready and 10.0.0.2
+/// unready.
+const WATCH_EVENTS: &str = concat!(
+    r#"{"type": "BOOKMARK", "object": {"metadata": {"resourceVersion": "101"}}}"#,
+    "\n",
+    r#"{"type": "MODIFIED", "object": {"metadata": {"name": "web-ipv4"}, "addressType": "IPv4", "#,
+    r#""ports": [{"name": "http", "port": 8080}], "endpoints": ["#,
+    r#"{"addresses": ["10.0.0.1"], "conditions": {"ready": true}}, "#,
+    r#"{"addresses": ["10.0.0.2"], "conditions": {"ready": false}}]}}"#,
+    "\n",
+);
+
+/// Serves `ENDPOINT_SLICES` to requests carrying the expected token, and
+/// `WATCH_EVENTS` to watches from their resource version.
+async fn spawn_fake_api_server() -> eyre::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let service = service_fn(|request: Request<hyper::body::Incoming>| async move {
+                let authorized = request
+                    .headers()
+                    .get(http::header::AUTHORIZATION)
+                    .is_some_and(|v| v == "Bearer test-token");
+                let expected = "/apis/discovery.k8s.io/v1/namespaces/default/endpointslices\
+                                ?labelSelector=kubernetes.io%2Fservice-name%3Dweb";
+                let watch = format!(
+                    "{}&watch=true&allowWatchBookmarks=true&resourceVersion=100",
+                    expected
+                );
+                let (status, body) = match request.uri().to_string() {
+                    _ if !authorized => (StatusCode::UNAUTHORIZED, ""),
+                    uri if uri == expected => (StatusCode::OK, ENDPOINT_SLICES),
+                    uri if uri.starts_with(&watch) => (StatusCode::OK, WATCH_EVENTS),
+                    _ => (StatusCode::NOT_FOUND, ""),
+                };
+                let mut response = Response::new(Full::new(Bytes::from(body)));
+                *response.status_mut() = status;
+                Ok::<_, Infallible>(response)
+            });
+            tokio::spawn(
+                hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service),
+            );
+        }
+    });
+    Ok(addr)
+}
+
+fn kubeconfig(server: SocketAddr, token: &str) -> eyre::Result<PathBuf> {
+    let path = std::env::temp_dir().join(format!(
+        "umay-{}-{}-kubeconfig",
+        std::process::id(),
+        server.port()
+    ));
+    std::fs::write(
+        &path,
+        format!(
+            r#"
+apiVersion: v1
+kind: Config
+current-context: test
+contexts:
+  - name: test
+    context:
+      cluster: fake
+      user: tester
+clusters:
+  - name: fake
+    cluster:
+      server: http://{}
+users:
+  - name: tester
+    user:
+      token: {}
+"#,
+            server, token
+        ),
+    )?;
+    Ok(path)
+}
+
+fn addr(last: u8) -> SocketAddr {
+    SocketAddr::new(Ipv4Addr::new(10, 0, 0, last).into(), 8080)
+}
+
+async fn discover(service: &str, zone: Option<&str>) -> eyre::Result<BTreeSet<Backend>> {
+    let server = spawn_fake_api_server().await?;
+    let path = kubeconfig(server, "test-token")?;
+    let mut config = KubernetesConfig::new(service.to_string());
+    config.set_kubeconfig(Some(path.display().to_string()));
+    config.set_zone(zone.map(str::to_string));
+
+    let backends = KubernetesDiscovery::new(config)?.discover().await;
+    std::fs::remove_file(&path)?;
+    Ok((*backends?).clone())
+}
+
+#[tokio::test]
+async fn test_kubernetes_discovery_honors_readiness() -> eyre::Result<()> {
+    let backends = discover("default/web:http", None).await?;
+
+    let addrs: Vec<SocketAddr> = backends.iter().map(|b| b.addr).collect();
+    assert_eq!(addrs, vec![addr(1), addr(2), addr(4)]);
+
+    let first = backends.iter().find(|b| b.addr == addr(1)).unwrap();
+    assert!(!first.backup);
+    assert_eq!(first.metadata.get("zone").map(String::as_str), Some("a"));
+    assert_eq!(
+        first.metadata.get("node").map(String::as_str),
+        Some("node-1")
+    );
+
+    // Terminating endpoints that still serve only take over when none is ready.
+    let terminating = backends.iter().find(|b| b.addr == addr(4)).unwrap();
+    assert!(terminating.backup);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_kubernetes_discovery_follows_zone_hints() -> eyre::Result<()> {
+    let backends = discover("default/web:8080", Some("b")).await?;
+
+    let primaries: Vec<SocketAddr> = backends
+        .iter()
+        .filter(|b| !b.backup)
+        .map(|b| b.addr)
+        .collect();
+    assert_eq!(primaries, vec![addr(2)]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_kubernetes_discovery_rejects_ambiguous_port() -> eyre::Result<()> {
+    // The slice exposes two ports, so one has to be named.
+    assert!(discover("default/web", None).await.is_err());
+    assert!(discover("default/web:grpc", None).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_kubernetes_discovery_requires_valid_credentials() -> eyre::Result<()> {
+    let server = spawn_fake_api_server().await?;
+    let path = kubeconfig(server, "wrong-token")?;
+    let mut config = KubernetesConfig::new("default/web:http".to_string());
+    config.set_kubeconfig(Some(path.display().to_string()));
+
+    let result = KubernetesDiscovery::new(config)?.discover().await;
+    std::fs::remove_file(&path)?;
+    assert!(result.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_kubernetes_discovery_watches_changes() -> eyre::Result<()> {
+    let server = spawn_fake_api_server().await?;
+    let path = kubeconfig(server, "test-token")?;
+    let mut config = KubernetesConfig::new("default/web:http".to_string());
+    config.set_kubeconfig(Some(path.display().to_string()));
+
+    let discovery = Arc::new(KubernetesDiscovery::new(config)?);
+    let mut changes = discovery.watch().expect("kubernetes discovery pushes");
+    let timeout = Duration::from_secs(5);
+    let listed = tokio::time::timeout(timeout, changes.next())
+        .await?
+        .unwrap();
+    let watched = tokio::time::timeout(timeout, changes.next())
+        .await?
+        .unwrap();
+    std::fs::remove_file(&path)?;
+
+    let addrs = |change: Change| match change {
+        Change::Replace(backends) => backends.iter().map(|b| b.addr).collect::<Vec<_>>(),
+        change => panic!("unexpected change {:?}", change),
+    };
+    assert_eq!(addrs(listed?), vec![addr(1), addr(2), addr(4)]);
+    assert_eq!(addrs(watched?), vec![addr(1)]);
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::{Method, Request, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use umay::app::config::{
+    AdminConfig, HttpConfig, HttpServer, ListenConfig, LoadBalancer, LocationConfig, LogFormat,
+    LogOutput, LoggingConfig, Protocol, ServiceDiscovery, UmayConfig, Upstream,
+};
+use umay::app::logging;
+use umay::app::server::UmayServer;
+
+async fn admin_request(
+    admin_addr: SocketAddr,
+    method: Method,
+    path: &str,
+    body: &'static str,
+) -> eyre::Result<(StatusCode, serde_json::Value)> {
+    let stream = TcpStream::connect(admin_addr).await?;
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+
+    let request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(http::header::HOST, "localhost")
+        .body(Full::new(Bytes::from(body)))?;
+    let response = sender.send_request(request).await?;
+    let status = response.status();
+    let body = response.into_body().collect().await?.to_bytes();
+    Ok((status, serde_json::from_slice(&body)?))
+}
+
+fn messages(path: &std::path::Path) -> eyre::Result<Vec<String>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .map(|line| {
+            let record: serde_json::Value = serde_json::from_str(line)?;
+            Ok(record["fields"]["message"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string())
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_logging_filter_changes_at_runtime() -> eyre::Result<()> {
+    let path = std::env::temp_dir().join(format!("umay-logging-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut logging_config =
+        LoggingConfig::new("warn".to_string(), LogFormat::Json, LogOutput::File);
+    logging_config.set_directives(vec!["test_logging=info".to_string()]);
+    logging_config.set_path(Some(path.to_string_lossy().into_owned()));
+    let log_filter = logging::init(&logging_config, None)?;
+
+    tracing::info!("first");
+    tracing::debug!("hidden");
+    assert_eq!(messages(&path)?, vec!["first"]);
+
+    let admin_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9984);
+    let http_server = HttpServer::new(
+        "http".to_string(),
+        ListenConfig::new(9990, Protocol::Http),
+        None,
+        "backend".to_string(),
+        LocationConfig::new("/".to_string()),
+        "1.1".to_string(),
+        String::new(),
+        60,
+    );
+    let upstream = Upstream::new(LoadBalancer::RoundRobin, ServiceDiscovery::Local, vec![]);
+    let http_config = HttpConfig::new(
+        HashMap::from([("backend".to_string(), upstream)]),
+        vec![http_server],
+    );
+    let mut config = UmayConfig::new(4, 1, 1, 1, None, Some(http_config));
+    config.set_admin(Some(AdminConfig::new(admin_addr.ip(), admin_addr.port())));
+    let server = UmayServer::try_from(Arc::new(config))?;
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let server_handle = tokio::spawn(async move { server.run(shutdown_rx).await });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (status, body) = admin_request(admin_addr, Method::GET, "/logging", "").await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["filter"], log_filter.current());
+
+    let (status, _) = admin_request(
+        admin_addr,
+        Method::PUT,
+        "/logging",
+        r#"{"filter": "test_logging=debug"}"#,
+    )
+    .await?;
+    assert_eq!(status, StatusCode::OK);
+    tracing::debug!("second");
+    let (status, _) = admin_request(
+        admin_addr,
+        Method::PUT,
+        "/logging",
+        r#"{"filter": "test_logging=nonsense"}"#,
+    )
+    .await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    log_filter.reset()?;
+    tracing::debug!("hidden again");
+    assert_eq!(messages(&path)?, vec!["first", "second"]);
+
+    shutdown_tx.send(())?;
+    server_handle.await??;
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
@@ -0,0 +1,187 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use umay::balance::selection::{
+    LeastConnections, Maglev, P2cEwma, SelectionAlgorithm, WeightedRoundRobin,
+};
+use umay::balance::Backend;
+
+fn backends(count: u8) -> Arc<BTreeSet<Backend>> {
+    Arc::new(
+        (1..=count)
+            .map(|i| Backend::new(SocketAddr::new(Ipv4Addr::new(10, 0, 0, i).into(), 8080), 1))
+            .collect(),
+    )
+}
+
+#[tokio::test]
+async fn test_maglev_rejects_non_prime_table_size() {
+    assert!(Maglev::new(65536).is_err());
+    assert!(Maglev::new(251).is_ok());
+}
+
+#[tokio::test]
+async fn test_maglev_same_key_same_backend() {
+    let maglev = Maglev::default();
+    let backends = backends(5);
+
+    let first = maglev.select(&backends, Some("192.168.1.10")).await;
+    for _ in 0..10 {
+        assert_eq!(maglev.select(&backends, Some("192.168.1.10")).await, first);
+    }
+}
+
+#[tokio::test]
+async fn test_maglev_even_distribution() -> eyre::Result<()> {
+    let maglev = Maglev::new(65537)?;
+    let backends = backends(10);
+
+    let mut hits: HashMap<SocketAddr, usize> = HashMap::new();
+    for i in 0..20_000 {
+        let key = format!("client-{}", i);
+        let backend = maglev.select(&backends, Some(&key)).await.unwrap();
+        *hits.entry(backend.addr).or_default() += 1;
+    }
+
+    assert_eq!(hits.len(), 10);
+    for count in hits.values() {
+        assert!(
+            (1_500..=2_500).contains(count),
+            "uneven distribution: {:?}",
+            hits
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_maglev_minimal_disruption_on_removal() {
+    let (maglev_before, maglev_after) = (Maglev::default(), Maglev::default());
+    let before = backends(10);
+    let removed = before.iter().next().unwrap().clone();
+    let after: Arc<BTreeSet<Backend>> =
+        Arc::new(before.iter().filter(|b| **b != removed).cloned().collect());
+
+    let keys: Vec<String> = (0..5_000).map(|i| format!("client-{}", i)).collect();
+    let mut moved = 0;
+    for key in &keys {
+        let old = maglev_before.select(&before, Some(key)).await.unwrap();
+        let new = maglev_after.select(&after, Some(key)).await.unwrap();
+        if old != removed && old != new {
+            moved += 1;
+        }
+    }
+
+    // Keys that were not on the removed backend should almost all stay put.
+    assert!(moved < keys.len() / 20, "{} keys moved", moved);
+}
+
+#[tokio::test]
+async fn test_p2c_ewma_avoids_slow_backend() {
+    let p2c = P2cEwma::default();
+    let backends = backends(2);
+    let mut iter = backends.iter();
+    let (fast, slow) = (iter.next().unwrap(), iter.next().unwrap());
+
+    p2c.on_latency(fast, Duration::from_millis(1));
+    p2c.on_latency(slow, Duration::from_millis(500));
+
+    for _ in 0..10 {
+        assert_eq!(p2c.select(&backends, None).await.as_ref(), Some(fast));
+    }
+}
+
+#[tokio::test]
+async fn test_p2c_ewma_avoids_failing_backend() {
+    let p2c = P2cEwma::default();
+    let backends = backends(2);
+    let mut iter = backends.iter();
+    let (failing, healthy) = (iter.next().unwrap(), iter.next().unwrap());
+
+    p2c.on_latency(healthy, Duration::from_millis(50));
+    p2c.on_failure(failing);
+
+    for _ in 0..10 {
+        assert_eq!(p2c.select(&backends, None).await.as_ref(), Some(healthy));
+    }
+}
+
+#[tokio::test]
+async fn test_p2c_ewma_accounts_for_in_flight() {
+    let p2c = P2cEwma::default();
+    let backends = backends(2);
+    let mut iter = backends.iter();
+    let (busy, idle) = (iter.next().unwrap(), iter.next().unwrap());
+
+    p2c.on_latency(busy, Duration::from_millis(10));
+    p2c.on_latency(idle, Duration::from_millis(10));
+    for _ in 0..5 {
+        p2c.on_start(busy);
+    }
+    assert_eq!(p2c.select(&backends, None).await.as_ref(), Some(idle));
+
+    for _ in 0..5 {
+        p2c.on_end(busy);
+    }
+    p2c.on_start(idle);
+    assert_eq!(p2c.select(&backends, None).await.as_ref(), Some(busy));
+}
+
+#[tokio::test]
+async fn test_least_connections_follows_in_flight() {
+    let least_conn = LeastConnections::default();
+    let backends = backends(2);
+    let mut iter = backends.iter();
+    let (first, second) = (iter.next().unwrap(), iter.next().unwrap());
+
+    least_conn.on_start(first);
+    assert_eq!(
+        least_conn.select(&backends, None).await.as_ref(),
+        Some(second)
+    );
+
+    least_conn.on_end(first);
+    least_conn.on_start(second);
+    assert_eq!(
+        least_conn.select(&backends, None).await.as_ref(),
+        Some(first)
+    );
+}
+
+#[tokio::test]
+async fn test_weighted_round_robin_interleaves_picks() {
+    let wrr = WeightedRoundRobin::default();
+    let addr = |i: u8| SocketAddr::new(Ipv4Addr::new(10, 0, 0, i).into(), 8080);
+    let backends = Arc::new(BTreeSet::from([
+        Backend::new(addr(1), 5),
+        Backend::new(addr(2), 1),
+        Backend::new(addr(3), 1),
+    ]));
+
+    let mut picks = vec![];
+    for _ in 0..7 {
+        picks.push(wrr.select(&backends, None).await.unwrap().addr);
+    }
+
+    let (a, b, c) = (addr(1), addr(2), addr(3));
+    assert_eq!(picks, vec![a, a, b, a, c, a, a]);
+}
+
+#[tokio::test]
+async fn test_weighted_round_robin_survives_refresh() {
+    let wrr = WeightedRoundRobin::default();
+    let first = backends(3);
+    let mut picks = vec![];
+    for _ in 0..2 {
+        picks.push(wrr.select(&first, None).await.unwrap());
+    }
+
+    // An equal set from a fresh discovery round continues the same cycle.
+    let refreshed = Arc::new((*first).clone());
+    picks.push(wrr.select(&refreshed, None).await.unwrap());
+
+    let unique: BTreeSet<Backend> = picks.into_iter().collect();
+    assert_eq!(unique.len(), 3);
+}
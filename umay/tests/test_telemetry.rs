@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::{Request, Response, StatusCode};
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
+    TraceService, TraceServiceServer,
+};
+use opentelemetry_proto::tonic::collector::trace::v1::{
+    ExportTraceServiceRequest, ExportTraceServiceResponse,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use umay::app::config::{
+    HttpConfig, HttpServer, ListenConfig, LoadBalancer, LocationConfig, LoggingConfig, Protocol,
+    ServiceDiscovery, TelemetryConfig, UmayConfig, Upstream, UpstreamServer,
+};
+use umay::app::server::UmayServer;
+use umay::app::{logging, telemetry};
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+/// Collects the name and trace id of every exported span.
+#[derive(Default, Clone)]
+struct Collector {
+    spans: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+#[tonic::async_trait]
+impl TraceService for Collector {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportTraceServiceRequest>,
+    ) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+        let mut spans = self.spans.lock().unwrap();
+        for resource_spans in request.into_inner().resource_spans {
+            for scope_spans in resource_spans.scope_spans {
+                for span in scope_spans.spans {
+                    let trace_id = span.trace_id.iter().map(|b| format!("{:02x}", b)).collect();
+                    spans.push((span.name, trace_id));
+                }
+            }
+        }
+        Ok(tonic::Response::new(ExportTraceServiceResponse::default()))
+    }
+}
+
+/// Answers every request, passing on the `traceparent` it received.
+async fn start_http_backend(
+    traceparents: mpsc::UnboundedSender<String>,
+) -> eyre::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let traceparents = traceparents.clone();
+            let service = service_fn(move |request: Request<hyper::body::Incoming>| {
+                let traceparent = request
+                    .headers()
+                    .get("traceparent")
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+                let _ = traceparents.send(traceparent);
+                async { Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("ok")))) }
+            });
+            tokio::spawn(
+                hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service),
+            );
+        }
+    });
+    Ok(addr)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_spans_are_exported_and_propagated() -> eyre::Result<()> {
+    let collector_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9989);
+    let collector = Collector::default();
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(TraceServiceServer::new(collector.clone()))
+            .serve(collector_addr),
+    );
+    let telemetry_config = TelemetryConfig::new(format!("http://{}", collector_addr));
+    logging::init(&LoggingConfig::default(), Some(&telemetry_config))?;
+
+    let (traceparents, mut received) = mpsc::unbounded_channel();
+    let backend = start_http_backend(traceparents).await?;
+    let proxy_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9988);
+    let http_server = HttpServer::new(
+        "http".to_string(),
+        ListenConfig::new(proxy_addr.port(), Protocol::Http),
+        None,
+        "backend".to_string(),
+        LocationConfig::new("/".to_string()),
+        "1.1".to_string(),
+        String::new(),
+        60,
+    );
+    let upstream = Upstream::new(
+        LoadBalancer::RoundRobin,
+        ServiceDiscovery::Local,
+        vec![UpstreamServer::new("127.0.0.1".to_string(), backend.port())],
+    );
+    let http_config = HttpConfig::new(
+        HashMap::from([("backend".to_string(), upstream)]),
+        vec![http_server],
+    );
+    let config = UmayConfig::new(4, 1, 1, 1, None, Some(http_config));
+    let server = UmayServer::try_from(Arc::new(config))?;
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let server_handle = tokio::spawn(async move { server.run(shutdown_rx).await });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let stream = TcpStream::connect(proxy_addr).await?;
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+    let request = Request::get("/")
+        .header(http::header::HOST, "umay.test")
+        .header(
+            "traceparent",
+            format!("00-{}-00f067aa0ba902b7-01", TRACE_ID),
+        )
+        .body(Empty::<Bytes>::new())?;
+    let response = sender.send_request(request).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    response.into_body().collect().await?;
+
+    // The backend sees the same trace, with the span of Umay as parent.
+    let traceparent = tokio::time::timeout(Duration::from_secs(5), received.recv())
+        .await?
+        .unwrap_or_default();
+    assert!(traceparent.starts_with(&format!("00-{}-", TRACE_ID)));
+    assert!(!traceparent.contains("00f067aa0ba902b7"));
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    tokio::task::spawn_blocking(telemetry::flush).await?;
+    let spans = collector.spans.lock().unwrap().clone();
+    for name in ["request", "select_backend", "connect", "proxy"] {
+        assert!(
+            spans.contains(&(name.to_string(), TRACE_ID.to_string())),
+            "no {} span in {:?}",
+            name,
+            spans
+        );
+    }
+
+    shutdown_tx.send(())?;
+    server_handle.await??;
+    Ok(())
+}